@@ -0,0 +1,391 @@
+//! Registry credentials from the auth config files of docker and podman.
+//!
+//! Credentials are looked up like docker does it: a credential helper configured for the
+//! registry (`credHelpers`) comes first, then the default credential store (`credsStore`),
+//! then the `auths` entries, which contain base64 encoded `user:password` pairs.
+//! Like podman, `auths` entries can also be scoped to a repository (e.g. `quay.io/org`),
+//! where the most specific entry wins.
+
+use std::{
+	env, fmt, fs,
+	io::{ErrorKind, Write},
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use indexmap::IndexMap;
+use oci_client::{secrets::RegistryAuth, Reference};
+use serde::Deserialize;
+
+use crate::{Error, Result};
+
+const DOCKER_HUB: &str = "docker.io";
+/// Server URL of docker hub, as credential helpers know it.
+const DOCKER_HUB_SERVER_URL: &str = "https://index.docker.io/v1/";
+
+/// Credentials of container registries, loaded from auth config files.
+#[derive(Clone, Default)]
+pub struct AuthConfig {
+	files: Vec<AuthFile>,
+	/// Directory containing the `docker-credential-<helper>` executables.
+	///
+	/// If unset, credential helpers are looked up in `$PATH`.
+	pub helper_dir: Option<PathBuf>,
+}
+
+/// Contents of a `config.json` of docker or an `auth.json` of podman.
+#[derive(Clone, Default, Deserialize)]
+struct AuthFile {
+	#[serde(default)]
+	auths: IndexMap<String, AuthEntry>,
+	#[serde(default, rename = "credHelpers")]
+	cred_helpers: IndexMap<String, String>,
+	#[serde(default, rename = "credsStore")]
+	creds_store: Option<String>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+struct AuthEntry {
+	auth: Option<String>,
+	username: Option<String>,
+	password: Option<String>,
+}
+
+/// Output of `docker-credential-<helper> get`.
+#[derive(Deserialize)]
+struct HelperCredentials {
+	#[serde(rename = "Username")]
+	username: String,
+	#[serde(rename = "Secret")]
+	secret: String,
+}
+
+impl fmt::Debug for AuthConfig {
+	/// Only lists the configured registries: the credentials must not end up in logs.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let registries = (self.files.iter())
+			.flat_map(|file| file.auths.keys().chain(file.cred_helpers.keys()))
+			.collect::<Vec<_>>();
+		f.debug_struct("AuthConfig")
+			.field("registries", &registries)
+			.finish_non_exhaustive()
+	}
+}
+
+impl AuthConfig {
+	/// Loads the auth config files of podman and docker, in the order podman checks them:
+	///   - `$REGISTRY_AUTH_FILE`
+	///   - `$XDG_RUNTIME_DIR/containers/auth.json`
+	///   - `$XDG_CONFIG_HOME/containers/auth.json` (defaults to `~/.config/containers/auth.json`)
+	///   - `$DOCKER_CONFIG/config.json` (defaults to `~/.docker/config.json`)
+	pub fn from_default_files() -> Result<Self> {
+		let home = env::var_os("HOME").map(PathBuf::from);
+		let mut paths = Vec::new();
+		paths.extend(env::var_os("REGISTRY_AUTH_FILE").map(PathBuf::from));
+		if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
+			paths.push(Path::new(&runtime_dir).join("containers/auth.json"));
+		}
+		match env::var_os("XDG_CONFIG_HOME") {
+			Some(config_home) => paths.push(Path::new(&config_home).join("containers/auth.json")),
+			None => paths.extend(
+				home.iter()
+					.map(|home| home.join(".config/containers/auth.json")),
+			),
+		}
+		match env::var_os("DOCKER_CONFIG") {
+			Some(docker_config) => paths.push(Path::new(&docker_config).join("config.json")),
+			None => paths.extend(home.iter().map(|home| home.join(".docker/config.json"))),
+		}
+		Self::from_files(paths)
+	}
+
+	/// Loads auth config files, where earlier files take precedence.
+	///
+	/// Files which don't exist are skipped.
+	pub fn from_files(paths: impl IntoIterator<Item = impl AsRef<Path>>) -> Result<Self> {
+		let mut files = Vec::new();
+		for path in paths {
+			let path = path.as_ref();
+			let data = match fs::read(path) {
+				Ok(data) => data,
+				Err(err) if err.kind() == ErrorKind::NotFound => continue,
+				Err(err) => return Err(err.into()),
+			};
+			let file = serde_json::from_slice(&data)
+				.map_err(|err| Error::ParseAuthFile(path.to_owned(), err))?;
+			files.push(file);
+		}
+		Ok(AuthConfig {
+			files,
+			helper_dir: None,
+		})
+	}
+
+	/// Credentials for pulling an image, from the first file configuring its registry.
+	///
+	/// Without any credentials configured, the image is pulled anonymously.
+	pub fn registry_auth(&self, image: &Reference) -> Result<RegistryAuth> {
+		let registry = normalize_registry(image.registry());
+		for file in &self.files {
+			let helper_dir = self.helper_dir.as_deref();
+			if let Some(auth) = file.registry_auth(&registry, image.repository(), helper_dir)? {
+				return Ok(auth);
+			}
+		}
+		Ok(RegistryAuth::Anonymous)
+	}
+}
+
+impl AuthFile {
+	fn registry_auth(
+		&self,
+		registry: &str,
+		repository: &str,
+		helper_dir: Option<&Path>,
+	) -> Result<Option<RegistryAuth>> {
+		let helper = (self.cred_helpers.iter())
+			.find(|(key, _)| normalize_registry(key) == registry)
+			.map(|(_, helper)| helper);
+		let auth = match (helper, &self.creds_store) {
+			(Some(helper), _) => helper_auth(helper_dir, helper, registry, true)?,
+			(None, Some(store)) => helper_auth(helper_dir, store, registry, false)?,
+			(None, None) => None,
+		};
+		if auth.is_some() {
+			return Ok(auth);
+		}
+
+		// Most specific entry first: `registry/org/repo`, `registry/org`, `registry`.
+		let scope = format!("{registry}/{repository}");
+		let scopes = (scope.char_indices().rev())
+			.filter(|(_, c)| *c == '/')
+			.map(|(i, _)| &scope[..i]);
+		for scope in std::iter::once(scope.as_str()).chain(scopes) {
+			let entry = (self.auths.iter()).find(|(key, _)| normalize_registry(key) == scope);
+			if let Some((_, entry)) = entry {
+				if let Some(auth) = entry.registry_auth(scope)? {
+					return Ok(Some(auth));
+				}
+			}
+		}
+		Ok(None)
+	}
+}
+
+impl AuthEntry {
+	fn registry_auth(&self, registry: &str) -> Result<Option<RegistryAuth>> {
+		if let Some(auth) = &self.auth {
+			let invalid = |reason: &str| Error::InvalidCredentials {
+				registry: registry.to_owned(),
+				reason: reason.to_owned(),
+			};
+			let decoded = STANDARD
+				.decode(auth.trim())
+				.map_err(|_| invalid("'auth' is not base64 encoded"))?;
+			let decoded = String::from_utf8(decoded).map_err(|_| invalid("'auth' is not UTF-8"))?;
+			let Some((username, password)) = decoded.split_once(':') else {
+				return Err(invalid("'auth' is not of the form 'username:password'"));
+			};
+			return Ok(Some(RegistryAuth::Basic(username.into(), password.into())));
+		}
+
+		match (&self.username, &self.password) {
+			(Some(username), Some(password)) => Ok(Some(RegistryAuth::Basic(
+				username.to_owned(),
+				password.to_owned(),
+			))),
+			_ => Ok(None),
+		}
+	}
+}
+
+/// Gets credentials from the executable `docker-credential-<helper>`, which is looked up
+/// in the helper directory, or else in `$PATH`.
+///
+/// Unless the helper is `required` (configured for the registry), it is skipped if it is
+/// not installed: e.g. the default store of Docker Desktop is missing on most CI runners.
+fn helper_auth(
+	helper_dir: Option<&Path>,
+	helper: &str,
+	registry: &str,
+	required: bool,
+) -> Result<Option<RegistryAuth>> {
+	let executable = format!("docker-credential-{helper}");
+	let helper_error = |reason: String| Error::CredentialHelper {
+		helper: executable.clone(),
+		reason,
+	};
+
+	let program = match helper_dir {
+		Some(dir) => dir.join(&executable),
+		None => PathBuf::from(&executable),
+	};
+	let child = (Command::new(program).arg("get"))
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn();
+	let mut child = match child {
+		Ok(child) => child,
+		Err(err) if err.kind() == ErrorKind::NotFound && !required => return Ok(None),
+		Err(err) => return Err(helper_error(err.to_string())),
+	};
+	let server_url = match registry {
+		DOCKER_HUB => DOCKER_HUB_SERVER_URL,
+		registry => registry,
+	};
+	(child.stdin.take().unwrap())
+		.write_all(server_url.as_bytes())
+		.map_err(|err| helper_error(err.to_string()))?;
+	let output = (child.wait_with_output()).map_err(|err| helper_error(err.to_string()))?;
+
+	if !output.status.success() {
+		let message = String::from_utf8_lossy(&output.stdout).to_string()
+			+ &String::from_utf8_lossy(&output.stderr);
+		if message.contains("credentials not found") {
+			return Ok(None);
+		}
+		return Err(helper_error(message.trim().to_owned()));
+	}
+	let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)
+		.map_err(|err| helper_error(format!("invalid output: {err}")))?;
+	Ok(Some(RegistryAuth::Basic(
+		credentials.username,
+		credentials.secret,
+	)))
+}
+
+/// Normalizes the keys of auth config files: docker uses URLs like `https://index.docker.io/v1/`,
+/// while podman uses hostnames, optionally followed by a repository.
+fn normalize_registry(key: &str) -> String {
+	let key = (key.strip_prefix("https://"))
+		.or_else(|| key.strip_prefix("http://"))
+		.unwrap_or(key);
+	let key = key.trim_end_matches('/');
+	let key = (key.strip_suffix("/v1"))
+		.or_else(|| key.strip_suffix("/v2"))
+		.unwrap_or(key);
+
+	let (host, rest) = key.split_at(key.find('/').unwrap_or(key.len()));
+	match host {
+		"index.docker.io" | "registry-1.docker.io" => format!("{DOCKER_HUB}{rest}"),
+		_ => key.to_owned(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::PermissionsExt;
+
+	use tempfile::TempDir;
+
+	use super::*;
+
+	fn basic(username: &str, password: &str) -> RegistryAuth {
+		RegistryAuth::Basic(username.into(), password.into())
+	}
+
+	fn registry_auth(config: &AuthConfig, image: &str) -> RegistryAuth {
+		config.registry_auth(&image.parse().unwrap()).unwrap()
+	}
+
+	#[test]
+	fn auths_entries() {
+		let dir = TempDir::new().unwrap();
+		let podman = dir.path().join("auth.json");
+		let docker = dir.path().join("config.json");
+		fs::write(
+			&podman,
+			r#"{"auths": {
+				"quay.io/team": {"auth": "dGVhbTpzZWNyZXQ="},
+				"ghcr.io": {"username": "podman", "password": "hunter2"}
+			}}"#,
+		)
+		.unwrap();
+		fs::write(
+			&docker,
+			r#"{"auths": {
+				"https://index.docker.io/v1/": {"auth": "ZG9ja2VyOmh1YiBwYXNz"},
+				"ghcr.io": {"auth": "ZG9ja2VyOnNoYWRvd2Vk"},
+				"quay.io": {"auth": "cXVheTpyZWdpc3RyeQ=="}
+			}}"#,
+		)
+		.unwrap();
+		let missing = dir.path().join("missing.json");
+		let config = AuthConfig::from_files([&missing, &podman, &docker]).unwrap();
+
+		assert_eq!(
+			registry_auth(&config, "busybox"),
+			basic("docker", "hub pass")
+		);
+		assert_eq!(
+			registry_auth(&config, "ghcr.io/a/b"),
+			basic("podman", "hunter2")
+		);
+		assert_eq!(
+			registry_auth(&config, "quay.io/team/tool:1"),
+			basic("team", "secret")
+		);
+		assert_eq!(
+			registry_auth(&config, "quay.io/other/tool:1"),
+			basic("quay", "registry")
+		);
+		assert_eq!(
+			registry_auth(&config, "example.org/x"),
+			RegistryAuth::Anonymous
+		);
+		assert!(!format!("{config:?}").contains("hunter2"));
+	}
+
+	#[test]
+	fn credential_helpers() {
+		let dir = TempDir::new().unwrap();
+		let helper = dir.path().join("docker-credential-test");
+		fs::write(
+			&helper,
+			"#!/bin/sh\n\
+			read server\n\
+			case \"$server\" in\n\
+			  example.org) echo '{\"Username\":\"helper\",\"Secret\":\"from '$server'\"}' ;;\n\
+			  *) echo 'credentials not found in native keychain'; exit 1 ;;\n\
+			esac\n",
+		)
+		.unwrap();
+		fs::set_permissions(&helper, fs::Permissions::from_mode(0o755)).unwrap();
+
+		let file = dir.path().join("config.json");
+		fs::write(
+			&file,
+			r#"{
+				"credHelpers": {"example.org": "test", "other.org": "test"},
+				"auths": {"other.org": {"auth": "b3RoZXI6ZmlsZQ=="}}
+			}"#,
+		)
+		.unwrap();
+		let config = AuthConfig {
+			helper_dir: Some(dir.path().to_owned()),
+			..AuthConfig::from_files([&file]).unwrap()
+		};
+
+		assert_eq!(
+			registry_auth(&config, "example.org/x"),
+			basic("helper", "from example.org")
+		);
+		assert_eq!(
+			registry_auth(&config, "other.org/x"),
+			basic("other", "file")
+		);
+	}
+
+	#[test]
+	fn invalid_auth_entry() {
+		let dir = TempDir::new().unwrap();
+		let file = dir.path().join("config.json");
+		fs::write(&file, r#"{"auths": {"ghcr.io": {"auth": "bm9jb2xvbg=="}}}"#).unwrap();
+		let config = AuthConfig::from_files([&file]).unwrap();
+
+		let result = config.registry_auth(&"ghcr.io/x".parse().unwrap());
+		assert!(matches!(result, Err(Error::InvalidCredentials { .. })));
+	}
+}
@@ -1,34 +1,96 @@
-// TODO: Function to remove image from cache.
-// TODO: GC function to remove orpahn blobs.
+//! Cache of pulled images.
+//!
+//! The cache directory contains an `index.json`, mapping image references to manifest
+//! digests, and a `blobs/` directory containing manifests, configs and layers by digest.
+//! Blobs can be shared by multiple images: removing an image only removes its index
+//! entry, while [gc] removes the blobs not reachable from any image anymore.
+//!
+//! Images unpacked by [crate::pull_and_unpack_rootfs] are kept in the `rootfs/` directory,
+//! until [gc] removes them together with the blobs of their image.
 
 use std::{
-	fs::{self, OpenOptions},
-	io::{ErrorKind, Write},
+	collections::HashSet,
+	fs::{self, File, OpenOptions, TryLockError},
+	io::{self, ErrorKind, Read, Write},
 	path::{Path, PathBuf},
-	thread::sleep,
-	time::{Duration, Instant},
 };
 
-use indexmap::{map, IndexMap};
+use indexmap::IndexMap;
 use oci_client::{
-	client::{ImageData, ImageLayer},
-	manifest::{OciImageManifest, OCI_IMAGE_MEDIA_TYPE},
+	manifest::{OciDescriptor, OciImageManifest, OCI_IMAGE_MEDIA_TYPE},
+	secrets::RegistryAuth,
 	Reference,
 };
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
 
-use crate::{Error, PullConfig, Result};
+use crate::progress::{LayerProgress, LayerState, ProgressCallback, ProgressWriter};
+use crate::rootfs::{self, ROOTFS_LOCK_FILE};
+use crate::tee::{AsyncTeeWriter, ReadExt};
 
-const BLOBS_DIR: &str = "blobs";
-const INDEX_FILE: &str = "index.json";
-const LOCK_FILE: &str = "index.lock";
+use crate::{Error, ImageData, LayerBlob, Platform, PullConfig, Result};
 
-const CACHE_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+pub(crate) const BLOBS_DIR: &str = "blobs";
+const INDEX_FILE: &str = "index.json";
+const INDEX_TEMP_FILE: &str = "index.json.tmp";
+const INDEX_LOCK_FILE: &str = "index.lock";
+/// Serializes pulls, so concurrent cache misses don't download the same image redundantly.
+const PULL_LOCK_FILE: &str = "pull.lock";
+/// Held shared while the blobs of an image are read, so [gc] doesn't remove them meanwhile.
+const BLOBS_LOCK_FILE: &str = "blobs.lock";
 
 pub(crate) struct Cache<'a> {
 	config: &'a PullConfig,
-	has_lock: bool,
+	pull_lock: Option<CacheLock>,
+}
+
+/// Advisory lock (`flock`) on a lock file of the cache, which is released when dropped.
+///
+/// Reading the index only takes a shared lock on `index.lock`, so cache hits don't serialize,
+/// while modifying the index takes an exclusive lock.  Pulls hold `pull.lock` until their image
+/// was added to the index.  Images found in (or added to) the cache come with a shared lock on
+/// `blobs.lock`, until they are unpacked.  Locks are taken in the order `rootfs.lock`,
+/// `pull.lock`, `index.lock`, `blobs.lock`.  The kernel releases the lock together with its file descriptor,
+/// so even a killed process can't leave the cache locked.
+pub(crate) struct CacheLock {
+	_file: File,
+}
+
+/// Image stored in the cache.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedImage {
+	/// Reference the image was pulled by.
+	pub reference: String,
+	pub manifest_digest: String,
+	/// Platform the manifest was selected for, in case the reference points to an image index.
+	pub platform: Option<String>,
+	/// Size of all blobs of the image in bytes, including blobs shared with other images.
+	pub size: u64,
+}
+
+/// Disk usage of the blobs in the cache.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiskUsage {
+	/// Number of blob files.
+	pub blobs: usize,
+	/// Size of all blob files in bytes.
+	pub size: u64,
+	/// Number of blob files not reachable from any image, which [gc] would remove.
+	pub reclaimable_blobs: usize,
+	/// Size of the blob files not reachable from any image in bytes.
+	pub reclaimable_size: u64,
+}
+
+/// Blobs and unpacked root filesystems removed by [gc].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GcStats {
+	pub removed_blobs: usize,
+	/// Size of the removed blob files in bytes.
+	pub removed_size: u64,
+	/// Number of removed root filesystems (see [crate::pull_and_unpack_rootfs]).
+	pub removed_rootfs: usize,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -39,22 +101,47 @@ struct Index {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct IndexImage {
 	manifest_digest: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	platform: Option<String>,
 }
 
-impl<'a> Drop for Cache<'a> {
-	fn drop(&mut self) {
-		if self.has_lock {
-			let path = self.config.cache.as_ref().unwrap().join(LOCK_FILE);
-			fs::remove_file(path).expect("failed to remove cache lock file");
+impl CacheLock {
+	pub(crate) fn shared(cache_dir: impl AsRef<Path>, name: &str) -> Result<Self> {
+		let file = Self::open(cache_dir, name)?;
+		file.lock_shared()?;
+		Ok(CacheLock { _file: file })
+	}
+
+	pub(crate) fn exclusive(cache_dir: impl AsRef<Path>, name: &str) -> Result<Self> {
+		let file = Self::open(cache_dir, name)?;
+		file.lock()?;
+		Ok(CacheLock { _file: file })
+	}
+
+	/// Takes an exclusive lock, unless the lock is held already.
+	pub(crate) fn try_exclusive(cache_dir: impl AsRef<Path>, name: &str) -> Result<Option<Self>> {
+		let file = Self::open(cache_dir, name)?;
+		match file.try_lock() {
+			Ok(()) => Ok(Some(CacheLock { _file: file })),
+			Err(TryLockError::WouldBlock) => Ok(None),
+			Err(TryLockError::Error(err)) => Err(err.into()),
 		}
 	}
+
+	fn open(cache_dir: impl AsRef<Path>, name: &str) -> Result<File> {
+		// Lock files are never removed: another process might be waiting on them already.
+		let file = (OpenOptions::new().read(true).write(true).create(true))
+			.truncate(false)
+			.open(cache_dir.as_ref().join(name))?;
+		Ok(file)
+	}
 }
 
 impl<'a> Cache<'a> {
 	pub(crate) fn new(config: &'a PullConfig) -> Self {
 		Cache {
 			config,
-			has_lock: false,
+			pull_lock: None,
 		}
 	}
 
@@ -63,131 +150,283 @@ impl<'a> Cache<'a> {
 			return Ok(None);
 		};
 
-		if !cache_dir.is_dir() {
+		if !cache_dir.join(INDEX_FILE).is_file() {
 			init_cache(cache_dir)?;
 		}
 
-		match image_from_reference(cache_dir, image) {
-			Ok(None) | Err(Error::ParseCacheIndex(_)) => {}
-			result @ (Ok(Some(_)) | Err(_)) => return result,
+		let platform = &self.config.platform;
+		if let Some(image_data) = image_from_reference(cache_dir, image, platform)? {
+			return Ok(Some(image_data));
 		}
 
-		// Trying to lock index. We do this, because we are going to report
+		// Trying to lock pulls. We do this, because we are going to report
 		// a cache miss and therefore trigger a pull from the registry next.
 		// To prevent redundant downloads (e.g. during testing), we lock here.
-		self.lock_index(cache_dir)?;
+		if self.pull_lock.is_none() {
+			self.pull_lock = Some(CacheLock::exclusive(cache_dir, PULL_LOCK_FILE)?);
+		}
 
 		// Prevent race condition: check again if we do not have image by now.
-		if let Some(image_data) = image_from_reference(cache_dir, image)? {
-			return Ok(Some(image_data));
-		}
+		image_from_reference(cache_dir, image, platform)
+	}
 
-		Ok(None)
+	/// Directory into which the blobs of pulled images should be downloaded.
+	pub(crate) fn blob_dir(&self) -> Option<PathBuf> {
+		(self.config.cache.as_ref()).map(|cache_dir| cache_dir.join(BLOBS_DIR))
 	}
 
+	/// Adds a pulled image to the index, after all its blobs were downloaded via [pull_blob].
+	///
+	/// The image is added by its own reference, even when it was pulled from a mirror (`source`).
+	/// Returns a shared lock on the blobs, which keeps [gc] from removing them, in case the
+	/// image is removed from the index before it was unpacked.
 	pub(crate) async fn after_pull(
 		&mut self,
 		image: &Reference,
-		image_data: &ImageData,
+		source: &Reference,
+		manifest: &OciImageManifest,
+		digest: &str,
 		client: &oci_client::Client,
-		config: &PullConfig,
-	) -> Result<()> {
-		let Some(cache_dir) = &config.cache else {
-			return Ok(());
+		auth: &RegistryAuth,
+	) -> Result<Option<CacheLock>> {
+		let Some(cache_dir) = &self.config.cache else {
+			return Ok(None);
 		};
-		assert!(self.has_lock, "should have called before_pull");
+		assert!(self.pull_lock.is_some(), "should have called before_pull");
 
 		// Obtain exact manifest bytes, because we need to make sure digest of manifest is correct.
-		let digest = image_data.digest.as_ref().unwrap();
-		let media_type = &image_data.manifest.as_ref().unwrap().media_type;
-		let media_type = media_type.as_ref().map(AsRef::as_ref);
+		let media_type = manifest.media_type.as_ref().map(AsRef::as_ref);
 		let media_type = [media_type.unwrap_or(OCI_IMAGE_MEDIA_TYPE)];
-		let reference = image.clone_with_digest(digest.to_owned());
+		let reference = source.clone_with_digest(digest.to_owned());
 		let (manifest_raw, digest_raw) = client
-			.pull_manifest_raw(&reference, &config.auth, &media_type)
+			.pull_manifest_raw(&reference, auth, &media_type)
 			.await?;
 		assert_eq!(digest, &digest_raw);
 
-		add_image_to_cache(cache_dir, image, image_data, &manifest_raw)
+		add_image_to_cache(
+			cache_dir,
+			image,
+			digest,
+			&self.config.platform,
+			&manifest_raw,
+		)?;
+		// Still holding the pull lock, so gc can't get in between.
+		CacheLock::shared(cache_dir, BLOBS_LOCK_FILE).map(Some)
 	}
+}
 
-	fn lock_index(&mut self, cache_dir: impl AsRef<Path>) -> Result<()> {
-		if self.has_lock {
-			return Ok(());
+/// Lists the images in the cache, in the order they were added.
+pub fn list_images(cache_dir: impl AsRef<Path>) -> Result<Vec<CachedImage>> {
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		return Ok(Vec::new());
+	}
+
+	let _lock = CacheLock::shared(&cache_dir, INDEX_LOCK_FILE)?;
+	let blob_dir = cache_dir.as_ref().join(BLOBS_DIR);
+	let index = get_index(&cache_dir)?;
+	let mut images = Vec::with_capacity(index.images.len());
+	for (reference, image) in index.images {
+		let mut size = 0;
+		for digest in image_digests(&blob_dir, &image.manifest_digest)? {
+			size += fs::metadata(blob_file(&blob_dir, &digest))?.len();
 		}
+		images.push(CachedImage {
+			reference,
+			manifest_digest: image.manifest_digest,
+			platform: image.platform,
+			size,
+		});
+	}
+	Ok(images)
+}
 
-		let path = cache_dir.as_ref().join(LOCK_FILE);
-		let start = Instant::now();
+/// Removes an image from the cache index, returning whether it was cached.
+///
+/// The blobs of the image are kept, until they are removed by [gc].
+pub fn remove_image(cache_dir: impl AsRef<Path>, image: &Reference) -> Result<bool> {
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		return Ok(false);
+	}
 
-		loop {
-			match OpenOptions::new().write(true).create_new(true).open(&path) {
-				Ok(_) => {
-					self.has_lock = true;
-					return Ok(());
-				}
-				Err(error) if error.kind() != ErrorKind::AlreadyExists => return Err(error.into()),
-				_ => {
-					// Lock file already exists: wait and try again.
-					// TODO: Use notify instead of polling repeatedly.
-					while path.exists() {
-						if start.elapsed() >= CACHE_LOCK_TIMEOUT {
-							return Err(Error::CacheLockTimeout(path));
-						}
-						sleep(Duration::from_millis(50));
-					}
-				}
-			}
+	let _lock = CacheLock::exclusive(&cache_dir, INDEX_LOCK_FILE)?;
+	let mut index = get_index(&cache_dir)?;
+	if index.images.shift_remove(&image.whole()).is_none() {
+		return Ok(false);
+	}
+	write_index(&cache_dir, &index)?;
+	Ok(true)
+}
+
+/// Removes all blobs, which are not reachable from any image in the cache index,
+/// and the unpacked root filesystems of images not in the cache index.
+///
+/// This includes partial downloads of interrupted pulls and partially unpacked root
+/// filesystems.  Unpacking, pulls, the index and the blobs are locked meanwhile, so nothing
+/// of an image being pulled or unpacked is removed.
+pub fn gc(cache_dir: impl AsRef<Path>) -> Result<GcStats> {
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		return Ok(GcStats::default());
+	}
+
+	// Unpacking takes the pull lock while holding the rootfs lock: same order here.
+	let _rootfs_lock = CacheLock::exclusive(&cache_dir, ROOTFS_LOCK_FILE)?;
+	let _pull_lock = CacheLock::exclusive(&cache_dir, PULL_LOCK_FILE)?;
+	let _index_lock = CacheLock::exclusive(&cache_dir, INDEX_LOCK_FILE)?;
+	let _blobs_lock = CacheLock::exclusive(&cache_dir, BLOBS_LOCK_FILE)?;
+	let blob_dir = cache_dir.as_ref().join(BLOBS_DIR);
+	let reachable = reachable_blobs(&cache_dir)?;
+
+	let mut stats = GcStats::default();
+	for (path, size) in blob_files(&blob_dir)? {
+		if !reachable.contains(&path) {
+			fs::remove_file(&path)?;
+			stats.removed_blobs += 1;
+			stats.removed_size += size;
 		}
 	}
+	remove_empty_dirs(&blob_dir)?;
+
+	let index = get_index(&cache_dir)?;
+	let referenced = (index.images.values()).map(|image| image.manifest_digest.as_str());
+	stats.removed_rootfs = rootfs::remove_unreferenced(cache_dir.as_ref(), referenced.collect())?;
+
+	Ok(stats)
 }
 
-fn get_index(cache_dir: impl AsRef<Path>) -> Result<Index> {
-	fn get_index_raw(cache_dir: impl AsRef<Path>) -> Result<Index> {
-		let contents = fs::read(cache_dir.as_ref().join(INDEX_FILE))?;
-		serde_json::from_slice(&contents[..]).map_err(Error::ParseCacheIndex)
+/// Reports the disk usage of the cache, and how much of it [gc] would free.
+pub fn disk_usage(cache_dir: impl AsRef<Path>) -> Result<DiskUsage> {
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		return Ok(DiskUsage::default());
+	}
+
+	let _lock = CacheLock::shared(&cache_dir, INDEX_LOCK_FILE)?;
+	let reachable = reachable_blobs(&cache_dir)?;
+	let mut usage = DiskUsage::default();
+	for (path, size) in blob_files(cache_dir.as_ref().join(BLOBS_DIR))? {
+		usage.blobs += 1;
+		usage.size += size;
+		if !reachable.contains(&path) {
+			usage.reclaimable_blobs += 1;
+			usage.reclaimable_size += size;
+		}
+	}
+	Ok(usage)
+}
+
+/// Paths of all blobs reachable from the images in the cache index.
+fn reachable_blobs(cache_dir: impl AsRef<Path>) -> Result<HashSet<PathBuf>> {
+	let blob_dir = cache_dir.as_ref().join(BLOBS_DIR);
+	let mut reachable = HashSet::new();
+	for image in get_index(&cache_dir)?.images.values() {
+		for digest in image_digests(&blob_dir, &image.manifest_digest)? {
+			reachable.insert(blob_file(&blob_dir, &digest));
+		}
+	}
+	Ok(reachable)
+}
+
+/// Digests of the manifest, config and layers of an image.
+fn image_digests(blob_dir: impl AsRef<Path>, manifest_digest: &str) -> Result<Vec<String>> {
+	let manifest_data = fs::read(blob_file(&blob_dir, manifest_digest))?;
+	let manifest: OciImageManifest =
+		serde_json::from_slice(&manifest_data[..]).map_err(Error::ParseManifest)?;
+
+	let mut digests = vec![manifest_digest.to_owned(), manifest.config.digest];
+	digests.extend(manifest.layers.into_iter().map(|layer| layer.digest));
+	Ok(digests)
+}
+
+/// All files below the blob directory, with their sizes.
+fn blob_files(dir: impl AsRef<Path>) -> Result<Vec<(PathBuf, u64)>> {
+	let mut files = Vec::new();
+	let mut pending = vec![dir.as_ref().to_owned()];
+	while let Some(dir) = pending.pop() {
+		let entries = match fs::read_dir(&dir) {
+			Ok(entries) => entries,
+			Err(error) if error.kind() == ErrorKind::NotFound => continue,
+			Err(error) => return Err(error.into()),
+		};
+		for entry in entries {
+			let entry = entry?;
+			let metadata = entry.metadata()?;
+			if metadata.is_dir() {
+				pending.push(entry.path());
+			} else {
+				files.push((entry.path(), metadata.len()));
+			}
+		}
 	}
+	Ok(files)
+}
 
-	match get_index_raw(&cache_dir) {
-		Ok(index) => Ok(index),
-		Err(_) => {
-			sleep(Duration::from_millis(100));
-			get_index_raw(&cache_dir) // retry
+/// Removes the empty directories below a directory, keeping the directory itself.
+fn remove_empty_dirs(dir: impl AsRef<Path>) -> Result<()> {
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		if entry.file_type()?.is_dir() {
+			remove_empty_dirs(entry.path())?;
+			if fs::read_dir(entry.path())?.next().is_none() {
+				fs::remove_dir(entry.path())?;
+			}
 		}
 	}
+	Ok(())
+}
+
+fn get_index(cache_dir: impl AsRef<Path>) -> Result<Index> {
+	let contents = fs::read(cache_dir.as_ref().join(INDEX_FILE))?;
+	serde_json::from_slice(&contents[..]).map_err(Error::ParseCacheIndex)
+}
+
+/// Locks pulls, so images can be added to the cache, initializing it if necessary.
+pub(crate) fn lock_pulls(cache_dir: impl AsRef<Path>) -> Result<CacheLock> {
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		init_cache(&cache_dir)?;
+	}
+	CacheLock::exclusive(cache_dir, PULL_LOCK_FILE)
 }
 
 fn init_cache(cache_dir: impl AsRef<Path>) -> Result<()> {
 	fs::create_dir_all(cache_dir.as_ref().join(BLOBS_DIR))?;
 
-	let file =
-		(OpenOptions::new().write(true).create_new(true)).open(cache_dir.as_ref().join(INDEX_FILE));
-	match file {
-		Ok(mut file) => {
-			let index = Index {
-				images: IndexMap::with_capacity(0),
-			};
-			let data = serde_json::to_vec(&index).map_err(Error::ParseCacheIndex)?;
-			file.write_all(&data[..])?;
-		}
-		Err(error) if error.kind() != ErrorKind::AlreadyExists => return Err(error.into()),
-		_ => {}
+	let _lock = CacheLock::exclusive(&cache_dir, INDEX_LOCK_FILE)?;
+	if !cache_dir.as_ref().join(INDEX_FILE).exists() {
+		let index = Index {
+			images: IndexMap::with_capacity(0),
+		};
+		write_index(&cache_dir, &index)?;
 	}
 
 	Ok(())
 }
 
+/// Looks up the image of a reference in the index.
+///
+/// Entries of references which don't name the manifest by its digest (e.g. tags, which may
+/// point to an image index) only match, if they were resolved for the same platform.
+/// The returned image holds a shared lock on the blobs, until it is dropped.
 fn image_from_reference(
 	cache_dir: impl AsRef<Path>,
 	image: &Reference,
+	platform: &Platform,
 ) -> Result<Option<ImageData>> {
-	let mut index = get_index(&cache_dir)?;
-	if let map::Entry::Occupied(entry) = index.images.entry(image.whole()) {
-		return Ok(Some(image_from_blobs(
-			cache_dir,
-			&entry.get().manifest_digest,
-		)?));
+	let (index, blobs_lock) = {
+		let _lock = CacheLock::shared(&cache_dir, INDEX_LOCK_FILE)?;
+		let blobs_lock = CacheLock::shared(&cache_dir, BLOBS_LOCK_FILE)?;
+		(get_index(&cache_dir)?, blobs_lock)
+	};
+	let Some(entry) = index.images.get(&image.whole()) else {
+		return Ok(None);
+	};
+	let pinned = image.digest() == Some(entry.manifest_digest.as_str());
+	if !pinned && entry.platform.as_deref() != Some(platform.to_string().as_str()) {
+		return Ok(None);
 	}
-	Ok(None)
+	let image_data = image_from_blobs(cache_dir, &entry.manifest_digest)?;
+	Ok(Some(ImageData {
+		_blobs_lock: Some(blobs_lock),
+		..image_data
+	}))
 }
 
 fn image_from_blobs(cache_dir: impl AsRef<Path>, manifest_digest: &str) -> Result<ImageData> {
@@ -197,86 +436,197 @@ fn image_from_blobs(cache_dir: impl AsRef<Path>, manifest_digest: &str) -> Resul
 	let manifest: OciImageManifest =
 		serde_json::from_slice(&manifest_data[..]).map_err(Error::ParseManifest)?;
 
-	let config_digest = &manifest.config.digest;
-	let config_data = read_blob(&blob_dir, config_digest)?;
-	let config = oci_client::client::Config {
-		data: config_data,
-		media_type: manifest.config.media_type.to_owned(),
-		annotations: manifest.config.annotations.clone(),
-	};
+	let config_data = read_blob(&blob_dir, &manifest.config.digest)?;
+	let config = serde_json::from_slice(&config_data).map_err(Error::ParseImageConfiguration)?;
 
+	// Layers can be huge: their digests were verified while downloading them, and their
+	// content is verified against the diff IDs of the config while unpacking.  Only their
+	// size is checked here, to catch truncated blobs early.
 	let mut layers = Vec::new();
 	for layer in &manifest.layers {
-		let data = read_blob(&blob_dir, &layer.digest)?;
-		layers.push(ImageLayer {
-			data,
+		let path = blob_file(&blob_dir, &layer.digest);
+		if !has_size(&path, layer.size)? {
+			return Err(Error::CorruptCacheBlob {
+				digest: layer.digest.to_owned(),
+			});
+		}
+		layers.push(LayerBlob {
+			path,
 			media_type: layer.media_type.clone(),
-			annotations: layer.annotations.clone(),
 		});
 	}
 
 	Ok(ImageData {
-		manifest: Some(manifest),
-		digest: Some(manifest_digest.to_owned()),
-		config,
+		manifest,
+		manifest_digest: manifest_digest.to_owned(),
 		layers,
+		config,
+		_blob_dir: None,
+		_blobs_lock: None,
 	})
 }
 
-fn add_image_to_cache(
+pub(crate) fn add_image_to_cache(
 	cache_dir: impl AsRef<Path>,
 	image: &Reference,
-	image_data: &ImageData,
+	digest: &str,
+	platform: &Platform,
 	manifest_raw: &[u8],
 ) -> Result<()> {
 	let blob_dir = cache_dir.as_ref().join(BLOBS_DIR);
-
-	let digest = image_data.digest.as_ref().unwrap();
 	add_blob_to_cache(&blob_dir, digest, manifest_raw)?;
 
-	let config_digest = &image_data.manifest.as_ref().unwrap().config.digest;
-	add_blob_to_cache(&blob_dir, config_digest, &image_data.config.data[..])?;
-
-	let manifest_layers = &image_data.manifest.as_ref().unwrap().layers;
-	assert_eq!(manifest_layers.len(), image_data.layers.len());
-	for (i, layer) in image_data.layers.iter().enumerate() {
-		add_blob_to_cache(&blob_dir, &manifest_layers[i].digest, &layer.data[..])?;
-	}
-
-	let index_path = cache_dir.as_ref().join(INDEX_FILE);
+	let _lock = CacheLock::exclusive(&cache_dir, INDEX_LOCK_FILE)?;
 	let mut index = get_index(&cache_dir)?;
 	index.images.insert(
 		image.whole(),
 		IndexImage {
 			manifest_digest: digest.to_owned(),
+			platform: Some(platform.to_string()),
 		},
 	);
-	let index_bytes = serde_json::to_vec(&index).unwrap();
-	fs::write(index_path, &index_bytes[..])?;
+	write_index(cache_dir, &index)
+}
 
+/// Replaces the index atomically, so readers and crashes never see a partially written one.
+///
+/// Must only be called with an exclusive lock on `index.lock`.
+fn write_index(cache_dir: impl AsRef<Path>, index: &Index) -> Result<()> {
+	let index_bytes = serde_json::to_vec(index).unwrap();
+	let temp_path = cache_dir.as_ref().join(INDEX_TEMP_FILE);
+	let mut file = File::create(&temp_path)?;
+	file.write_all(&index_bytes[..])?;
+	file.sync_all()?;
+	fs::rename(temp_path, cache_dir.as_ref().join(INDEX_FILE))?;
 	Ok(())
 }
 
-fn add_blob_to_cache(
+pub(crate) fn add_blob_to_cache(
 	blob_dir: impl AsRef<Path>,
 	digest: &str,
 	data: impl AsRef<[u8]>,
 ) -> Result<()> {
+	check_digest(digest)?;
+
+	let colon = digest.find(':').unwrap();
+	let blob_path = blob_path(blob_dir, digest, colon);
+	fs::create_dir_all(&blob_path)?;
+	Ok(fs::write(blob_path.join(&digest[colon + 1..]), data)?)
+}
+
+/// Downloads a blob into the blob directory, without holding it in memory.
+///
+/// The blob is written to a temporary file first, while hashing it, which is only moved to
+/// its final path once the digest of the downloaded data was verified.  Concurrent downloads
+/// of the same blob (e.g. by parallel pulls without cache lock) each use their own file.
+/// Blobs which are already present (e.g. layers shared with other images) are not downloaded
+/// again, as long as they have the expected size: their content is only verified on unpacking,
+/// against the diff IDs of the image.
+///
+/// For layers, the progress is reported to the callback, starting from the given state.
+pub(crate) async fn pull_blob(
+	client: &oci_client::Client,
+	image: &Reference,
+	descriptor: &OciDescriptor,
+	blob_dir: impl AsRef<Path>,
+	progress: Option<(&ProgressCallback, LayerProgress)>,
+) -> Result<PathBuf> {
+	let digest = &descriptor.digest;
+	check_digest(digest)?;
+
+	let path = blob_file(&blob_dir, digest);
+	if has_size(&path, descriptor.size)? {
+		if let Some((callback, layer)) = progress {
+			callback.report(&LayerProgress {
+				downloaded: layer.size,
+				state: LayerState::Cached,
+				..layer
+			});
+		}
+		return Ok(path);
+	}
+
+	fs::create_dir_all(path.parent().unwrap())?;
+	let partial = NamedTempFile::new_in(path.parent().unwrap())?;
+	let mut hasher = Sha256::new();
+	let file = tokio::fs::File::from_std(partial.reopen()?);
+	let mut file = AsyncTeeWriter::new(file, &mut hasher);
+	let result = match progress {
+		Some((callback, layer)) => {
+			let mut writer = ProgressWriter::new(&mut file, callback, layer);
+			let result = client.pull_blob(image, descriptor, &mut writer).await;
+			if result.is_ok() {
+				writer.finish();
+			}
+			result
+		}
+		None => client.pull_blob(image, descriptor, &mut file).await,
+	};
+	// On errors, the temporary file is removed on drop.
+	result?;
+	file.flush().await?;
+	drop(file);
+	if format!("sha256:{:x}", hasher.finalize()) != *digest {
+		let digest = digest.to_owned();
+		return Err(Error::CorruptDownloadBlob { digest });
+	}
+	partial.persist(&path).map_err(|err| err.error)?;
+
+	Ok(path)
+}
+
+/// Adds a blob to the blob directory, while hashing it.  Returns the digest of the blob.
+///
+/// If a digest is expected, the blob is only added when it matches.
+pub(crate) fn add_blob_from_reader(
+	blob_dir: impl AsRef<Path>,
+	expected_digest: Option<&str>,
+	data: impl Read,
+) -> Result<String> {
+	if let Some(digest) = expected_digest {
+		check_digest(digest)?;
+	}
+
+	let mut hasher = Sha256::new();
+	let mut file = NamedTempFile::new_in(&blob_dir)?;
+	io::copy(&mut data.tee(&mut hasher), &mut file)?;
+	let digest = format!("sha256:{:x}", hasher.finalize());
+	if let Some(expected) = expected_digest.filter(|expected| *expected != digest) {
+		let digest = expected.to_owned();
+		return Err(Error::CorruptImportBlob { digest });
+	}
+
+	let path = blob_file(&blob_dir, &digest);
+	fs::create_dir_all(path.parent().unwrap())?;
+	file.persist(path).map_err(|err| err.error)?;
+	Ok(digest)
+}
+
+pub(crate) fn check_digest(digest: &str) -> Result<()> {
 	if !digest.starts_with("sha256:") {
 		return Err(Error::DigestNotSupported {
 			digest: digest.to_owned(),
 		});
 	}
+	Ok(())
+}
 
+/// Whether the blob file exists with the size of its descriptor.
+fn has_size(path: impl AsRef<Path>, size: i64) -> Result<bool> {
+	match fs::metadata(path) {
+		Ok(metadata) => Ok(metadata.is_file() && i64::try_from(metadata.len()) == Ok(size)),
+		Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+		Err(err) => Err(err.into()),
+	}
+}
+
+fn blob_file(blob_dir: impl AsRef<Path>, digest: &str) -> PathBuf {
 	let colon = digest.find(':').unwrap();
-	let blob_path = blob_path(blob_dir, digest, colon);
-	fs::create_dir_all(&blob_path)?;
-	Ok(fs::write(blob_path.join(&digest[colon + 1..]), data)?)
+	blob_path(blob_dir, digest, colon).join(&digest[colon + 1..])
 }
 
 fn read_blob(blob_dir: impl AsRef<Path>, digest: &str) -> Result<Vec<u8>> {
-	let colon = digest.find(':').unwrap();
-	let data = fs::read(blob_path(blob_dir, digest, colon).join(&digest[colon + 1..]))?;
+	let data = fs::read(blob_file(blob_dir, digest))?;
 
 	let actual = format!("sha256:{:x}", Sha256::digest(&data));
 	if actual != digest {
@@ -297,3 +647,417 @@ fn blob_path(blob_dir: impl AsRef<Path>, digest: &str, colon: usize) -> PathBuf
 		.join(&rest[..2])
 		.join(&rest[2..4])
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use std::sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	};
+
+	use serde_json::json;
+	use tempfile::TempDir;
+
+	use super::*;
+	use crate::{pull_and_unpack, IMAGE_LAYER_ZSTD_MEDIA_TYPE};
+
+	pub(crate) const IMAGE: &str = "example.org/cached:latest";
+
+	fn digest(data: &[u8]) -> String {
+		format!("sha256:{:x}", Sha256::digest(data))
+	}
+
+	/// Adds a single-layer image to a cache, returning the path of the layer blob.
+	pub(crate) fn cache_image(cache_dir: &Path, reference: &str, content: &str) -> PathBuf {
+		cache_layered_image(cache_dir, reference, &[content], &[]).remove(0)
+	}
+
+	/// Caches an image whose layers each write their content to `hello`, returning the
+	/// paths of the layer blobs.  Layers at `wrong_diff_ids` get a diff_id not matching them.
+	fn cache_layered_image(
+		cache_dir: &Path,
+		reference: &str,
+		contents: &[&str],
+		wrong_diff_ids: &[usize],
+	) -> Vec<PathBuf> {
+		let mut layers = Vec::new();
+		let mut diff_ids = Vec::new();
+		for (index, content) in contents.iter().enumerate() {
+			let mut builder = tar::Builder::new(Vec::new());
+			let mut header = tar::Header::new_gnu();
+			header.set_mode(0o644);
+			header.set_size(content.len() as u64);
+			builder
+				.append_data(&mut header, "hello", content.as_bytes())
+				.unwrap();
+			let tar = builder.into_inner().unwrap();
+			layers.push(zstd::encode_all(&tar[..], 0).unwrap());
+			diff_ids.push(match wrong_diff_ids.contains(&index) {
+				true => digest(b"wrong"),
+				false => digest(&tar),
+			});
+		}
+
+		let config = serde_json::to_vec(&json!({
+			"architecture": "amd64",
+			"os": "linux",
+			"config": { "Env": ["PATH=/bin"], "WorkingDir": "/work" },
+			"rootfs": { "type": "layers", "diff_ids": diff_ids },
+			"history": [],
+		}))
+		.unwrap();
+		let descriptors: Vec<_> = (layers.iter())
+			.map(|layer| {
+				json!({
+					"mediaType": IMAGE_LAYER_ZSTD_MEDIA_TYPE,
+					"digest": digest(layer),
+					"size": layer.len(),
+				})
+			})
+			.collect();
+		let manifest = serde_json::to_vec(&json!({
+			"schemaVersion": 2,
+			"mediaType": OCI_IMAGE_MEDIA_TYPE,
+			"config": {
+				"mediaType": "application/vnd.oci.image.config.v1+json",
+				"digest": digest(&config),
+				"size": config.len(),
+			},
+			"layers": descriptors,
+		}))
+		.unwrap();
+
+		if !cache_dir.join(INDEX_FILE).exists() {
+			init_cache(cache_dir).unwrap();
+		}
+		let blob_dir = cache_dir.join(BLOBS_DIR);
+		add_blob_to_cache(&blob_dir, &digest(&config), &config).unwrap();
+		for layer in &layers {
+			add_blob_to_cache(&blob_dir, &digest(layer), layer).unwrap();
+		}
+		let image = reference.parse().unwrap();
+		let platform = Platform::current();
+		add_image_to_cache(cache_dir, &image, &digest(&manifest), &platform, &manifest).unwrap();
+
+		(layers.iter())
+			.map(|layer| blob_file(&blob_dir, &digest(layer)))
+			.collect()
+	}
+
+	#[test]
+	fn unpack_from_cached_blobs() {
+		let cache_dir = TempDir::new().unwrap();
+		cache_image(cache_dir.path(), IMAGE, "hello\n");
+
+		let target = TempDir::new().unwrap();
+		let bundle = target.path().join("bundle");
+		let config = PullConfig {
+			cache: Some(cache_dir.path().to_owned()),
+			..PullConfig::default()
+		};
+		pull_and_unpack(&IMAGE.parse().unwrap(), &bundle, &config).unwrap();
+
+		let hello = fs::read_to_string(bundle.join("rootfs/hello")).unwrap();
+		assert_eq!(hello, "hello\n");
+	}
+
+	#[test]
+	fn unpack_layers_in_order() {
+		let cache_dir = TempDir::new().unwrap();
+		let contents = ["first\n", "second\n", "third\n"];
+		cache_layered_image(cache_dir.path(), IMAGE, &contents, &[]);
+
+		let target = TempDir::new().unwrap();
+		let bundle = target.path().join("bundle");
+		let config = PullConfig {
+			cache: Some(cache_dir.path().to_owned()),
+			..PullConfig::default()
+		};
+		pull_and_unpack(&IMAGE.parse().unwrap(), &bundle, &config).unwrap();
+
+		let hello = fs::read_to_string(bundle.join("rootfs/hello")).unwrap();
+		assert_eq!(hello, "third\n");
+	}
+
+	#[test]
+	fn unpack_verifies_diff_ids() {
+		let cache_dir = TempDir::new().unwrap();
+		let contents = ["first\n", "second\n", "third\n"];
+		cache_layered_image(cache_dir.path(), IMAGE, &contents, &[1]);
+
+		let target = TempDir::new().unwrap();
+		let config = PullConfig {
+			cache: Some(cache_dir.path().to_owned()),
+			..PullConfig::default()
+		};
+		let result = pull_and_unpack(&IMAGE.parse().unwrap(), target.path(), &config);
+		assert!(matches!(result, Err(Error::LayerDiffIdMismatch)));
+	}
+
+	#[test]
+	fn cache_hits_report_cached_layers() {
+		let cache_dir = TempDir::new().unwrap();
+		cache_image(cache_dir.path(), IMAGE, "hello\n");
+
+		let reports = Arc::new(Mutex::new(Vec::new()));
+		let callback = {
+			let reports = reports.clone();
+			ProgressCallback::new(move |progress| reports.lock().unwrap().push(progress.clone()))
+		};
+		let config = PullConfig {
+			cache: Some(cache_dir.path().to_owned()),
+			progress: Some(callback),
+			..PullConfig::default()
+		};
+		let image = crate::pull_image(&IMAGE.parse().unwrap(), &config).unwrap();
+
+		let reports = reports.lock().unwrap();
+		assert_eq!(reports.len(), 1);
+		assert_eq!(reports[0].state, LayerState::Cached);
+		assert_eq!(reports[0].digest, image.manifest().layers[0].digest);
+		assert_eq!(reports[0].downloaded, reports[0].size);
+	}
+
+	#[test]
+	fn corrupt_layer_blob() {
+		let cache_dir = TempDir::new().unwrap();
+		let layer_path = cache_image(cache_dir.path(), IMAGE, "hello\n");
+		fs::write(layer_path, "corrupt").unwrap();
+
+		let config = PullConfig {
+			cache: Some(cache_dir.path().to_owned()),
+			..PullConfig::default()
+		};
+		let result = Cache::new(&config).before_pull(&IMAGE.parse().unwrap());
+		assert!(matches!(result, Err(Error::CorruptCacheBlob { .. })));
+	}
+
+	#[test]
+	fn corrupt_layer_blob_fails_unpacking() {
+		let cache_dir = TempDir::new().unwrap();
+		let layer_path = cache_image(cache_dir.path(), IMAGE, "hello\n");
+		// Same size, so only unpacking notices.
+		let corrupt = vec![0; fs::metadata(&layer_path).unwrap().len() as usize];
+		fs::write(layer_path, corrupt).unwrap();
+
+		let target = TempDir::new().unwrap();
+		let config = PullConfig {
+			cache: Some(cache_dir.path().to_owned()),
+			..PullConfig::default()
+		};
+		let image = IMAGE.parse().unwrap();
+		assert!(Cache::new(&config).before_pull(&image).unwrap().is_some());
+		assert!(pull_and_unpack(&image, target.path().join("bundle"), &config).is_err());
+	}
+
+	#[test]
+	fn tags_are_cached_per_platform() {
+		let cache_dir = TempDir::new().unwrap();
+		cache_image(cache_dir.path(), IMAGE, "hello\n");
+		let images = list_images(cache_dir.path()).unwrap();
+		assert_eq!(images[0].platform, Some(Platform::current().to_string()));
+
+		let mut config = PullConfig {
+			cache: Some(cache_dir.path().to_owned()),
+			..PullConfig::default()
+		};
+		let image: Reference = IMAGE.parse().unwrap();
+		assert!(Cache::new(&config).before_pull(&image).unwrap().is_some());
+
+		config.platform = "linux/s390x".parse().unwrap();
+		assert!(Cache::new(&config).before_pull(&image).unwrap().is_none());
+
+		// A reference pinned to the manifest digest names the same image on every platform.
+		let pinned = format!("{IMAGE}@{}", images[0].manifest_digest);
+		cache_image(cache_dir.path(), &pinned, "hello\n");
+		let pinned: Reference = pinned.parse().unwrap();
+		assert!(Cache::new(&config).before_pull(&pinned).unwrap().is_some());
+	}
+
+	#[test]
+	fn list_and_remove_images() {
+		let cache_dir = TempDir::new().unwrap();
+		assert_eq!(list_images(cache_dir.path()).unwrap(), []);
+
+		cache_image(cache_dir.path(), IMAGE, "hello\n");
+		cache_image(cache_dir.path(), "example.org/other:1", "other\n");
+		let images = list_images(cache_dir.path()).unwrap();
+		let references = images.iter().map(|image| image.reference.as_str());
+		assert_eq!(
+			references.collect::<Vec<_>>(),
+			[IMAGE, "example.org/other:1"]
+		);
+		assert!(images.iter().all(|image| image.size > 0));
+
+		assert!(remove_image(cache_dir.path(), &IMAGE.parse().unwrap()).unwrap());
+		assert!(!remove_image(cache_dir.path(), &IMAGE.parse().unwrap()).unwrap());
+		let images = list_images(cache_dir.path()).unwrap();
+		assert_eq!(images.len(), 1);
+		assert_eq!(images[0].reference, "example.org/other:1");
+	}
+
+	#[test]
+	fn gc_removes_unreachable_blobs() {
+		let cache_dir = TempDir::new().unwrap();
+		cache_image(cache_dir.path(), IMAGE, "hello\n");
+		cache_image(cache_dir.path(), "example.org/other:1", "other\n");
+		let partial = cache_dir.path().join("blobs/sha256/00/00/0000.partial");
+		fs::create_dir_all(partial.parent().unwrap()).unwrap();
+		fs::write(&partial, "interrupted").unwrap();
+
+		let usage = disk_usage(cache_dir.path()).unwrap();
+		assert_eq!(usage.blobs, 7);
+		assert_eq!(usage.reclaimable_blobs, 1);
+		assert_eq!(usage.reclaimable_size, "interrupted".len() as u64);
+
+		remove_image(cache_dir.path(), &IMAGE.parse().unwrap()).unwrap();
+		let reclaimable = disk_usage(cache_dir.path()).unwrap().reclaimable_size;
+		let stats = gc(cache_dir.path()).unwrap();
+		assert_eq!(stats.removed_blobs, 4);
+		assert_eq!(stats.removed_size, reclaimable);
+		assert!(!cache_dir.path().join("blobs/sha256/00").exists());
+
+		let usage = disk_usage(cache_dir.path()).unwrap();
+		assert_eq!(usage.blobs, 3);
+		assert_eq!(usage.reclaimable_blobs, 0);
+		assert_eq!(gc(cache_dir.path()).unwrap(), GcStats::default());
+
+		// The remaining image is still intact.
+		let target = TempDir::new().unwrap();
+		let config = PullConfig {
+			cache: Some(cache_dir.path().to_owned()),
+			..PullConfig::default()
+		};
+		let image = "example.org/other:1".parse().unwrap();
+		pull_and_unpack(&image, target.path().join("bundle"), &config).unwrap();
+	}
+
+	#[test]
+	fn leftover_lock_files_do_not_block() {
+		let cache_dir = TempDir::new().unwrap();
+		cache_image(cache_dir.path(), IMAGE, "hello\n");
+		// Like a lock file of a killed process.
+		fs::write(cache_dir.path().join(INDEX_LOCK_FILE), "").unwrap();
+		fs::write(cache_dir.path().join(PULL_LOCK_FILE), "").unwrap();
+
+		assert!(remove_image(cache_dir.path(), &IMAGE.parse().unwrap()).unwrap());
+		assert_eq!(gc(cache_dir.path()).unwrap().removed_blobs, 3);
+	}
+
+	#[test]
+	fn cache_hits_share_the_index_lock() {
+		let cache_dir = TempDir::new().unwrap();
+		cache_image(cache_dir.path(), IMAGE, "hello\n");
+		let config = PullConfig {
+			cache: Some(cache_dir.path().to_owned()),
+			..PullConfig::default()
+		};
+
+		let reader = CacheLock::shared(cache_dir.path(), INDEX_LOCK_FILE).unwrap();
+		// A pull in progress does not block cache hits either.
+		let _pull = CacheLock::exclusive(cache_dir.path(), PULL_LOCK_FILE).unwrap();
+		let image_data = Cache::new(&config).before_pull(&IMAGE.parse().unwrap());
+		assert!(image_data.unwrap().is_some());
+
+		let writer = File::open(cache_dir.path().join(INDEX_LOCK_FILE)).unwrap();
+		assert!(writer.try_lock().is_err());
+		drop(reader);
+		assert!(writer.try_lock().is_ok());
+	}
+
+	#[test]
+	fn cache_hits_keep_their_blobs() {
+		let cache_dir = TempDir::new().unwrap();
+		let layer = cache_image(cache_dir.path(), IMAGE, "hello\n");
+		let config = PullConfig {
+			cache: Some(cache_dir.path().to_owned()),
+			..PullConfig::default()
+		};
+
+		let image_data = Cache::new(&config).before_pull(&IMAGE.parse().unwrap());
+		let image_data = image_data.unwrap().unwrap();
+		remove_image(cache_dir.path(), &IMAGE.parse().unwrap()).unwrap();
+		// gc has to wait until the image is unpacked.
+		let gc_lock = CacheLock::try_exclusive(cache_dir.path(), BLOBS_LOCK_FILE).unwrap();
+		assert!(gc_lock.is_none());
+		assert!(layer.exists());
+
+		drop(image_data);
+		assert_eq!(gc(cache_dir.path()).unwrap().removed_blobs, 3);
+		assert!(!layer.exists());
+	}
+
+	#[test]
+	fn index_is_replaced_atomically() {
+		let cache_dir = TempDir::new().unwrap();
+		init_cache(cache_dir.path()).unwrap();
+
+		// Readers without lock never see a partially written index, while it keeps growing.
+		let done = Arc::new(AtomicBool::new(false));
+		let reader = {
+			let cache_dir = cache_dir.path().to_owned();
+			let done = done.clone();
+			std::thread::spawn(move || {
+				let mut reads = 0;
+				while !done.load(Ordering::Relaxed) {
+					get_index(&cache_dir).unwrap();
+					reads += 1;
+				}
+				reads
+			})
+		};
+		let mut index = get_index(cache_dir.path()).unwrap();
+		for number in 0..200 {
+			let image = IndexImage {
+				manifest_digest: digest(number.to_string().as_bytes()),
+				platform: Some(Platform::current().to_string()),
+			};
+			index
+				.images
+				.insert(format!("example.org/image:{number}"), image);
+			write_index(cache_dir.path(), &index).unwrap();
+		}
+		done.store(true, Ordering::Relaxed);
+		assert!(reader.join().unwrap() > 0);
+
+		assert!(!cache_dir.path().join(INDEX_TEMP_FILE).exists());
+		assert_eq!(get_index(cache_dir.path()).unwrap().images.len(), 200);
+	}
+
+	#[tokio::test]
+	async fn corrupt_download_is_not_cached() {
+		// Serves other data than the blob for every request.
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		std::thread::spawn(move || {
+			for stream in listener.incoming() {
+				let mut stream = stream.unwrap();
+				let mut request = [0; 4096];
+				let _ = stream.read(&mut request);
+				let body = b"tampered";
+				let head = format!(
+					"HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+					body.len()
+				);
+				let _ = stream.write_all(head.as_bytes());
+				let _ = stream.write_all(body);
+			}
+		});
+
+		let blob_dir = TempDir::new().unwrap();
+		let client = oci_client::Client::new(oci_client::client::ClientConfig {
+			protocol: oci_client::client::ClientProtocol::Http,
+			..Default::default()
+		});
+		let image: Reference = format!("{address}/test/blob:latest").parse().unwrap();
+		let descriptor = OciDescriptor {
+			digest: digest(b"original"),
+			size: 8,
+			..Default::default()
+		};
+
+		let result = pull_blob(&client, &image, &descriptor, blob_dir.path(), None).await;
+		assert!(result.is_err());
+		assert!(!blob_file(blob_dir.path(), &descriptor.digest).exists());
+	}
+}
@@ -1,6 +1,8 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, num::NonZeroUsize, path::PathBuf};
 
-use oci_client::secrets::RegistryAuth;
+use oci_client::{secrets::RegistryAuth, Reference};
+
+use crate::{AuthConfig, Platform, ProgressCallback, RegistryMirror, RegistrySettings, Result};
 
 #[derive(Clone, Debug)]
 pub struct PullConfig {
@@ -13,7 +15,37 @@ pub struct PullConfig {
 	/// If no cache is specified, the images are always fetched from the registry.
 	pub cache: Option<PathBuf>,
 
+	/// Credentials used for all registries.
+	///
+	/// Unless they are [RegistryAuth::Anonymous], they take precedence over [Self::auth_config].
 	pub auth: RegistryAuth,
+
+	/// Credentials per registry, e.g. from the auth config files of docker and podman.
+	///
+	/// If no auth config is specified, images are pulled with [Self::auth].
+	pub auth_config: Option<AuthConfig>,
+
+	/// Platform whose manifest is selected, when an image reference points to an image index
+	/// or a Docker manifest list.
+	///
+	/// Defaults to the platform of the host (see [Platform::current]).
+	pub platform: Platform,
+
+	/// Callback receiving the progress of each layer, while images are pulled.
+	///
+	/// Layers of images found in the cache are only reported as [crate::LayerState::Cached].
+	pub progress: Option<ProgressCallback>,
+
+	/// Maximum number of layers downloaded at the same time.
+	pub max_concurrent_downloads: NonZeroUsize,
+
+	/// Mirrors images are pulled from, before falling back to their own registry.
+	pub mirrors: Vec<RegistryMirror>,
+
+	/// Connection settings per registry (including mirrors), by host and optional port.
+	///
+	/// Registries without settings are connected to via HTTPS with certificate verification.
+	pub registries: HashMap<String, RegistrySettings>,
 }
 
 impl Default for PullConfig {
@@ -21,6 +53,22 @@ impl Default for PullConfig {
 		Self {
 			cache: None,
 			auth: RegistryAuth::Anonymous,
+			auth_config: None,
+			platform: Platform::current(),
+			progress: None,
+			max_concurrent_downloads: NonZeroUsize::new(3).unwrap(),
+			mirrors: Vec::new(),
+			registries: HashMap::new(),
+		}
+	}
+}
+
+impl PullConfig {
+	/// Credentials for pulling an image from its registry.
+	pub fn registry_auth(&self, image: &Reference) -> Result<RegistryAuth> {
+		match (&self.auth, &self.auth_config) {
+			(RegistryAuth::Anonymous, Some(auth_config)) => auth_config.registry_auth(image),
+			(auth, _) => Ok(auth.clone()),
 		}
 	}
 }
@@ -37,12 +37,36 @@ pub enum Error {
 	#[error("config: unsupported rootfs.type: {typ}")]
 	UnsupportedRootFSType { typ: String },
 
-	#[error("failed to obtain image cache lock: '{0}'")]
-	CacheLockTimeout(PathBuf),
-
 	#[error("the following digest is not (yet) supported: {digest}")]
 	DigestNotSupported { digest: String },
 
 	#[error("cache blob data did not match digest: {digest}")]
 	CorruptCacheBlob { digest: String },
+
+	#[error("imported blob data did not match digest: {digest}")]
+	CorruptImportBlob { digest: String },
+
+	#[error("downloaded blob data did not match digest: {digest}")]
+	CorruptDownloadBlob { digest: String },
+
+	#[error("failed to parse registry auth file '{0}': {1}")]
+	ParseAuthFile(PathBuf, serde_json::Error),
+
+	#[error("invalid registry credentials for '{registry}': {reason}")]
+	InvalidCredentials { registry: String, reason: String },
+
+	#[error("credential helper '{helper}' failed: {reason}")]
+	CredentialHelper { helper: String, reason: String },
+
+	#[error("invalid platform '{0}': expected os/architecture[/variant]")]
+	InvalidPlatform(String),
+
+	#[error("an image cache is required")]
+	NoCache,
+
+	#[error("invalid registry mirror: {0}")]
+	InvalidMirror(String),
+
+	#[error("image index of {image} has no manifest for platform {platform}")]
+	PlatformNotFound { image: String, platform: String },
 }
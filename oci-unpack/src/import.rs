@@ -0,0 +1,564 @@
+//! Importing images into the cache without a registry.
+//!
+//! Supported are [OCI Image Layouts] (as directories or tar archives) and archives
+//! written by `docker save`, optionally compressed with gzip.  All blobs are verified
+//! against their digests while they are copied into the cache.
+//!
+//! [OCI Image Layouts]: https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+
+use std::{
+	fs::{self, File},
+	io::{BufRead, BufReader},
+	path::{Component, Path, PathBuf},
+};
+
+use flate2::bufread::GzDecoder;
+use oci_client::{
+	manifest::{
+		ImageIndexEntry, OciDescriptor, OciImageIndex, OciImageManifest, IMAGE_CONFIG_MEDIA_TYPE,
+		IMAGE_LAYER_MEDIA_TYPE, IMAGE_MANIFEST_LIST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE,
+		OCI_IMAGE_MEDIA_TYPE,
+	},
+	Reference,
+};
+use oci_spec::image::ImageConfiguration;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+
+use crate::cache::{self, BLOBS_DIR};
+use crate::{validate_layers, Error, Platform, Result};
+
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+const OCI_LAYOUT_INDEX_FILE: &str = "index.json";
+const DOCKER_MANIFEST_FILE: &str = "manifest.json";
+
+const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+const ANNOTATION_IMAGE_NAME: &str = "io.containerd.image.name";
+
+/// Image imported into the cache.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportedImage {
+	/// Reference the image is cached under.
+	///
+	/// The image is also cached under this reference pinned to the manifest digest.
+	pub reference: String,
+	pub manifest_digest: String,
+}
+
+/// Result of [import_images].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportedImages {
+	pub images: Vec<ImportedImage>,
+	/// Names of images which were skipped, because they are just a tag and no repository
+	/// was given.
+	pub skipped: Vec<String>,
+}
+
+/// Entry of the `manifest.json` written by `docker save`.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+	config: String,
+	repo_tags: Option<Vec<String>>,
+	layers: Vec<String>,
+}
+
+/// Imports the images of an OCI image layout or a `docker save` archive into the cache.
+///
+/// OCI image layouts can be directories or tar archives.  Images are cached under the
+/// reference in their `io.containerd.image.name` or `org.opencontainers.image.ref.name`
+/// annotation.  Names which are just a tag (e.g. `latest`, as written by `skopeo copy`)
+/// are combined with the given repository, or skipped without one.  Images of `docker save`
+/// archives are cached under their `RepoTags`.  Images without any name are skipped.
+///
+/// Images are cached for the platform in their configuration.
+///
+/// Docker archives don't contain manifests: a manifest is generated, so the manifest digest
+/// differs from the one in the registry.  Newer versions of Docker also write an OCI image
+/// layout into the archive, which is preferred.
+pub fn import_images(
+	path: impl AsRef<Path>,
+	cache_dir: impl AsRef<Path>,
+	repository: Option<&str>,
+) -> Result<ImportedImages> {
+	let (path, cache_dir) = (path.as_ref(), cache_dir.as_ref());
+	let _lock = cache::lock_pulls(cache_dir)?;
+	if path.is_dir() {
+		return import_dir(path, cache_dir, repository);
+	}
+
+	// Archives are extracted first, because their blobs are not necessarily ordered
+	// the way they are needed.  Extracting into the cache keeps big images off `/tmp`.
+	let temp_dir = TempDir::new_in(cache_dir)?;
+	let mut reader = BufReader::new(File::open(path)?);
+	if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+		tar::Archive::new(GzDecoder::new(reader)).unpack(temp_dir.path())?;
+	} else {
+		tar::Archive::new(reader).unpack(temp_dir.path())?;
+	}
+	import_dir(temp_dir.path(), cache_dir, repository)
+}
+
+fn import_dir(dir: &Path, cache_dir: &Path, repository: Option<&str>) -> Result<ImportedImages> {
+	if dir.join(OCI_LAYOUT_FILE).is_file() {
+		import_oci_layout(dir, cache_dir, repository)
+	} else if dir.join(DOCKER_MANIFEST_FILE).is_file() {
+		import_docker_archive(dir, cache_dir)
+	} else {
+		let reason = "neither an OCI image layout nor a docker archive".to_string();
+		Err(Error::ImageInvalid(reason))
+	}
+}
+
+fn import_oci_layout(
+	dir: &Path,
+	cache_dir: &Path,
+	repository: Option<&str>,
+) -> Result<ImportedImages> {
+	let index_data = fs::read(dir.join(OCI_LAYOUT_INDEX_FILE))?;
+	let index: OciImageIndex = serde_json::from_slice(&index_data).map_err(Error::ParseManifest)?;
+
+	let blob_dir = cache_dir.join(BLOBS_DIR);
+	let mut imported = ImportedImages::default();
+	for entry in &index.manifests {
+		let Some(name) = entry_name(entry) else {
+			continue;
+		};
+		let Some(reference) = name_reference(name, repository)? else {
+			imported.skipped.push(name.to_owned());
+			continue;
+		};
+
+		let (manifest_digest, manifest_data) = resolve_manifest(dir, entry)?;
+		let manifest: OciImageManifest =
+			serde_json::from_slice(&manifest_data).map_err(Error::ParseManifest)?;
+		validate_layers(&manifest)?;
+
+		let config_data = read_layout_blob(dir, &manifest.config.digest)?;
+		let config: ImageConfiguration =
+			serde_json::from_slice(&config_data).map_err(Error::ParseImageConfiguration)?;
+		cache::add_blob_to_cache(&blob_dir, &manifest.config.digest, &config_data)?;
+		for descriptor in &manifest.layers {
+			let file = File::open(layout_blob(dir, &descriptor.digest)?)?;
+			cache::add_blob_from_reader(&blob_dir, Some(&descriptor.digest), file)?;
+		}
+		imported.images.push(add_image(
+			cache_dir,
+			&reference,
+			&config_platform(&config),
+			&manifest_digest,
+			&manifest_data,
+		)?);
+	}
+	Ok(imported)
+}
+
+/// Name of an entry of the index of an OCI image layout.
+fn entry_name(entry: &ImageIndexEntry) -> Option<&String> {
+	let annotations = entry.annotations.as_ref()?;
+	(annotations.get(ANNOTATION_IMAGE_NAME)).or_else(|| annotations.get(ANNOTATION_REF_NAME))
+}
+
+/// Reference of an image name, combining names which are just a tag with the repository.
+///
+/// Returns `None` for such names without a repository.
+fn name_reference(name: &str, repository: Option<&str>) -> Result<Option<Reference>> {
+	if name.contains(['/', ':', '@']) {
+		return parse_reference(name).map(Some);
+	}
+	(repository.map(|repository| parse_reference(&format!("{repository}:{name}")))).transpose()
+}
+
+/// Reads the image manifest of an index entry, choosing the platform of multi-platform images.
+fn resolve_manifest(dir: &Path, entry: &ImageIndexEntry) -> Result<(String, Vec<u8>)> {
+	let data = read_layout_blob(dir, &entry.digest)?;
+	if entry.media_type != OCI_IMAGE_INDEX_MEDIA_TYPE
+		&& entry.media_type != IMAGE_MANIFEST_LIST_MEDIA_TYPE
+	{
+		return Ok((entry.digest.to_owned(), data));
+	}
+
+	let index: OciImageIndex = serde_json::from_slice(&data).map_err(Error::ParseManifest)?;
+	let Some(digest) = Platform::current().resolve(&index.manifests) else {
+		let reason = format!("no image for the current platform in {}", entry.digest);
+		return Err(Error::ImageInvalid(reason));
+	};
+	let data = read_layout_blob(dir, &digest)?;
+	Ok((digest, data))
+}
+
+fn import_docker_archive(dir: &Path, cache_dir: &Path) -> Result<ImportedImages> {
+	let manifests_data = fs::read(dir.join(DOCKER_MANIFEST_FILE))?;
+	let manifests: Vec<DockerManifest> =
+		serde_json::from_slice(&manifests_data).map_err(Error::ParseManifest)?;
+
+	let blob_dir = cache_dir.join(BLOBS_DIR);
+	let mut imported = ImportedImages::default();
+	for docker_manifest in manifests {
+		let repo_tags = docker_manifest.repo_tags.unwrap_or_default();
+		if repo_tags.is_empty() {
+			continue;
+		}
+
+		let config_data = fs::read(archive_path(dir, &docker_manifest.config)?)?;
+		let config: ImageConfiguration =
+			serde_json::from_slice(&config_data).map_err(Error::ParseImageConfiguration)?;
+		let config_digest = format!("sha256:{:x}", Sha256::digest(&config_data));
+		cache::add_blob_to_cache(&blob_dir, &config_digest, &config_data)?;
+
+		// The layers are uncompressed, so their digests are their diff_ids.
+		let diff_ids = config.rootfs().diff_ids();
+		if diff_ids.len() != docker_manifest.layers.len() {
+			let reason = "len(layers) != len(diff_ids)".to_string();
+			return Err(Error::ImageInvalid(reason));
+		}
+		let mut layers = Vec::with_capacity(diff_ids.len());
+		for (layer, diff_id) in docker_manifest.layers.iter().zip(diff_ids) {
+			let path = archive_path(dir, layer)?;
+			let size = fs::metadata(&path)?.len();
+			match cache::add_blob_from_reader(&blob_dir, Some(diff_id), File::open(path)?) {
+				Err(Error::CorruptImportBlob { .. }) => return Err(Error::LayerDiffIdMismatch),
+				result => result?,
+			};
+			layers.push(OciDescriptor {
+				media_type: IMAGE_LAYER_MEDIA_TYPE.to_owned(),
+				digest: diff_id.to_owned(),
+				size: size as i64,
+				..OciDescriptor::default()
+			});
+		}
+
+		let manifest = OciImageManifest {
+			media_type: Some(OCI_IMAGE_MEDIA_TYPE.to_owned()),
+			config: OciDescriptor {
+				media_type: IMAGE_CONFIG_MEDIA_TYPE.to_owned(),
+				digest: config_digest,
+				size: config_data.len() as i64,
+				..OciDescriptor::default()
+			},
+			layers,
+			..OciImageManifest::default()
+		};
+		let manifest_data = serde_json::to_vec(&manifest).unwrap();
+		let manifest_digest = format!("sha256:{:x}", Sha256::digest(&manifest_data));
+
+		for name in &repo_tags {
+			let reference = parse_reference(name)?;
+			imported.images.push(add_image(
+				cache_dir,
+				&reference,
+				&config_platform(&config),
+				&manifest_digest,
+				&manifest_data,
+			)?);
+		}
+	}
+	Ok(imported)
+}
+
+/// Adds an image to the cache index, also pinned to its manifest digest (with and without tag).
+fn add_image(
+	cache_dir: &Path,
+	reference: &Reference,
+	platform: &Platform,
+	manifest_digest: &str,
+	manifest_data: &[u8],
+) -> Result<ImportedImage> {
+	if reference
+		.digest()
+		.is_some_and(|digest| digest != manifest_digest)
+	{
+		let reason = format!("reference {reference} does not match digest {manifest_digest}");
+		return Err(Error::ImageInvalid(reason));
+	}
+
+	let mut references = vec![
+		reference.clone(),
+		reference.clone_with_digest(manifest_digest.to_owned()),
+	];
+	if reference.tag().is_some() && reference.digest().is_none() {
+		references.push(parse_reference(&format!(
+			"{}@{manifest_digest}",
+			reference.whole()
+		))?);
+	}
+	for reference in &references {
+		cache::add_image_to_cache(
+			cache_dir,
+			reference,
+			manifest_digest,
+			platform,
+			manifest_data,
+		)?;
+	}
+
+	Ok(ImportedImage {
+		reference: reference.whole(),
+		manifest_digest: manifest_digest.to_owned(),
+	})
+}
+
+/// Platform of an image, as it is matched when looking up tags in the cache.
+fn config_platform(config: &ImageConfiguration) -> Platform {
+	Platform {
+		os: config.os().to_string(),
+		architecture: config.architecture().to_string(),
+		variant: None,
+	}
+}
+
+fn parse_reference(name: &str) -> Result<Reference> {
+	name.parse().map_err(|err| {
+		let reason = format!("invalid image reference '{name}': {err}");
+		Error::ImageInvalid(reason)
+	})
+}
+
+/// Reads a blob of an OCI image layout, verifying its digest.
+fn read_layout_blob(dir: &Path, digest: &str) -> Result<Vec<u8>> {
+	let data = fs::read(layout_blob(dir, digest)?)?;
+	if format!("sha256:{:x}", Sha256::digest(&data)) != digest {
+		let digest = digest.to_owned();
+		return Err(Error::CorruptImportBlob { digest });
+	}
+	Ok(data)
+}
+
+/// Path of a blob within an OCI image layout: `blobs/<algorithm>/<encoded>`.
+fn layout_blob(dir: &Path, digest: &str) -> Result<PathBuf> {
+	match digest.split_once(':') {
+		Some(("sha256", encoded)) if encoded.chars().all(|c| c.is_ascii_hexdigit()) => {
+			Ok(dir.join(BLOBS_DIR).join("sha256").join(encoded))
+		}
+		_ => Err(Error::DigestNotSupported {
+			digest: digest.to_owned(),
+		}),
+	}
+}
+
+/// Path of a file named in the `manifest.json` of a docker archive, which must stay within it.
+fn archive_path(dir: &Path, name: &str) -> Result<PathBuf> {
+	let path = Path::new(name);
+	if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+		let reason = format!("path outside of the archive: {name}");
+		return Err(Error::ImageInvalid(reason));
+	}
+	Ok(dir.join(path))
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+	use crate::{list_images, pull_and_unpack, PullConfig};
+
+	fn digest(data: &[u8]) -> String {
+		format!("sha256:{:x}", Sha256::digest(data))
+	}
+
+	fn layer_tar(content: &str) -> Vec<u8> {
+		let mut builder = tar::Builder::new(Vec::new());
+		let mut header = tar::Header::new_gnu();
+		header.set_mode(0o644);
+		header.set_size(content.len() as u64);
+		builder
+			.append_data(&mut header, "hello", content.as_bytes())
+			.unwrap();
+		builder.into_inner().unwrap()
+	}
+
+	fn image_config(layer: &[u8], architecture: &str) -> Vec<u8> {
+		serde_json::to_vec(&json!({
+			"architecture": architecture,
+			"os": "linux",
+			"rootfs": { "type": "layers", "diff_ids": [digest(layer)] },
+			"history": [],
+		}))
+		.unwrap()
+	}
+
+	fn write_layout_blob(dir: &Path, data: &[u8]) -> String {
+		let digest = digest(data);
+		let path = layout_blob(dir, &digest).unwrap();
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		fs::write(path, data).unwrap();
+		digest
+	}
+
+	/// Writes an OCI image layout with a single image named by `ref_name`.
+	fn oci_layout(dir: &Path, ref_name: &str) -> String {
+		oci_layout_for(dir, ref_name, &Platform::current().architecture)
+	}
+
+	fn oci_layout_for(dir: &Path, ref_name: &str, architecture: &str) -> String {
+		let layer = layer_tar("from layout\n");
+		let config = image_config(&layer, architecture);
+		let manifest = serde_json::to_vec(&json!({
+			"schemaVersion": 2,
+			"mediaType": OCI_IMAGE_MEDIA_TYPE,
+			"config": {
+				"mediaType": IMAGE_CONFIG_MEDIA_TYPE,
+				"digest": write_layout_blob(dir, &config),
+				"size": config.len(),
+			},
+			"layers": [{
+				"mediaType": IMAGE_LAYER_MEDIA_TYPE,
+				"digest": write_layout_blob(dir, &layer),
+				"size": layer.len(),
+			}],
+		}))
+		.unwrap();
+		let manifest_digest = write_layout_blob(dir, &manifest);
+
+		let index = json!({
+			"schemaVersion": 2,
+			"manifests": [{
+				"mediaType": OCI_IMAGE_MEDIA_TYPE,
+				"digest": manifest_digest,
+				"size": manifest.len(),
+				"annotations": { ANNOTATION_REF_NAME: ref_name },
+			}],
+		});
+		fs::write(
+			dir.join(OCI_LAYOUT_FILE),
+			r#"{"imageLayoutVersion":"1.0.0"}"#,
+		)
+		.unwrap();
+		fs::write(dir.join(OCI_LAYOUT_INDEX_FILE), index.to_string()).unwrap();
+		manifest_digest
+	}
+
+	fn unpacked_hello(cache_dir: &Path, reference: &str) -> String {
+		let target = TempDir::new().unwrap();
+		let config = PullConfig {
+			cache: Some(cache_dir.to_owned()),
+			..PullConfig::default()
+		};
+		let bundle = target.path().join("bundle");
+		pull_and_unpack(&reference.parse().unwrap(), &bundle, &config).unwrap();
+		fs::read_to_string(bundle.join("rootfs/hello")).unwrap()
+	}
+
+	#[test]
+	fn import_oci_layout_dir() {
+		let layout = TempDir::new().unwrap();
+		let manifest_digest = oci_layout(layout.path(), "example.org/layout:1.0");
+
+		let cache_dir = TempDir::new().unwrap();
+		let imported = import_images(layout.path(), cache_dir.path(), None).unwrap();
+		assert_eq!(
+			imported.images,
+			[ImportedImage {
+				reference: "example.org/layout:1.0".into(),
+				manifest_digest: manifest_digest.clone(),
+			}]
+		);
+		assert_eq!(list_images(cache_dir.path()).unwrap().len(), 3);
+
+		for pinned in [
+			format!("example.org/layout:1.0@{manifest_digest}"),
+			format!("example.org/layout@{manifest_digest}"),
+		] {
+			assert_eq!(unpacked_hello(cache_dir.path(), &pinned), "from layout\n");
+		}
+	}
+
+	#[test]
+	fn import_oci_layout_archive() {
+		let layout = TempDir::new().unwrap();
+		oci_layout(layout.path(), "example.org/layout:1.0");
+		let mut builder = tar::Builder::new(Vec::new());
+		builder.append_dir_all(".", layout.path()).unwrap();
+		let archive = layout.path().join("layout.tar");
+		fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+		let cache_dir = TempDir::new().unwrap();
+		import_images(&archive, cache_dir.path(), None).unwrap();
+		let hello = unpacked_hello(cache_dir.path(), "example.org/layout:1.0");
+		assert_eq!(hello, "from layout\n");
+	}
+
+	#[test]
+	fn import_docker_archive() {
+		let layer = layer_tar("from docker\n");
+		let config = image_config(&layer, &Platform::current().architecture);
+		let manifest = json!([{
+			"Config": "config.json",
+			"RepoTags": ["example.org/docker:latest"],
+			"Layers": ["0123/layer.tar"],
+		}]);
+
+		let mut builder = tar::Builder::new(Vec::new());
+		for (path, data) in [
+			("manifest.json", manifest.to_string().into_bytes()),
+			("config.json", config),
+			("0123/layer.tar", layer),
+		] {
+			let mut header = tar::Header::new_gnu();
+			header.set_mode(0o644);
+			header.set_size(data.len() as u64);
+			builder.append_data(&mut header, path, &data[..]).unwrap();
+		}
+		let archive_dir = TempDir::new().unwrap();
+		let archive = archive_dir.path().join("docker.tar");
+		fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+		let cache_dir = TempDir::new().unwrap();
+		let imported = import_images(&archive, cache_dir.path(), None).unwrap();
+		assert_eq!(imported.images.len(), 1);
+		assert_eq!(imported.images[0].reference, "example.org/docker:latest");
+		let hello = unpacked_hello(cache_dir.path(), "example.org/docker:latest");
+		assert_eq!(hello, "from docker\n");
+	}
+
+	#[test]
+	fn import_verifies_digests() {
+		let layout = TempDir::new().unwrap();
+		oci_layout(layout.path(), "example.org/layout:1.0");
+		let layer_digest = digest(&layer_tar("from layout\n"));
+		let layer_path = layout_blob(layout.path(), &layer_digest).unwrap();
+		fs::write(layer_path, "tampered").unwrap();
+
+		let cache_dir = TempDir::new().unwrap();
+		let result = import_images(layout.path(), cache_dir.path(), None);
+		assert!(
+			matches!(result, Err(Error::CorruptImportBlob { digest }) if digest == layer_digest)
+		);
+		assert_eq!(list_images(cache_dir.path()).unwrap(), []);
+	}
+
+	#[test]
+	fn import_tag_only_names() {
+		let layout = TempDir::new().unwrap();
+		oci_layout(layout.path(), "1.0");
+
+		let cache_dir = TempDir::new().unwrap();
+		let imported = import_images(layout.path(), cache_dir.path(), None).unwrap();
+		assert_eq!(imported.images, []);
+		assert_eq!(imported.skipped, ["1.0"]);
+		assert_eq!(list_images(cache_dir.path()).unwrap(), []);
+
+		let repository = Some("example.org/layout");
+		let imported = import_images(layout.path(), cache_dir.path(), repository).unwrap();
+		assert_eq!(imported.images[0].reference, "example.org/layout:1.0");
+		assert_eq!(imported.skipped, Vec::<String>::new());
+		let hello = unpacked_hello(cache_dir.path(), "example.org/layout:1.0");
+		assert_eq!(hello, "from layout\n");
+	}
+
+	#[test]
+	fn import_records_image_platform() {
+		let layout = TempDir::new().unwrap();
+		oci_layout_for(layout.path(), "example.org/layout:1.0", "riscv64");
+
+		let cache_dir = TempDir::new().unwrap();
+		import_images(layout.path(), cache_dir.path(), None).unwrap();
+		let images = list_images(cache_dir.path()).unwrap();
+		assert_eq!(images.len(), 3);
+		for image in images {
+			assert_eq!(image.platform.as_deref(), Some("linux/riscv64"));
+		}
+	}
+}
@@ -0,0 +1,348 @@
+//! Applying layers onto a rootfs, including whiteouts.
+//!
+//! Whiteouts are handled like umoci does it, when it does not unpack for overlayfs:
+//!   - `.wh.<name>` removes `<name>` of lower layers, and is not unpacked itself.
+//!   - `.wh..wh..opq` removes all content of its directory from lower layers,
+//!     while content of the same layer is kept (no matter whether it came before or after).
+//!   - An entry replacing a lower-layer entry of a different type removes it first.
+//!     Directories are merged with lower-layer directories.
+
+use std::collections::{HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+
+use tar::{Archive, EntryType};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+/// Maximal number of symlinks followed, when resolving a path within the rootfs.
+const MAX_SYMLINKS: usize = 255;
+
+pub(crate) fn unpack_layer_archive(
+	archive: &mut Archive<impl Read>,
+	rootfs: &Path,
+) -> io::Result<()> {
+	let rootfs = rootfs.canonicalize()?;
+
+	// Paths created by this layer: opaque whiteouts must keep them.
+	let mut unpacked = HashSet::new();
+	// Delay directories until the end like [Archive::unpack], so their permissions
+	// don't interfere with unpacking their content.
+	let mut directories = Vec::new();
+
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		let path = entry.path()?.into_owned();
+		let Some(name) = path.file_name().map(OsStr::to_owned) else {
+			// The root directory itself.
+			continue;
+		};
+		let parent = secure_join(&rootfs, path.parent().unwrap_or(Path::new("")))?;
+
+		match name.to_str() {
+			Some(WHITEOUT_OPAQUE) => {
+				remove_lower_children(&parent, &unpacked)?;
+				continue;
+			}
+			Some(name) if name.starts_with(WHITEOUT_PREFIX) => {
+				let whited_out = parent.join(&name[WHITEOUT_PREFIX.len()..]);
+				remove_all(&whited_out)?;
+				continue;
+			}
+			_ => {}
+		}
+
+		let target = parent.join(&name);
+		let is_dir = entry.header().entry_type() == EntryType::Directory;
+		if let Ok(metadata) = target.symlink_metadata() {
+			if !(is_dir && metadata.is_dir()) {
+				remove_all(&target)?;
+			}
+		}
+
+		for ancestor in target.ancestors().take_while(|path| *path != rootfs) {
+			if !unpacked.insert(ancestor.to_owned()) {
+				break;
+			}
+		}
+		if is_dir {
+			directories.push(entry);
+		} else {
+			entry.unpack_in(&rootfs)?;
+		}
+	}
+
+	// Children before parents, just like [Archive::unpack] does, so the permissions and
+	// mtimes of parents are applied after their children are unpacked.
+	directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+	for mut directory in directories {
+		directory.unpack_in(&rootfs)?;
+	}
+
+	Ok(())
+}
+
+/// Removes everything in a directory, which was not unpacked from the current layer.
+fn remove_lower_children(dir: &Path, unpacked: &HashSet<PathBuf>) -> io::Result<()> {
+	let children = match fs::read_dir(dir) {
+		Ok(children) => children,
+		Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(err),
+	};
+	for child in children {
+		let path = child?.path();
+		if !unpacked.contains(&path) {
+			remove_all(&path)?;
+		}
+	}
+	Ok(())
+}
+
+/// Removes a file or a directory with all its content, without following symlinks.
+fn remove_all(path: &Path) -> io::Result<()> {
+	let result = match path.symlink_metadata() {
+		Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+		Ok(_) => fs::remove_file(path),
+		Err(err) => Err(err),
+	};
+	match result {
+		Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+		result => result,
+	}
+}
+
+/// Joins a path onto the rootfs, resolving symlinks as if the rootfs was `/`.
+///
+/// Like umoci's securejoin: the resulting path never escapes the rootfs, so removing
+/// it can't touch anything on the host.
+fn secure_join(rootfs: &Path, path: &Path) -> io::Result<PathBuf> {
+	let mut pending = components(path);
+	let mut resolved = PathBuf::new();
+	let mut symlinks = 0;
+
+	while let Some(component) = pending.pop_front() {
+		if component == ".." {
+			resolved.pop();
+			continue;
+		}
+
+		let candidate = resolved.join(&component);
+		let host_path = rootfs.join(&candidate);
+		match host_path.symlink_metadata() {
+			Ok(metadata) if metadata.file_type().is_symlink() => {
+				symlinks += 1;
+				if symlinks > MAX_SYMLINKS {
+					let msg = format!("too many symlinks resolving '{}'", path.display());
+					return Err(io::Error::new(ErrorKind::InvalidData, msg));
+				}
+				let link = fs::read_link(&host_path)?;
+				if link.is_absolute() {
+					resolved = PathBuf::new();
+				}
+				let mut link = components(&link);
+				link.append(&mut pending);
+				pending = link;
+			}
+			_ => resolved = candidate,
+		}
+	}
+
+	Ok(rootfs.join(resolved))
+}
+
+fn components(path: &Path) -> VecDeque<OsString> {
+	(path.components())
+		.filter_map(|component| match component {
+			Component::Normal(name) => Some(name.to_owned()),
+			Component::ParentDir => Some("..".into()),
+			Component::RootDir | Component::CurDir | Component::Prefix(_) => None,
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use sha2::{Digest, Sha256};
+	use tar::{Builder, Header};
+	use tempfile::TempDir;
+
+	use super::*;
+	use crate::unpack_layer_tar;
+
+	enum Entry<'a> {
+		Dir(&'a str),
+		File(&'a str, &'a str),
+		Symlink(&'a str, &'a str),
+	}
+
+	fn layer(entries: &[Entry]) -> Vec<u8> {
+		let mut builder = Builder::new(Vec::new());
+		for entry in entries {
+			let mut header = Header::new_gnu();
+			header.set_mtime(0);
+			match entry {
+				Entry::Dir(path) => {
+					header.set_entry_type(EntryType::Directory);
+					header.set_mode(0o755);
+					header.set_size(0);
+					builder.append_data(&mut header, path, io::empty()).unwrap();
+				}
+				Entry::File(path, content) => {
+					header.set_entry_type(EntryType::Regular);
+					header.set_mode(0o644);
+					header.set_size(content.len() as u64);
+					builder
+						.append_data(&mut header, path, content.as_bytes())
+						.unwrap();
+				}
+				Entry::Symlink(path, target) => {
+					header.set_entry_type(EntryType::Symlink);
+					header.set_mode(0o777);
+					header.set_size(0);
+					builder.append_link(&mut header, path, target).unwrap();
+				}
+			}
+		}
+		builder.into_inner().unwrap()
+	}
+
+	fn apply(rootfs: &Path, layers: &[Vec<u8>]) {
+		for layer in layers {
+			let diff_id = format!("sha256:{:x}", Sha256::digest(layer));
+			unpack_layer_tar(&layer[..], &diff_id, rootfs).unwrap();
+		}
+	}
+
+	fn tree(root: &Path) -> Vec<String> {
+		let mut paths = Vec::new();
+		let mut pending = vec![root.to_owned()];
+		while let Some(dir) = pending.pop() {
+			for child in fs::read_dir(dir).unwrap() {
+				let path = child.unwrap().path();
+				let relative = path
+					.strip_prefix(root)
+					.unwrap()
+					.to_str()
+					.unwrap()
+					.to_owned();
+				if path.symlink_metadata().unwrap().is_dir() {
+					paths.push(relative + "/");
+					pending.push(path);
+				} else {
+					paths.push(relative);
+				}
+			}
+		}
+		paths.sort();
+		paths
+	}
+
+	#[test]
+	fn whiteout_removes_lower_files_and_dirs() {
+		let rootfs = TempDir::new().unwrap();
+		apply(
+			rootfs.path(),
+			&[
+				layer(&[
+					Entry::Dir("etc/"),
+					Entry::File("etc/keep", "keep"),
+					Entry::File("etc/remove", "remove"),
+					Entry::Dir("var/"),
+					Entry::Dir("var/cache/"),
+					Entry::File("var/cache/file", "cached"),
+				]),
+				layer(&[
+					Entry::File("etc/.wh.remove", ""),
+					Entry::File("var/.wh.cache", ""),
+					Entry::File(".wh.missing", ""),
+				]),
+			],
+		);
+
+		assert_eq!(tree(rootfs.path()), ["etc/", "etc/keep", "var/"]);
+	}
+
+	#[test]
+	fn opaque_whiteout_keeps_same_layer_content() {
+		let rootfs = TempDir::new().unwrap();
+		apply(
+			rootfs.path(),
+			&[
+				layer(&[
+					Entry::Dir("opt/"),
+					Entry::File("opt/old", "old"),
+					Entry::Dir("opt/nested/"),
+					Entry::File("opt/nested/old", "old"),
+				]),
+				layer(&[
+					Entry::Dir("opt/"),
+					Entry::File("opt/before", "new"),
+					Entry::File("opt/.wh..wh..opq", ""),
+					Entry::File("opt/after", "new"),
+				]),
+			],
+		);
+
+		assert_eq!(tree(rootfs.path()), ["opt/", "opt/after", "opt/before"]);
+	}
+
+	#[test]
+	fn entries_replace_lower_entries_of_other_type() {
+		let rootfs = TempDir::new().unwrap();
+		apply(
+			rootfs.path(),
+			&[
+				layer(&[
+					Entry::Dir("a/"),
+					Entry::File("a/file", "in dir"),
+					Entry::File("b", "file"),
+					Entry::Dir("c/"),
+					Entry::File("c/lower", "lower"),
+				]),
+				layer(&[
+					Entry::File("a", "now a file"),
+					Entry::Dir("b/"),
+					Entry::Dir("c/"),
+					Entry::File("c/upper", "upper"),
+				]),
+			],
+		);
+
+		assert_eq!(tree(rootfs.path()), ["a", "b/", "c/", "c/lower", "c/upper"]);
+		assert_eq!(
+			fs::read_to_string(rootfs.path().join("a")).unwrap(),
+			"now a file"
+		);
+	}
+
+	#[test]
+	fn whiteouts_stay_within_rootfs() {
+		let host = TempDir::new().unwrap();
+		fs::write(host.path().join("precious"), "host file").unwrap();
+
+		let rootfs = TempDir::new().unwrap();
+		let host_path = host.path().to_str().unwrap();
+		apply(
+			rootfs.path(),
+			&[
+				layer(&[
+					Entry::Symlink("escape", host_path),
+					Entry::Symlink("lib", "usr/lib"),
+					Entry::Dir("usr/"),
+					Entry::Dir("usr/lib/"),
+					Entry::File("usr/lib/libfoo.so", "foo"),
+				]),
+				layer(&[
+					Entry::File("escape/.wh.precious", ""),
+					Entry::File("lib/.wh.libfoo.so", ""),
+				]),
+			],
+		);
+
+		assert!(host.path().join("precious").exists());
+		assert!(!rootfs.path().join("usr/lib/libfoo.so").exists());
+	}
+}
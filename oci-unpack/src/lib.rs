@@ -10,36 +10,58 @@
 //! [oci-client]: https://github.com/oras-project/rust-oci-client
 //! [umoci]: https://github.com/opencontainers/umoci/blob/8e665b719d0aff18dbf97a287f78faa6d0ef4f18/unpack.go
 
+mod auth;
 mod cache;
 mod config;
 mod error;
+mod import;
+mod layer;
+mod platform;
+mod progress;
+mod registry;
+mod rootfs;
 pub mod tee;
 
 use std::{
-	fs::{self},
-	io::Read,
-	path::Path,
+	fs::{self, File},
+	io::{self, BufReader, Read},
+	path::{Path, PathBuf},
+	sync::mpsc,
+	thread,
 	time::UNIX_EPOCH,
 };
 
-use cache::Cache;
+use cache::{Cache, CacheLock};
 use file_mode::ModePath;
 use filetime::set_file_times;
 use flate2::read::GzDecoder;
+use futures_util::{stream, StreamExt};
 use oci_client::{
-	client::{ClientConfig, ImageLayer},
+	errors::OciDistributionError,
 	manifest::{
-		OciImageManifest, IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE, IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE,
-		IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE, IMAGE_MANIFEST_LIST_MEDIA_TYPE,
-		IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+		OciImageManifest, OciManifest, IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+		IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE, IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE,
+		IMAGE_MANIFEST_LIST_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE,
+		OCI_IMAGE_MEDIA_TYPE,
 	},
+	secrets::RegistryAuth,
 	Client, Reference,
 };
 use oci_spec::image::ImageConfiguration;
 use sha2::{Digest, Sha256};
+use tempfile::TempDir;
 
+pub use crate::auth::AuthConfig;
+pub use crate::cache::{
+	disk_usage, gc, list_images, remove_image, CachedImage, DiskUsage, GcStats,
+};
 pub use crate::config::PullConfig;
 pub use crate::error::{Error, Result};
+pub use crate::import::{import_images, ImportedImage, ImportedImages};
+pub use crate::platform::Platform;
+pub use crate::progress::{LayerProgress, LayerState, ProgressCallback};
+pub use crate::registry::{RegistryMirror, RegistrySettings};
+pub use crate::rootfs::{pull_and_unpack_rootfs, RootfsLock, UnpackedRootfs};
 use crate::tee::ReadExt;
 
 const MANIFEST_MEDIA_TYPES: &[&str] = &[
@@ -49,10 +71,13 @@ const MANIFEST_MEDIA_TYPES: &[&str] = &[
 	OCI_IMAGE_INDEX_MEDIA_TYPE,
 ];
 
-// TODO: Consider adding ZSTD support in addition to TAR and GZIP.
+/// Media type of zstd compressed layers (not defined by [oci_client]).
+pub const IMAGE_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+
 const LAYER_MEDIA_TYPES: &[&str] = &[
 	IMAGE_LAYER_MEDIA_TYPE,
 	IMAGE_LAYER_GZIP_MEDIA_TYPE,
+	IMAGE_LAYER_ZSTD_MEDIA_TYPE,
 	IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE,
 	IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
 ];
@@ -61,37 +86,85 @@ fn is_gzip(media_type: &str) -> bool {
 	media_type == IMAGE_LAYER_GZIP_MEDIA_TYPE || media_type == IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE
 }
 
+fn is_zstd(media_type: &str) -> bool {
+	media_type == IMAGE_LAYER_ZSTD_MEDIA_TYPE
+}
+
 pub struct BundleInfo {
 	pub manifest: OciImageManifest,
 	pub manifest_digest: String,
+	/// Configuration of the image, e.g. the environment and working directory of its processes.
+	pub config: ImageConfiguration,
 }
 
+/// Pulls an image and unpacks it into a bundle.
+///
+/// Layers are unpacked in order on a separate thread, as soon as they and all layers
+/// before them are downloaded, so unpacking overlaps with downloading the later layers.
 pub fn pull_and_unpack(
 	image: &Reference,
 	target: impl AsRef<Path>,
 	config: &PullConfig,
 ) -> Result<BundleInfo> {
-	let image_data = pull_image(image, config)?;
-
-	let manifest = image_data.manifest.unwrap();
-	let manifest_digest = image_data.digest.unwrap();
-	let config: ImageConfiguration =
-		serde_json::from_slice(&image_data.config.data).map_err(Error::ParseImageConfiguration)?;
-
-	let image_data = ImageData {
-		manifest,
-		manifest_digest,
-		layers: image_data.layers,
-		config,
+	let rootfs_dir = create_bundle(&target)?;
+	// Outlives the unpacker thread, which reads the blobs until it is joined.
+	let (blob_dir, _temp_dir) = blob_dir(config)?;
+
+	let (sender, receiver) = mpsc::channel::<(LayerBlob, String)>();
+	let mut unpacking_stopped = false;
+	let (pulled, unpacked) = thread::scope(|scope| {
+		let unpacker = scope.spawn(|| -> Result<()> {
+			for (layer, diff_id) in receiver {
+				unpack_layer(&layer, &diff_id, &rootfs_dir)?;
+			}
+			Ok(())
+		});
+		let pulled = pull_image_layers(image, config, &blob_dir, |layer, diff_id| {
+			// Only fails when unpacking failed: its error is returned below.
+			let sent = sender.send((layer.clone(), diff_id.to_owned()));
+			unpacking_stopped = sent.is_err();
+			sent.map_err(|_| Error::IO(io::Error::other("unpacking stopped")))
+		});
+		drop(sender);
+		(pulled, unpacker.join().unwrap())
+	});
+	let image_data = match pulled {
+		// The error of unpacking made the pull stop.
+		Err(_) if unpacking_stopped => return Err(unpacked.unwrap_err()),
+		pulled => pulled?,
 	};
+	unpacked?;
 
-	unpack(target, image_data)
+	Ok(BundleInfo {
+		manifest: image_data.manifest,
+		manifest_digest: image_data.manifest_digest,
+		config: image_data.config,
+	})
 }
 
 pub fn unpack(
 	target: impl AsRef<Path>,
 	image_data: ImageData,
 ) -> std::result::Result<BundleInfo, Error> {
+	let rootfs_dir = create_bundle(&target)?;
+
+	let diff_ids = diff_ids(&image_data.config, image_data.layers.len())?;
+	for (layer, diff_id) in image_data.layers.iter().zip(diff_ids) {
+		unpack_layer(layer, diff_id, &rootfs_dir)?;
+	}
+
+	// TODO: Should we unpack a config.json here or do we create
+	// that from scratch using the warpforge build instructions?
+
+	Ok(BundleInfo {
+		manifest: image_data.manifest,
+		manifest_digest: image_data.manifest_digest,
+		config: image_data.config,
+	})
+}
+
+/// Creates the bundle directory with an empty rootfs, returning the path of the rootfs.
+fn create_bundle(target: impl AsRef<Path>) -> Result<PathBuf> {
 	fs::create_dir_all(&target)?;
 	let is_empty = target.as_ref().read_dir()?.next().is_none();
 	if !is_empty {
@@ -117,79 +190,284 @@ pub fn unpack(
 	// (which is as good of an arbitrary choice as any)."
 	set_file_times(&target, UNIX_EPOCH.into(), UNIX_EPOCH.into())?;
 
-	let rootfs_config = image_data.config.rootfs();
+	Ok(rootfs_dir)
+}
+
+/// The diff_ids of the layers of an image, which are verified while unpacking them.
+fn diff_ids(config: &ImageConfiguration, layers: usize) -> Result<&[String]> {
+	let rootfs_config = config.rootfs();
 	if rootfs_config.typ() != "layers" {
 		let typ = rootfs_config.typ().to_string();
 		return Err(Error::UnsupportedRootFSType { typ });
 	}
 
 	let diff_ids = rootfs_config.diff_ids();
-	if image_data.layers.len() != diff_ids.len() {
+	if layers != diff_ids.len() {
 		let reason = "len(layers) != len(diff_ids)".to_string();
 		return Err(Error::ImageInvalid(reason));
 	}
+	Ok(diff_ids)
+}
 
-	for (layer, diff_id) in image_data.layers.iter().zip(diff_ids) {
-		unpack_layer(layer, diff_id, &rootfs_dir)?;
+/// Pulled image, whose layers are stored in blob files.
+///
+/// Layers are only read from their files while unpacking, so memory use does not
+/// depend on the size of the image.
+pub struct ImageData {
+	manifest: OciImageManifest,
+	manifest_digest: String,
+	layers: Vec<LayerBlob>,
+	config: ImageConfiguration,
+	/// Holds the blob files, when the image was pulled without a cache.
+	_blob_dir: Option<TempDir>,
+	/// Keeps the blob files in the cache, until the image is unpacked.
+	_blobs_lock: Option<CacheLock>,
+}
+
+impl ImageData {
+	pub fn manifest(&self) -> &OciImageManifest {
+		&self.manifest
 	}
 
-	// TODO: Should we unpack a config.json here or do we create
-	// that from scratch using the warpforge build instructions?
+	pub fn manifest_digest(&self) -> &str {
+		&self.manifest_digest
+	}
+}
 
-	Ok(BundleInfo {
-		manifest: image_data.manifest,
-		manifest_digest: image_data.manifest_digest,
+#[derive(Clone)]
+pub(crate) struct LayerBlob {
+	path: PathBuf,
+	media_type: String,
+}
+
+/// Pulls an image, streaming its blobs into files.
+///
+/// With a cache, the blobs are downloaded directly into its `blobs/` directory.
+/// Otherwise, they are stored in a temporary directory, which is removed
+/// together with the returned [ImageData].
+///
+/// Up to [PullConfig::max_concurrent_downloads] layers are downloaded at the same time.
+pub fn pull_image(image: &Reference, config: &PullConfig) -> Result<ImageData> {
+	let (blob_dir, temp_dir) = blob_dir(config)?;
+	let image_data = pull_image_layers(image, config, &blob_dir, |_, _| Ok(()))?;
+	Ok(ImageData {
+		_blob_dir: temp_dir,
+		..image_data
 	})
 }
 
-pub struct ImageData {
-	manifest: OciImageManifest,
-	manifest_digest: String,
-	layers: Vec<ImageLayer>,
-	config: ImageConfiguration,
+/// Directory the blobs of pulled images are downloaded into: the `blobs/` directory of
+/// the cache, or else a temporary directory, which must be kept until the blobs are used.
+fn blob_dir(config: &PullConfig) -> Result<(PathBuf, Option<TempDir>)> {
+	match Cache::new(config).blob_dir() {
+		Some(blob_dir) => Ok((blob_dir, None)),
+		None => {
+			let temp_dir = TempDir::new()?;
+			Ok((temp_dir.path().to_owned(), Some(temp_dir)))
+		}
+	}
 }
 
+/// Pulls an image into the blob directory, passing each layer with its diff_id to
+/// `on_layer` in order, as soon as it and all layers before it are downloaded.
 #[tokio::main]
-pub async fn pull_image(
+async fn pull_image_layers(
 	image: &Reference,
 	config: &PullConfig,
-) -> Result<oci_client::client::ImageData> {
+	blob_dir: &Path,
+	mut on_layer: impl FnMut(&LayerBlob, &str) -> Result<()>,
+) -> Result<ImageData> {
 	let mut cache = Cache::new(config);
 	if let Some(image_data) = cache.before_pull(image)? {
+		let diff_ids = diff_ids(&image_data.config, image_data.layers.len())?;
+		for (index, (layer, diff_id)) in image_data.layers.iter().zip(diff_ids).enumerate() {
+			if let Some(callback) = &config.progress {
+				let descriptor = &image_data.manifest.layers[index];
+				let size = descriptor.size.max(0) as u64;
+				callback.report(&LayerProgress {
+					index,
+					layers: image_data.layers.len(),
+					digest: descriptor.digest.clone(),
+					size,
+					downloaded: size,
+					state: LayerState::Cached,
+				});
+			}
+			on_layer(layer, diff_id)?;
+		}
 		return Ok(image_data);
 	}
 
-	let client = Client::new(ClientConfig::default());
-	let media_types = LAYER_MEDIA_TYPES.to_vec();
-
-	let image_data = client.pull(image, &config.auth, media_types).await?;
+	let (source, manifest, manifest_digest) = resolve_source(image, config).await?;
+	let ImageSource {
+		reference: source,
+		client,
+		auth,
+	} = source;
+	validate_layers(&manifest)?;
+
+	let config_path = cache::pull_blob(&client, &source, &manifest.config, blob_dir, None).await?;
+	let image_config: ImageConfiguration =
+		serde_json::from_reader(BufReader::new(File::open(config_path)?))
+			.map_err(Error::ParseImageConfiguration)?;
+	let diff_ids = diff_ids(&image_config, manifest.layers.len())?;
+
+	// Downloads run concurrently, while `buffered` yields them in the order of the layers.
+	let mut downloads = stream::iter(manifest.layers.iter().enumerate())
+		.map(|(index, layer)| {
+			let progress = (config.progress.as_ref()).map(|callback| {
+				let progress = LayerProgress {
+					index,
+					layers: manifest.layers.len(),
+					digest: layer.digest.clone(),
+					size: layer.size.max(0) as u64,
+					downloaded: 0,
+					state: LayerState::Downloading,
+				};
+				(callback, progress)
+			});
+			cache::pull_blob(&client, &source, layer, blob_dir, progress)
+		})
+		.buffered(config.max_concurrent_downloads.get());
+
+	let mut layers = Vec::with_capacity(manifest.layers.len());
+	for (layer, diff_id) in manifest.layers.iter().zip(diff_ids) {
+		let path = downloads.next().await.expect("one download per layer")?;
+		let layer = LayerBlob {
+			path,
+			media_type: layer.media_type.clone(),
+		};
+		on_layer(&layer, diff_id)?;
+		layers.push(layer);
+	}
+	drop(downloads);
 
-	cache
-		.after_pull(image, &image_data, &client, config)
+	let blobs_lock = cache
+		.after_pull(image, &source, &manifest, &manifest_digest, &client, &auth)
 		.await?;
 
-	Ok(image_data)
+	Ok(ImageData {
+		manifest,
+		manifest_digest,
+		layers,
+		config: image_config,
+		_blob_dir: None,
+		_blobs_lock: blobs_lock,
+	})
 }
 
+fn validate_layers(manifest: &OciImageManifest) -> Result<()> {
+	if manifest.layers.is_empty() {
+		return Err(OciDistributionError::PullNoLayersError.into());
+	}
+	for layer in &manifest.layers {
+		if !LAYER_MEDIA_TYPES.contains(&layer.media_type.as_str()) {
+			let media_type = layer.media_type.clone();
+			return Err(OciDistributionError::IncompatibleLayerMediaTypeError(media_type).into());
+		}
+	}
+	Ok(())
+}
+
+/// Resolves the digest of the image manifest a reference points to.
+///
+/// If the reference points to an image index or a Docker manifest list, the digest of
+/// the manifest for [PullConfig::platform] is returned instead of the digest of the index.
 #[tokio::main]
 pub async fn pull_image_manifest(image: &Reference, config: &PullConfig) -> Result<String> {
 	let mut cache = Cache::new(config);
 	if let Some(image_data) = cache.before_pull(image)? {
-		return Ok(image_data.digest.unwrap());
+		return Ok(image_data.manifest_digest);
+	}
+
+	let (_, _, digest) = resolve_source(image, config).await?;
+	Ok(digest)
+}
+
+/// Registry an image is pulled from, which is either the one of the image or a mirror.
+struct ImageSource {
+	reference: Reference,
+	client: Client,
+	auth: RegistryAuth,
+}
+
+/// Resolves the image manifest from the first source of the image serving it.
+///
+/// Mirrors (see [PullConfig::mirrors]) are tried in order before the registry of the image.
+/// Only failures to download the manifest make it fall back to the next source.
+async fn resolve_source(
+	image: &Reference,
+	config: &PullConfig,
+) -> Result<(ImageSource, OciImageManifest, String)> {
+	let mut result = None;
+	for reference in config.image_sources(image)? {
+		let client = config.client(&reference);
+		let auth = config.registry_auth(&reference)?;
+		match resolve_manifest(&client, &reference, &auth, &config.platform).await {
+			Ok((manifest, digest)) => {
+				let source = ImageSource {
+					reference,
+					client,
+					auth,
+				};
+				return Ok((source, manifest, digest));
+			}
+			Err(err @ Error::DownloadFailed(_)) => result = Some(Err(err)),
+			Err(err) => return Err(err),
+		}
 	}
+	result.expect("the image itself is always a source")
+}
 
-	let client = Client::new(ClientConfig::default());
-	let manifest = client
-		.pull_manifest_raw(image, &config.auth, MANIFEST_MEDIA_TYPES)
+async fn pull_manifest(
+	client: &Client,
+	image: &Reference,
+	auth: &RegistryAuth,
+) -> Result<(OciManifest, String)> {
+	let (manifest, digest) = client
+		.pull_manifest_raw(image, auth, MANIFEST_MEDIA_TYPES)
 		.await?;
-	Ok(manifest.1)
+	let manifest = serde_json::from_slice(&manifest).map_err(Error::ParseManifest)?;
+	Ok((manifest, digest))
+}
+
+/// Pulls the image manifest of a reference, selecting the manifest for the platform from indexes.
+async fn resolve_manifest(
+	client: &Client,
+	image: &Reference,
+	auth: &RegistryAuth,
+	platform: &Platform,
+) -> Result<(OciImageManifest, String)> {
+	let (manifest, digest) = pull_manifest(client, image, auth).await?;
+	let index = match manifest {
+		OciManifest::Image(manifest) => return Ok((manifest, digest)),
+		OciManifest::ImageIndex(index) => index,
+	};
+
+	let Some(digest) = platform.resolve(&index.manifests) else {
+		return Err(Error::PlatformNotFound {
+			image: image.whole(),
+			platform: platform.to_string(),
+		});
+	};
+	let platform_image = image.clone_with_digest(digest.clone());
+	match pull_manifest(client, &platform_image, auth).await?.0 {
+		OciManifest::Image(manifest) => Ok((manifest, digest)),
+		OciManifest::ImageIndex(_) => {
+			let reason = format!("index entry {digest} of {image} is an index itself");
+			Err(Error::ImageInvalid(reason))
+		}
+	}
 }
 
-fn unpack_layer(layer: &ImageLayer, diff_id: &str, target: impl AsRef<Path>) -> Result<()> {
+fn unpack_layer(layer: &LayerBlob, diff_id: &str, target: impl AsRef<Path>) -> Result<()> {
+	let data = BufReader::new(File::open(&layer.path)?);
 	if is_gzip(&layer.media_type) {
-		unpack_layer_gzip(&layer.data[..], diff_id, target)
+		unpack_layer_gzip(data, diff_id, target)
+	} else if is_zstd(&layer.media_type) {
+		unpack_layer_zstd(data, diff_id, target)
 	} else {
-		unpack_layer_tar(&layer.data[..], diff_id, target)
+		unpack_layer_tar(data, diff_id, target)
 	}
 }
 
@@ -198,6 +476,11 @@ fn unpack_layer_gzip(data: impl Read, diff_id: &str, target: impl AsRef<Path>) -
 	unpack_layer_tar(decoder, diff_id, target)
 }
 
+fn unpack_layer_zstd(data: impl Read, diff_id: &str, target: impl AsRef<Path>) -> Result<()> {
+	let decoder = zstd::Decoder::new(data)?;
+	unpack_layer_tar(decoder, diff_id, target)
+}
+
 fn unpack_layer_tar(data: impl Read, diff_id: &str, target: impl AsRef<Path>) -> Result<()> {
 	if !diff_id.starts_with("sha256:") {
 		let algorithm = diff_id.split(':').next().unwrap_or("none");
@@ -207,15 +490,14 @@ fn unpack_layer_tar(data: impl Read, diff_id: &str, target: impl AsRef<Path>) ->
 	let mut digester = Sha256::new();
 
 	let mut read = data.tee(&mut digester);
-	tar::Archive::new(&mut read).unpack(&target)?;
+	layer::unpack_layer_archive(&mut tar::Archive::new(&mut read), target.as_ref())?;
 
 	// From opencontainers/umoci:
 	// "Different tar implementations can have different levels of redundant
 	// padding and other similar weird behaviours. While on paper they are
 	// all entirely valid archives [...]. Just blindly consume anything left
 	// in the layer."
-	let mut buffer = Vec::new();
-	let length = read.read_to_end(&mut buffer)?;
+	let length = io::copy(&mut read, &mut io::sink())?;
 
 	let diff_id = &diff_id["sha256:".len()..];
 	let computed = format!("{:x}", digester.finalize());
@@ -228,3 +510,59 @@ fn unpack_layer_tar(data: impl Read, diff_id: &str, target: impl AsRef<Path>) ->
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+
+	use flate2::{write::GzEncoder, Compression};
+	use tempfile::TempDir;
+
+	use super::*;
+
+	fn layer_tar() -> Vec<u8> {
+		let mut builder = tar::Builder::new(Vec::new());
+		let mut header = tar::Header::new_gnu();
+		header.set_mode(0o644);
+		header.set_size(6);
+		builder
+			.append_data(&mut header, "hello", &b"hello\n"[..])
+			.unwrap();
+		builder.into_inner().unwrap()
+	}
+
+	fn check_unpack(data: Vec<u8>, media_type: &str) {
+		let tar = layer_tar();
+		let diff_id = format!("sha256:{:x}", Sha256::digest(&tar));
+		let blob_dir = TempDir::new().unwrap();
+		let layer = LayerBlob {
+			path: blob_dir.path().join("layer"),
+			media_type: media_type.into(),
+		};
+		fs::write(&layer.path, &data).unwrap();
+
+		let target = TempDir::new().unwrap();
+		unpack_layer(&layer, &diff_id, target.path()).unwrap();
+		let hello = fs::read_to_string(target.path().join("hello")).unwrap();
+		assert_eq!(hello, "hello\n");
+
+		// The diff_id is verified over the decompressed tar stream.
+		let wrong_diff_id = format!("sha256:{:x}", Sha256::digest(&data));
+		let target = TempDir::new().unwrap();
+		let result = unpack_layer(&layer, &wrong_diff_id, target.path());
+		assert!(matches!(result, Err(Error::LayerDiffIdMismatch)));
+	}
+
+	#[test]
+	fn unpack_gzip_layer() {
+		let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(&layer_tar()).unwrap();
+		check_unpack(encoder.finish().unwrap(), IMAGE_LAYER_GZIP_MEDIA_TYPE);
+	}
+
+	#[test]
+	fn unpack_zstd_layer() {
+		let data = zstd::encode_all(&layer_tar()[..], 0).unwrap();
+		check_unpack(data, IMAGE_LAYER_ZSTD_MEDIA_TYPE);
+	}
+}
@@ -0,0 +1,159 @@
+//! Platforms of multi-platform images, which are selected when pulling from image indexes.
+
+use std::{fmt, str::FromStr};
+
+use oci_client::manifest::ImageIndexEntry;
+
+use crate::Error;
+
+/// Platform of an image, as used by the entries of image indexes and Docker manifest lists.
+///
+/// Values are the ones of Go's `GOOS` and `GOARCH`, e.g. `linux/amd64` or `linux/arm/v7`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Platform {
+	pub os: String,
+	pub architecture: String,
+	/// Variant of the CPU, e.g. `v7` for `arm`.
+	///
+	/// Without a variant, the first entry matching the os and architecture is selected.
+	pub variant: Option<String>,
+}
+
+impl Platform {
+	/// Platform of the host.
+	pub fn current() -> Self {
+		let os = match std::env::consts::OS {
+			"macos" => "darwin",
+			os => os,
+		};
+		let architecture = match std::env::consts::ARCH {
+			"x86_64" => "amd64",
+			"x86" => "386",
+			"aarch64" => "arm64",
+			"powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+			"powerpc64" => "ppc64",
+			"loongarch64" => "loong64",
+			arch => arch,
+		};
+		Platform {
+			os: os.to_owned(),
+			architecture: architecture.to_owned(),
+			variant: None,
+		}
+	}
+
+	/// Digest of the manifest for this platform in the entries of an image index.
+	pub(crate) fn resolve(&self, manifests: &[ImageIndexEntry]) -> Option<String> {
+		(manifests.iter())
+			.find(|entry| {
+				entry.platform.as_ref().is_some_and(|platform| {
+					platform.os == self.os
+						&& platform.architecture == self.architecture
+						&& self.matches_variant(platform.variant.as_deref())
+				})
+			})
+			.map(|entry| entry.digest.clone())
+	}
+
+	fn matches_variant(&self, variant: Option<&str>) -> bool {
+		match (self.variant.as_deref(), variant) {
+			(None, _) => true,
+			(Some(expected), Some(variant)) => expected == variant,
+			// v8 is the only variant of arm64, so index entries often omit it.
+			(Some(expected), None) => self.architecture == "arm64" && expected == "v8",
+		}
+	}
+}
+
+impl Default for Platform {
+	fn default() -> Self {
+		Self::current()
+	}
+}
+
+impl fmt::Display for Platform {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}", self.os, self.architecture)?;
+		if let Some(variant) = &self.variant {
+			write!(f, "/{variant}")?;
+		}
+		Ok(())
+	}
+}
+
+impl FromStr for Platform {
+	type Err = Error;
+
+	/// Parses a platform of the form `os/architecture[/variant]`.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let parts: Vec<&str> = s.split('/').collect();
+		if parts.iter().any(|part| part.is_empty()) {
+			return Err(Error::InvalidPlatform(s.to_owned()));
+		}
+		match parts[..] {
+			[os, architecture] | [os, architecture, _] => Ok(Platform {
+				os: os.to_owned(),
+				architecture: architecture.to_owned(),
+				variant: parts.get(2).map(|variant| (*variant).to_owned()),
+			}),
+			_ => Err(Error::InvalidPlatform(s.to_owned())),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use oci_client::manifest::{ImageIndexEntry, Platform as IndexPlatform};
+
+	use super::*;
+
+	fn entry(digest: &str, platform: &str) -> ImageIndexEntry {
+		let platform: Platform = platform.parse().unwrap();
+		ImageIndexEntry {
+			media_type: oci_client::manifest::OCI_IMAGE_MEDIA_TYPE.to_owned(),
+			digest: digest.to_owned(),
+			size: 0,
+			platform: Some(IndexPlatform {
+				architecture: platform.architecture,
+				os: platform.os,
+				os_version: None,
+				os_features: None,
+				variant: platform.variant,
+				features: None,
+			}),
+			annotations: None,
+		}
+	}
+
+	#[test]
+	fn parse_platforms() {
+		let platform: Platform = "linux/arm/v7".parse().unwrap();
+		assert_eq!(platform.variant.as_deref(), Some("v7"));
+		assert_eq!(platform.to_string(), "linux/arm/v7");
+		assert_eq!(
+			"linux/amd64".parse::<Platform>().unwrap().to_string(),
+			"linux/amd64"
+		);
+		for invalid in ["linux", "linux/", "linux/arm/v7/extra", "/amd64"] {
+			assert!(invalid.parse::<Platform>().is_err(), "{invalid}");
+		}
+	}
+
+	#[test]
+	fn resolve_index_entries() {
+		let manifests = [
+			entry("sha256:amd64", "linux/amd64"),
+			entry("sha256:armv6", "linux/arm/v6"),
+			entry("sha256:armv7", "linux/arm/v7"),
+			entry("sha256:arm64", "linux/arm64"),
+		];
+		let resolve = |platform: &str| platform.parse::<Platform>().unwrap().resolve(&manifests);
+
+		assert_eq!(resolve("linux/amd64").as_deref(), Some("sha256:amd64"));
+		assert_eq!(resolve("linux/arm/v7").as_deref(), Some("sha256:armv7"));
+		assert_eq!(resolve("linux/arm").as_deref(), Some("sha256:armv6"));
+		assert_eq!(resolve("linux/arm64/v8").as_deref(), Some("sha256:arm64"));
+		assert_eq!(resolve("linux/s390x"), None);
+		assert_eq!(resolve("windows/amd64"), None);
+	}
+}
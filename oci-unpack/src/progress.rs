@@ -0,0 +1,162 @@
+//! Progress reporting of image pulls.
+
+use std::{
+	fmt,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+};
+
+use tokio::io::AsyncWrite;
+
+/// Minimal number of downloaded bytes between two [LayerState::Downloading] reports.
+const REPORT_INTERVAL: u64 = 256 * 1024;
+
+/// Progress of a single layer of a pulled image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerProgress {
+	/// Position of the layer in the image manifest.
+	pub index: usize,
+	/// Number of layers of the image.
+	pub layers: usize,
+	pub digest: String,
+	/// Size of the layer in bytes, as specified by the image manifest.
+	pub size: u64,
+	/// Bytes of the layer downloaded so far.
+	pub downloaded: u64,
+	pub state: LayerState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayerState {
+	/// The layer is already in the cache and is not downloaded.
+	Cached,
+	/// The layer is being downloaded.
+	Downloading,
+	/// The layer was downloaded completely.
+	Done,
+}
+
+/// Callback receiving the progress of the layers of image pulls (see [crate::PullConfig::progress]).
+///
+/// Layers may be reported from other threads than the one that started the pull.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(&LayerProgress) + Send + Sync>);
+
+impl ProgressCallback {
+	pub fn new(callback: impl Fn(&LayerProgress) + Send + Sync + 'static) -> Self {
+		Self(Arc::new(callback))
+	}
+
+	pub(crate) fn report(&self, progress: &LayerProgress) {
+		(self.0)(progress)
+	}
+}
+
+impl fmt::Debug for ProgressCallback {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("ProgressCallback")
+	}
+}
+
+/// Writer reporting the bytes written through it as download progress of a layer.
+pub(crate) struct ProgressWriter<'a, W> {
+	inner: W,
+	callback: &'a ProgressCallback,
+	progress: LayerProgress,
+	reported: u64,
+}
+
+impl<'a, W> ProgressWriter<'a, W> {
+	pub(crate) fn new(inner: W, callback: &'a ProgressCallback, progress: LayerProgress) -> Self {
+		callback.report(&progress);
+		Self {
+			inner,
+			callback,
+			progress,
+			reported: 0,
+		}
+	}
+
+	/// Reports the layer as downloaded completely.
+	pub(crate) fn finish(mut self) {
+		self.progress.state = LayerState::Done;
+		self.callback.report(&self.progress);
+	}
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ProgressWriter<'_, W> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<std::io::Result<usize>> {
+		let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(written)) = poll {
+			self.progress.downloaded += written as u64;
+			if self.progress.downloaded - self.reported >= REPORT_INTERVAL {
+				self.reported = self.progress.downloaded;
+				self.callback.report(&self.progress);
+			}
+		}
+		poll
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Mutex;
+
+	use tokio::io::AsyncWriteExt;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn writer_reports_downloaded_bytes() {
+		let reports = Arc::new(Mutex::new(Vec::new()));
+		let callback = {
+			let reports = reports.clone();
+			ProgressCallback::new(move |progress| {
+				let mut reports = reports.lock().unwrap();
+				reports.push((progress.downloaded, progress.state));
+			})
+		};
+		let size = 3 * REPORT_INTERVAL;
+		let progress = LayerProgress {
+			index: 0,
+			layers: 1,
+			digest: "sha256:layer".into(),
+			size,
+			downloaded: 0,
+			state: LayerState::Downloading,
+		};
+
+		let mut data = Vec::new();
+		let mut writer = ProgressWriter::new(&mut data, &callback, progress);
+		for _ in 0..(size / 1024) {
+			writer.write_all(&[0; 1024]).await.unwrap();
+		}
+		writer.finish();
+
+		assert_eq!(data.len() as u64, size);
+		use LayerState::*;
+		assert_eq!(
+			*reports.lock().unwrap(),
+			[
+				(0, Downloading),
+				(REPORT_INTERVAL, Downloading),
+				(2 * REPORT_INTERVAL, Downloading),
+				(size, Downloading),
+				(size, Done),
+			]
+		);
+	}
+}
@@ -0,0 +1,281 @@
+//! Registry mirrors and connection settings of registries.
+
+use oci_client::{
+	client::{ClientConfig, ClientProtocol},
+	Client, Reference,
+};
+
+use crate::{Error, PullConfig, Result};
+
+/// Rule redirecting the images below a prefix to mirrors.
+///
+/// Images are pulled from the first mirror which serves their manifest, falling back to
+/// the original registry if none does.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegistryMirror {
+	/// Registry, optionally followed by a repository path, e.g. `docker.io` or `docker.io/library`.
+	///
+	/// Only whole path segments match: `docker.io/lib` does not match `docker.io/library/busybox`.
+	pub prefix: String,
+
+	/// Locations replacing the prefix, in the order they are tried,
+	/// e.g. `mirror.example.org/dockerhub`.
+	pub mirrors: Vec<String>,
+}
+
+/// How to connect to a registry.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegistrySettings {
+	/// Talk plain HTTP instead of HTTPS, e.g. for a local registry.
+	pub http: bool,
+
+	/// Accept invalid TLS certificates, e.g. self-signed ones.
+	pub accept_invalid_certificates: bool,
+}
+
+impl PullConfig {
+	/// References an image is pulled from: its mirrors in order, followed by the image itself.
+	///
+	/// If multiple [RegistryMirror] rules match, the one with the longest prefix is used.
+	pub fn image_sources(&self, image: &Reference) -> Result<Vec<Reference>> {
+		let name = format!("{}/{}", image.registry(), image.repository());
+		let rule = (self.mirrors.iter())
+			.filter(|rule| strip_prefix(&name, &rule.prefix).is_some())
+			.max_by_key(|rule| rule.prefix.trim_end_matches('/').len());
+
+		let mut sources = Vec::new();
+		if let Some(rule) = rule {
+			let rest = strip_prefix(&name, &rule.prefix).unwrap();
+			for mirror in &rule.mirrors {
+				let mut reference = format!("{}{rest}", mirror.trim_end_matches('/'));
+				if let Some(tag) = image.tag() {
+					reference = format!("{reference}:{tag}");
+				}
+				if let Some(digest) = image.digest() {
+					reference = format!("{reference}@{digest}");
+				}
+				let reference = reference.parse().map_err(|err| {
+					let reason = format!("mirror '{mirror}' of '{}': {err}", rule.prefix);
+					Error::InvalidMirror(reason)
+				})?;
+				sources.push(reference);
+			}
+		}
+		sources.push(image.clone());
+		Ok(sources)
+	}
+
+	/// Client for pulling from the registry of an image, using the settings of the registry.
+	pub(crate) fn client(&self, image: &Reference) -> Client {
+		let settings = (self.registries.get(image.registry())).cloned();
+		let settings = settings.unwrap_or_default();
+		Client::new(ClientConfig {
+			protocol: match settings.http {
+				true => ClientProtocol::Http,
+				false => ClientProtocol::Https,
+			},
+			accept_invalid_certificates: settings.accept_invalid_certificates,
+			..ClientConfig::default()
+		})
+	}
+}
+
+/// Remainder of an image name after a prefix, if the prefix matches whole path segments.
+fn strip_prefix<'a>(name: &'a str, prefix: &str) -> Option<&'a str> {
+	let rest = name.strip_prefix(prefix.trim_end_matches('/'))?;
+	(rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::HashMap,
+		fs,
+		io::{BufRead, BufReader, Write},
+		net::TcpListener,
+		sync::Arc,
+		thread,
+	};
+
+	use serde_json::json;
+	use sha2::{Digest, Sha256};
+	use tempfile::TempDir;
+
+	use super::*;
+	use crate::pull_and_unpack;
+
+	fn digest(data: &[u8]) -> String {
+		format!("sha256:{:x}", Sha256::digest(data))
+	}
+
+	fn config_with_mirrors(prefix: &str, mirrors: &[&str]) -> PullConfig {
+		PullConfig {
+			mirrors: vec![RegistryMirror {
+				prefix: prefix.to_owned(),
+				mirrors: mirrors.iter().map(|mirror| (*mirror).to_owned()).collect(),
+			}],
+			..PullConfig::default()
+		}
+	}
+
+	fn sources(config: &PullConfig, image: &str) -> Vec<String> {
+		let sources = config.image_sources(&image.parse().unwrap()).unwrap();
+		sources.iter().map(Reference::whole).collect()
+	}
+
+	/// Serves the manifest and blobs of a single-layer image over plain HTTP, like a registry
+	/// allowing anonymous pulls would.  Returns the address of the registry.
+	fn serve_image(repository: &str, tag: &str, content: &str) -> String {
+		let mut builder = tar::Builder::new(Vec::new());
+		let mut header = tar::Header::new_gnu();
+		header.set_mode(0o644);
+		header.set_size(content.len() as u64);
+		builder
+			.append_data(&mut header, "hello", content.as_bytes())
+			.unwrap();
+		let layer = builder.into_inner().unwrap();
+		let config = serde_json::to_vec(&json!({
+			"architecture": "amd64",
+			"os": "linux",
+			"rootfs": { "type": "layers", "diff_ids": [digest(&layer)] },
+			"history": [],
+		}))
+		.unwrap();
+		let manifest = serde_json::to_vec(&json!({
+			"schemaVersion": 2,
+			"mediaType": oci_client::manifest::OCI_IMAGE_MEDIA_TYPE,
+			"config": {
+				"mediaType": oci_client::manifest::IMAGE_CONFIG_MEDIA_TYPE,
+				"digest": digest(&config),
+				"size": config.len(),
+			},
+			"layers": [{
+				"mediaType": oci_client::manifest::IMAGE_LAYER_MEDIA_TYPE,
+				"digest": digest(&layer),
+				"size": layer.len(),
+			}],
+		}))
+		.unwrap();
+
+		let mut routes = HashMap::new();
+		routes.insert("/v2/".to_owned(), b"{}".to_vec());
+		for reference in [tag.to_owned(), digest(&manifest)] {
+			let path = format!("/v2/{repository}/manifests/{reference}");
+			routes.insert(path, manifest.clone());
+		}
+		for blob in [config, layer] {
+			routes.insert(format!("/v2/{repository}/blobs/{}", digest(&blob)), blob);
+		}
+		let routes = Arc::new(routes);
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap().to_string();
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				let routes = routes.clone();
+				thread::spawn(move || {
+					let mut stream = stream.unwrap();
+					let mut reader = BufReader::new(&stream);
+					let mut request_line = String::new();
+					reader.read_line(&mut request_line).unwrap();
+					let mut line = String::new();
+					while reader.read_line(&mut line).unwrap() > 2 {
+						line.clear();
+					}
+
+					let path = request_line.split(' ').nth(1).unwrap_or_default();
+					let (status, body) = match routes.get(path) {
+						Some(body) => ("200 OK", body.clone()),
+						None => ("404 Not Found", br#"{"errors":[]}"#.to_vec()),
+					};
+					let content_type = match path.contains("/manifests/") {
+						true => oci_client::manifest::OCI_IMAGE_MEDIA_TYPE,
+						false => "application/octet-stream",
+					};
+					let head = format!(
+						"HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n\
+						Content-Length: {}\r\nConnection: close\r\n\r\n",
+						body.len()
+					);
+					let _ = stream.write_all(head.as_bytes());
+					let _ = stream.write_all(&body);
+				});
+			}
+		});
+		address
+	}
+
+	#[test]
+	fn mirror_sources() {
+		let config = config_with_mirrors("docker.io", &["mirror.local/hub", "other.local"]);
+		assert_eq!(
+			sources(&config, "busybox:1.36"),
+			[
+				"mirror.local/hub/library/busybox:1.36",
+				"other.local/library/busybox:1.36",
+				"docker.io/library/busybox:1.36",
+			]
+		);
+		assert_eq!(
+			sources(&config, "quay.io/busybox"),
+			["quay.io/busybox:latest"]
+		);
+
+		let mut config = config_with_mirrors("docker.io/library/", &["mirror.local/library"]);
+		config.mirrors.push(RegistryMirror {
+			prefix: "docker.io".into(),
+			mirrors: vec!["mirror.local/hub".into()],
+		});
+		let pinned = format!("busybox@{}", digest(b"manifest"));
+		assert_eq!(
+			sources(&config, &pinned),
+			[
+				format!("mirror.local/library/busybox@{}", digest(b"manifest")),
+				format!("docker.io/library/busybox@{}", digest(b"manifest")),
+			]
+		);
+		assert_eq!(
+			sources(&config, "docker.io/libraryx/busybox:1"),
+			[
+				"mirror.local/hub/libraryx/busybox:1",
+				"docker.io/libraryx/busybox:1"
+			]
+		);
+	}
+
+	#[test]
+	fn invalid_mirror() {
+		let config = config_with_mirrors("docker.io", &["Invalid Mirror"]);
+		let result = config.image_sources(&"busybox".parse().unwrap());
+		assert!(matches!(result, Err(Error::InvalidMirror(_))));
+	}
+
+	#[test]
+	fn pull_from_http_mirror_after_unreachable_one() {
+		let address = serve_image("mirror/library/hello", "latest", "hello\n");
+		let unreachable = {
+			let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+			listener.local_addr().unwrap().to_string()
+		};
+
+		let mut config = config_with_mirrors(
+			"registry.invalid",
+			&[
+				&format!("{unreachable}/mirror"),
+				&format!("{address}/mirror"),
+			],
+		);
+		let http = RegistrySettings {
+			http: true,
+			..RegistrySettings::default()
+		};
+		config.registries.insert(unreachable, http.clone());
+		config.registries.insert(address, http);
+
+		let target = TempDir::new().unwrap();
+		let image = "registry.invalid/library/hello:latest".parse().unwrap();
+		pull_and_unpack(&image, target.path(), &config).unwrap();
+		let hello = fs::read_to_string(target.path().join("rootfs/hello")).unwrap();
+		assert_eq!(hello, "hello\n");
+	}
+}
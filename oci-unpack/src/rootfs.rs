@@ -0,0 +1,258 @@
+//! Store of unpacked root filesystems in the cache, keyed by manifest digest.
+//!
+//! The `rootfs/` directory of the cache contains a bundle directory per unpacked image at
+//! `rootfs/<algorithm>/<hex>`, whose `rootfs/` is shared by all containers using the image.
+//! Next to it, `image-config.json` holds the configuration of the image.
+//! Images are unpacked into a temporary directory next to the bundles first, which is renamed
+//! once unpacking succeeded, so a bundle in the store is always complete.
+//!
+//! Users of a root filesystem hold a shared lock on the `bundle.lock` of its bundle, which
+//! keeps [crate::gc] from removing the bundle while it is still mounted.
+
+use std::{
+	collections::HashSet,
+	fs, io,
+	path::{Path, PathBuf},
+};
+
+use oci_client::Reference;
+use oci_spec::image::ImageConfiguration;
+
+use crate::cache::{self, CacheLock};
+use crate::{pull_and_unpack, pull_image_manifest, Error, PullConfig, Result};
+
+pub(crate) const ROOTFS_DIR: &str = "rootfs";
+/// Held shared while looking up or unpacking into the store, and exclusively by [crate::gc].
+pub(crate) const ROOTFS_LOCK_FILE: &str = "rootfs.lock";
+/// Held shared by the users of a bundle (see [RootfsLock]).
+const BUNDLE_LOCK_FILE: &str = "bundle.lock";
+pub(crate) const UNPACK_TEMP_PREFIX: &str = ".unpack-";
+const IMAGE_CONFIG_FILE: &str = "image-config.json";
+
+/// Root filesystem of an image in the store of the cache.
+#[derive(Debug)]
+pub struct UnpackedRootfs {
+	/// Path of the root filesystem, which is shared and therefore must not be modified:
+	/// it is meant to be used read-only, e.g. as lower directory of an overlayfs.
+	pub path: PathBuf,
+	pub manifest_digest: String,
+	/// Configuration of the image, e.g. the environment and working directory of its processes.
+	pub config: ImageConfiguration,
+	/// Keeps the root filesystem in the store: it has to be held as long as it is used.
+	pub lock: RootfsLock,
+}
+
+/// Shared lock on a root filesystem in the store, which [crate::gc] does not remove
+/// while it is held.  Released when dropped.
+pub struct RootfsLock {
+	_lock: CacheLock,
+}
+
+impl std::fmt::Debug for RootfsLock {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("RootfsLock").finish_non_exhaustive()
+	}
+}
+
+/// Pulls an image and unpacks it into the rootfs store of [PullConfig::cache],
+/// unless the image was unpacked already.
+///
+/// References pinned to the digest of an unpacked image are found without pulling.
+/// The root filesystem is locked (see [UnpackedRootfs::lock]) before it is returned.
+pub fn pull_and_unpack_rootfs(image: &Reference, config: &PullConfig) -> Result<UnpackedRootfs> {
+	let Some(cache_dir) = &config.cache else {
+		return Err(Error::NoCache);
+	};
+
+	let manifest_digest = match image.digest() {
+		Some(digest) => digest.to_owned(),
+		None => pull_image_manifest(image, config)?,
+	};
+	let store = cache_dir.join(ROOTFS_DIR);
+	fs::create_dir_all(&store)?;
+	// Keeps gc out, until the bundle is locked by itself.
+	let _lock = CacheLock::shared(cache_dir, ROOTFS_LOCK_FILE)?;
+	if let Some(rootfs) = unpacked_rootfs(cache_dir, &manifest_digest)? {
+		return Ok(rootfs);
+	}
+
+	let temp_dir = tempfile::Builder::new()
+		.prefix(UNPACK_TEMP_PREFIX)
+		.tempdir_in(&store)?;
+	let temp_bundle = temp_dir.path().join("bundle");
+	let bundle = pull_and_unpack(image, &temp_bundle, config)?;
+	let config_file = fs::File::create(temp_bundle.join(IMAGE_CONFIG_FILE))?;
+	serde_json::to_writer(config_file, &bundle.config).map_err(io::Error::from)?;
+
+	let bundle_dir = bundle_dir(cache_dir, &bundle.manifest_digest)?;
+	fs::create_dir_all(bundle_dir.parent().unwrap())?;
+	if let Err(err) = fs::rename(&temp_bundle, &bundle_dir) {
+		// Another process unpacked the same image meanwhile, which has the same content.
+		if !bundle_dir.is_dir() {
+			return Err(err.into());
+		}
+	}
+
+	Ok(UnpackedRootfs {
+		path: bundle_dir.join("rootfs"),
+		manifest_digest: bundle.manifest_digest,
+		config: bundle.config,
+		lock: RootfsLock {
+			_lock: CacheLock::shared(&bundle_dir, BUNDLE_LOCK_FILE)?,
+		},
+	})
+}
+
+/// Root filesystem of an image, if it is in the store already.
+///
+/// Must only be called with a shared lock on `rootfs.lock`.
+fn unpacked_rootfs(cache_dir: &Path, manifest_digest: &str) -> Result<Option<UnpackedRootfs>> {
+	let bundle_dir = bundle_dir(cache_dir, manifest_digest)?;
+	let path = bundle_dir.join("rootfs");
+	if !path.is_dir() {
+		return Ok(None);
+	}
+	let config_file = fs::File::open(bundle_dir.join(IMAGE_CONFIG_FILE))?;
+	let config = serde_json::from_reader(io::BufReader::new(config_file))
+		.map_err(Error::ParseImageConfiguration)?;
+	Ok(Some(UnpackedRootfs {
+		path,
+		manifest_digest: manifest_digest.to_owned(),
+		config,
+		lock: RootfsLock {
+			_lock: CacheLock::shared(&bundle_dir, BUNDLE_LOCK_FILE)?,
+		},
+	}))
+}
+
+fn bundle_dir(cache_dir: &Path, manifest_digest: &str) -> Result<PathBuf> {
+	cache::check_digest(manifest_digest)?;
+	let (algorithm, hex) = manifest_digest.split_once(':').unwrap();
+	Ok(cache_dir.join(ROOTFS_DIR).join(algorithm).join(hex))
+}
+
+/// Removes the bundles of images not referenced anymore and leftovers of interrupted
+/// unpacking, returning the number of removed bundles.
+///
+/// Bundles which are still in use (see [RootfsLock]) are kept.
+///
+/// Must only be called with an exclusive lock on `rootfs.lock`.
+pub(crate) fn remove_unreferenced(cache_dir: &Path, referenced: HashSet<&str>) -> Result<usize> {
+	let store = cache_dir.join(ROOTFS_DIR);
+	if !store.is_dir() {
+		return Ok(0);
+	}
+
+	let mut removed = 0;
+	for entry in fs::read_dir(store)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().into_owned();
+		if name.starts_with(UNPACK_TEMP_PREFIX) {
+			fs::remove_dir_all(entry.path())?;
+			continue;
+		}
+
+		// Only the directories of the digest algorithms are checked for being empty:
+		// empty directories within a rootfs are part of the image.
+		for bundle in fs::read_dir(entry.path())? {
+			let bundle = bundle?;
+			let digest = format!("{name}:{}", bundle.file_name().to_string_lossy());
+			if referenced.contains(digest.as_str()) {
+				continue;
+			}
+			let Some(_lock) = CacheLock::try_exclusive(bundle.path(), BUNDLE_LOCK_FILE)? else {
+				continue;
+			};
+			fs::remove_dir_all(bundle.path())?;
+			removed += 1;
+		}
+		if fs::read_dir(entry.path())?.next().is_none() {
+			fs::remove_dir(entry.path())?;
+		}
+	}
+	Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+	use tempfile::TempDir;
+
+	use super::*;
+	use crate::cache::tests::{cache_image, IMAGE};
+	use crate::{gc, remove_image, GcStats};
+
+	fn cached_config(cache_dir: &Path) -> PullConfig {
+		PullConfig {
+			cache: Some(cache_dir.to_owned()),
+			..PullConfig::default()
+		}
+	}
+
+	#[test]
+	fn rootfs_is_unpacked_once() {
+		let cache_dir = TempDir::new().unwrap();
+		let layer_path = cache_image(cache_dir.path(), IMAGE, "hello\n");
+		let config = cached_config(cache_dir.path());
+
+		let rootfs = pull_and_unpack_rootfs(&IMAGE.parse().unwrap(), &config).unwrap();
+		assert_eq!(
+			fs::read_to_string(rootfs.path.join("hello")).unwrap(),
+			"hello\n"
+		);
+		assert!(rootfs.path.starts_with(cache_dir.path().join(ROOTFS_DIR)));
+		let again = pull_and_unpack_rootfs(&IMAGE.parse().unwrap(), &config).unwrap();
+		assert_eq!(again.path, rootfs.path);
+		assert_eq!(again.manifest_digest, rootfs.manifest_digest);
+		let image_config = rootfs.config.config().as_ref().unwrap();
+		assert_eq!(image_config.working_dir().as_deref(), Some("/work"));
+
+		// Unpacking again would fail on the corrupt layer.
+		fs::write(layer_path, "corrupt").unwrap();
+		let pinned = format!("{IMAGE}@{}", rootfs.manifest_digest);
+		let again = pull_and_unpack_rootfs(&pinned.parse().unwrap(), &config).unwrap();
+		assert_eq!(again.path, rootfs.path);
+
+		let store = cache_dir.path().join(ROOTFS_DIR);
+		let entries = fs::read_dir(store)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name());
+		assert_eq!(entries.collect::<Vec<_>>(), ["sha256"]);
+	}
+
+	#[test]
+	fn rootfs_requires_cache() {
+		let result = pull_and_unpack_rootfs(&IMAGE.parse().unwrap(), &PullConfig::default());
+		assert!(matches!(result, Err(Error::NoCache)));
+	}
+
+	#[test]
+	fn gc_removes_unreferenced_rootfs() {
+		let cache_dir = TempDir::new().unwrap();
+		cache_image(cache_dir.path(), IMAGE, "hello\n");
+		cache_image(cache_dir.path(), "example.org/other:1", "other\n");
+		let config = cached_config(cache_dir.path());
+		let rootfs = pull_and_unpack_rootfs(&IMAGE.parse().unwrap(), &config).unwrap();
+		let other = "example.org/other:1".parse().unwrap();
+		let other = pull_and_unpack_rootfs(&other, &config).unwrap();
+		let interrupted = cache_dir
+			.path()
+			.join(ROOTFS_DIR)
+			.join(".unpack-interrupted");
+		fs::create_dir_all(interrupted.join("bundle/rootfs")).unwrap();
+
+		assert_eq!(gc(cache_dir.path()).unwrap().removed_rootfs, 0);
+		assert!(!interrupted.exists());
+
+		remove_image(cache_dir.path(), &IMAGE.parse().unwrap()).unwrap();
+		// Still in use, e.g. by a running container.
+		assert_eq!(gc(cache_dir.path()).unwrap().removed_rootfs, 0);
+		assert!(rootfs.path.join("hello").exists());
+
+		drop(rootfs.lock);
+		let stats = gc(cache_dir.path()).unwrap();
+		assert_eq!(stats.removed_rootfs, 1);
+		assert!(!rootfs.path.exists());
+		assert!(other.path.join("hello").exists());
+		assert_eq!(gc(cache_dir.path()).unwrap(), GcStats::default());
+	}
+}
@@ -2,6 +2,10 @@
 //! (Did not want to add a dependency, which might not be maintained.)
 
 use std::io::{Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::AsyncWrite;
 
 pub trait ReadExt: Read {
 	fn tee<W: Write>(self, out: W) -> TeeReader<Self, W>
@@ -94,3 +98,37 @@ impl<L: Write, R: Write> Write for TeeWriter<L, R> {
 		Ok(())
 	}
 }
+
+/// An async writer which tees what it wrote to a blocking writer, e.g. a hasher.
+pub struct AsyncTeeWriter<W, T> {
+	writer: W,
+	tee: T,
+}
+
+impl<W: AsyncWrite + Unpin, T: Write + Unpin> AsyncTeeWriter<W, T> {
+	pub fn new(writer: W, tee: T) -> Self {
+		Self { writer, tee }
+	}
+}
+
+impl<W: AsyncWrite + Unpin, T: Write + Unpin> AsyncWrite for AsyncTeeWriter<W, T> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<std::io::Result<usize>> {
+		let poll = Pin::new(&mut self.writer).poll_write(cx, buf);
+		if let Poll::Ready(Ok(written)) = poll {
+			self.tee.write_all(&buf[..written])?;
+		}
+		poll
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.writer).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.writer).poll_shutdown(cx)
+	}
+}
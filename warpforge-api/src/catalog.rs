@@ -32,6 +32,31 @@ pub struct CatalogRelease {
 	pub metadata: IndexMap<String, String>,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum CatalogMirrorsCapsule {
+	#[serde(rename = "catalogmirrors.v1")]
+	V1(CatalogMirrors),
+}
+
+/// Hints on where the wares of a catalog module can be fetched from.
+///
+/// Mirrors are either listed for single wares, or as content-addressed warehouses
+/// which hold all wares (of a packtype) of a module.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CatalogMirrors {
+	#[serde(rename = "byWare", default, skip_serializing_if = "IndexMap::is_empty")]
+	pub by_ware: IndexMap<crate::content::WareID, Vec<crate::formula::WarehouseAddr>>,
+	#[serde(
+		rename = "byModule",
+		default,
+		skip_serializing_if = "IndexMap::is_empty"
+	)]
+	pub by_module: IndexMap<
+		ModuleName,
+		IndexMap<crate::content::Packtype, Vec<crate::formula::WarehouseAddr>>,
+	>,
+}
+
 #[derive(Clone, Debug, SerializeDisplay, DeserializeFromStr, catverters_derive::Stringoid)]
 pub struct CatalogRef {
 	pub module_name: ModuleName,
@@ -92,4 +117,26 @@ mod tests {
             }"#]];
 		assert_eq_json_roundtrip::<CatalogModuleCapsule>(&expect);
 	}
+
+	#[test]
+	fn test_mirrors() {
+		let expect = expect![[r#"
+            {
+              "catalogmirrors.v1": {
+                "byWare": {
+                  "tar:4z9DCTxoKkStqXQRwtf9nimpfQQ36dncJsTygqkfPLJJ7ctACnEuToBGuLxrSWNtbH": [
+                    "https://example.org/bash.tgz"
+                  ]
+                },
+                "byModule": {
+                  "warpsys.org/bash": {
+                    "tar": [
+                      "ca+https://warpsys.s3.amazonaws.com/warehouse"
+                    ]
+                  }
+                }
+              }
+            }"#]];
+		assert_eq_json_roundtrip::<CatalogMirrorsCapsule>(&expect);
+	}
 }
@@ -1,2 +1,3 @@
 pub const MAGIC_FILENAME_MODULE: &str = "module.wf";
 pub const MAGIC_FILENAME_PLOT: &str = "plot.wf";
+pub const MAGIC_FILENAME_RUNRECORD: &str = "runrecord.json";
@@ -55,8 +55,79 @@ pub enum FormulaInput {
 pub struct GatherDirective {
 	pub from: SandboxPort,
 	pub packtype: Option<crate::content::Packtype>,
-	// TODO:
-	// filters: Option<FilterMap>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub filters: Option<FilterMap>,
+}
+
+/// Filters applied to the metadata of the files of an output when it's packed.
+///
+/// Unset filters normalize the metadata, so outputs are reproducible:
+/// files are owned by uid and gid 1000, modified at 2010-01-01T00:00:00Z,
+/// sticky bits are kept and setuid/setgid bits are rejected.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FilterMap {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub uid: Option<IdFilter>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub gid: Option<IdFilter>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub mtime: Option<MtimeFilter>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub sticky: Option<StickyFilter>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub setid: Option<SetidFilter>,
+}
+
+/// Filter for the owner or group of files: "keep" or a numeric ID (e.g. "0").
+#[derive(Clone, Copy, Debug, PartialEq, Eq, SerializeDisplay, DeserializeFromStr)]
+pub enum IdFilter {
+	Keep,
+	Id(u32),
+}
+
+impl std::fmt::Display for IdFilter {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			IdFilter::Keep => write!(f, "keep"),
+			IdFilter::Id(id) => write!(f, "{id}"),
+		}
+	}
+}
+
+impl std::str::FromStr for IdFilter {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"keep" => Ok(IdFilter::Keep),
+			id => (id.parse().map(IdFilter::Id))
+				.map_err(|_| format!("invalid id filter '{id}' (expected 'keep' or a number)")),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MtimeFilter {
+	Keep,
+	/// Sets the modification time of all files to the Unix epoch.
+	Epoch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StickyFilter {
+	Keep,
+	Strip,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SetidFilter {
+	/// Fails packing outputs containing setuid or setgid files.
+	Reject,
+	Strip,
+	Keep,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -115,6 +186,16 @@ impl Borrow<String> for WarehouseAddr {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct FormulaContext {
 	pub warehouses: IndexMap<crate::content::WareID, WarehouseAddr>,
+
+	/// JSON Patch operations (RFC 6902) amending the OCI spec of the container.
+	///
+	/// They are applied after the patches of the executor, so they can override anything.
+	#[serde(
+		rename = "ociSpecPatch",
+		default,
+		skip_serializing_if = "Vec::is_empty"
+	)]
+	pub oci_spec_patch: Vec<serde_json::Value>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -151,14 +232,32 @@ mod tests {
           ]
         }
       },
-      "outputs": {}
+      "outputs": {
+        "out": {
+          "from": "/out",
+          "packtype": "tgz",
+          "filters": {
+            "uid": "0",
+            "gid": "keep",
+            "mtime": "epoch",
+            "setid": "strip"
+          }
+        }
+      }
     }
   },
   "context": {
     "context.v1": {
       "warehouses": {
         "tar:4z9DCTxoKkStqXQRwtf9nimpfQQ36dbndDsAPCQgECfbXt3edanUrsVKCjE9TkX2v9": "https://warpsys.s3.amazonaws.com/warehouse/4z9/DCT/4z9DCTxoKkStqXQRwtf9nimpfQQ36dbndDsAPCQgECfbXt3edanUrsVKCjE9TkX2v9"
-      }
+      },
+      "ociSpecPatch": [
+        {
+          "op": "replace",
+          "path": "/process/rlimits/0/soft",
+          "value": 4096
+        }
+      ]
     }
   }
 }"#]];
@@ -3,6 +3,7 @@ pub mod constants;
 pub mod content;
 pub mod formula;
 pub mod plot;
+pub mod run_record;
 
 #[cfg(test)]
 mod test_common;
@@ -0,0 +1,145 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::content::WareID;
+use crate::plot::{LocalLabel, StepName};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum RunRecordCapsule {
+	#[serde(rename = "runrecord.v1")]
+	V1(RunRecord),
+}
+
+/// Record of a formula execution.
+///
+/// When the outputs of a formula are reused from an earlier run, the record of
+/// that earlier run is returned: the GUID tells records of different runs apart.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RunRecord {
+	pub guid: String,
+	#[serde(rename = "formulaID")]
+	pub formula_id: String,
+	/// Start of the run, in seconds since the unix epoch.
+	#[serde(rename = "startTime")]
+	pub start_time: u64,
+	/// End of the run, in seconds since the unix epoch.
+	#[serde(rename = "endTime")]
+	pub end_time: u64,
+	/// Exit code of the container, or -1 if it didn't exit normally (e.g. killed by a signal).
+	#[serde(rename = "exitCode")]
+	pub exit_code: i32,
+	/// Whether the container used the network of the host.  Otherwise, it only had loopback.
+	#[serde(default)]
+	pub network: bool,
+	#[serde(
+		rename = "resourceUsage",
+		default,
+		skip_serializing_if = "Option::is_none"
+	)]
+	pub resource_usage: Option<ResourceUsage>,
+	pub results: IndexMap<LocalLabel, WareID>,
+}
+
+/// Resources used by the container of a formula run.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ResourceUsage {
+	#[serde(rename = "userTimeMicros")]
+	pub user_time_micros: u64,
+	#[serde(rename = "systemTimeMicros")]
+	pub system_time_micros: u64,
+	#[serde(rename = "maxRssKilobytes")]
+	pub max_rss_kilobytes: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum PlotRunRecordCapsule {
+	#[serde(rename = "plotrunrecord.v1")]
+	V1(PlotRunRecord),
+}
+
+/// Record of a plot execution, containing the records of all steps which ran.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PlotRunRecord {
+	pub guid: String,
+	/// Start of the run, in seconds since the unix epoch.
+	#[serde(rename = "startTime")]
+	pub start_time: u64,
+	/// End of the run, in seconds since the unix epoch.
+	#[serde(rename = "endTime")]
+	pub end_time: u64,
+	pub steps: IndexMap<StepName, StepRunRecord>,
+	pub results: IndexMap<LocalLabel, WareID>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[allow(clippy::large_enum_variant)]
+pub enum StepRunRecord {
+	#[serde(rename = "plot")]
+	Plot(PlotRunRecord),
+
+	#[serde(rename = "protoformula")]
+	Protoformula(RunRecord),
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::test_common::assert_eq_json_roundtrip;
+	use expect_test::expect;
+
+	#[test]
+	fn test_roundtrip() {
+		let expect = expect![[r#"
+            {
+              "runrecord.v1": {
+                "guid": "4c3cee4e-bd4d-4bd7-8b8c-3a7a2c0e7f3b",
+                "formulaID": "8d4d6e0c0e2b1d3f",
+                "startTime": 1700000000,
+                "endTime": 1700000042,
+                "exitCode": 0,
+                "network": false,
+                "resourceUsage": {
+                  "userTimeMicros": 1200000,
+                  "systemTimeMicros": 300000,
+                  "maxRssKilobytes": 20480
+                },
+                "results": {
+                  "out": "tar:4z9DCTxoKkStqXQRwtf9nimpfQQ36dncJsTygqkfPLJJ7ctACnEuToBGuLxrSWNtbH"
+                }
+              }
+            }"#]];
+		assert_eq_json_roundtrip::<RunRecordCapsule>(&expect);
+	}
+
+	#[test]
+	fn test_plot_roundtrip() {
+		let expect = expect![[r#"
+            {
+              "plotrunrecord.v1": {
+                "guid": "0f6b6a1e-86c6-4f0c-9f5e-5b7c1b8f4d2a",
+                "startTime": 1700000000,
+                "endTime": 1700000042,
+                "steps": {
+                  "build": {
+                    "protoformula": {
+                      "guid": "4c3cee4e-bd4d-4bd7-8b8c-3a7a2c0e7f3b",
+                      "formulaID": "8d4d6e0c0e2b1d3f",
+                      "startTime": 1700000000,
+                      "endTime": 1700000042,
+                      "exitCode": 0,
+                      "network": true,
+                      "results": {
+                        "out": "tar:4z9DCTxoKkStqXQRwtf9nimpfQQ36dncJsTygqkfPLJJ7ctACnEuToBGuLxrSWNtbH"
+                      }
+                    }
+                  }
+                },
+                "results": {
+                  "out": "tar:4z9DCTxoKkStqXQRwtf9nimpfQQ36dncJsTygqkfPLJJ7ctACnEuToBGuLxrSWNtbH"
+                }
+              }
+            }"#]];
+		assert_eq_json_roundtrip::<PlotRunRecordCapsule>(&expect);
+	}
+}
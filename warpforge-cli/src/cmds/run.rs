@@ -1,16 +1,27 @@
 use std::{
-	env::current_dir,
+	env::{self, current_dir},
 	ffi::OsStr,
 	fs::{self, File},
 	io::BufReader,
+	num::NonZeroUsize,
 	path::{Path, PathBuf},
+	sync::Arc,
 };
 
+use oci_unpack::{AuthConfig, Platform};
+use serde::Serialize;
 use warpforge_api::{
-	constants::{MAGIC_FILENAME_MODULE, MAGIC_FILENAME_PLOT},
-	plot::PlotCapsule,
+	constants::{MAGIC_FILENAME_MODULE, MAGIC_FILENAME_PLOT, MAGIC_FILENAME_RUNRECORD},
+	content::WareID,
+	plot::{LocalLabel, PlotCapsule},
+	run_record::{PlotRunRecordCapsule, RunRecordCapsule},
+};
+use warpforge_dab::catalog::FsHandle;
+use warpforge_executors::{
+	context::Context,
+	formula::{check_exit_code, run_formula},
+	plot::run_plot,
 };
-use warpforge_executors::{context::Context, formula::run_formula, plot::run_plot, Digest};
 use warpforge_terminal::{log_global, logln, Level};
 use warpforge_validate::validate_formula;
 
@@ -25,8 +36,34 @@ pub struct Cmd {
 	pub target: Option<PathBuf>,
 
 	/// Container runtime used to run OCI bundles.
+	///
+	/// The runtime is recognized by its file name: runc, crun and runsc are supported.
 	#[arg(long, default_value = "runc")]
 	pub runtime: PathBuf,
+
+	/// Maximum number of plot steps run at the same time.
+	///
+	/// Defaults to the available parallelism of the host.
+	#[arg(long, short)]
+	pub jobs: Option<NonZeroUsize>,
+
+	/// Keep running the plot steps not depending on a failed step.
+	#[arg(long, short)]
+	pub keep_going: bool,
+
+	/// Platform of the images used, as os/architecture[/variant] (e.g. linux/arm64).
+	///
+	/// Defaults to the platform of the host.
+	#[arg(long)]
+	pub platform: Option<Platform>,
+
+	/// Ignore the environment variables and working directory configured by images.
+	#[arg(long)]
+	pub ignore_image_config: bool,
+
+	/// Directory to export a copy of the outputs to, in addition to the local warehouse.
+	#[arg(long)]
+	pub export: Option<PathBuf>,
 }
 
 pub fn execute(_cli: &Root, cmd: &Cmd) -> Result<(), Error> {
@@ -65,23 +102,28 @@ fn execute_module(cmd: &Cmd, path: impl AsRef<Path>) -> Result<(), Error> {
 			cause: format!("invalid plot file: {e}").into(),
 		})?;
 
-	let parent = parent(path)?;
+	let parent = parent(&path)?;
+	let warphome = warphome()?;
 	let context = Context {
 		runtime: cmd.runtime.to_owned(),
 		mount_path: Some(parent),
+		parallelism: cmd.jobs,
+		keep_going: cmd.keep_going,
+		catalog: Some(Arc::new(catalog_handle(&warphome))),
+		memo_path: Some(warphome.join("memos")),
+		warehouse: Some(warphome.join("warehouse")),
+		image_cache: Some(warphome.join("images")),
+		output_path: cmd.export.clone(),
+		auth_config: Some(auth_config()?),
+		platform: cmd.platform.clone().unwrap_or_default(),
+		ignore_image_config: cmd.ignore_image_config,
 		..Default::default()
 	};
-	let outputs = run_plot(plot, &context)?;
-
-	for output in outputs {
-		let warpforge_executors::Output {
-			name,
-			digest: Digest::Sha384(digest),
-		} = output;
-		logln!("sha384:{digest} {name}");
-	}
+	let record = run_plot(plot, &context)?;
 
-	Ok(())
+	log_results(&record.results);
+	let record_path = path.as_ref().join(MAGIC_FILENAME_RUNRECORD);
+	write_run_record(record_path, &PlotRunRecordCapsule::V1(record))
 }
 
 fn execute_formula(cmd: &Cmd, path: impl AsRef<Path>) -> Result<(), Error> {
@@ -101,22 +143,49 @@ fn execute_formula(cmd: &Cmd, path: impl AsRef<Path>) -> Result<(), Error> {
 	};
 
 	let parent = parent(&path)?;
+	let warphome = warphome()?;
 	let context = Context {
 		runtime: cmd.runtime.to_owned(),
-		mount_path: Some(parent),
+		mount_path: Some(parent.clone()),
+		memo_path: Some(warphome.join("memos")),
+		warehouse: Some(warphome.join("warehouse")),
+		image_cache: Some(warphome.join("images")),
+		output_path: cmd.export.clone(),
+		auth_config: Some(auth_config()?),
+		platform: cmd.platform.clone().unwrap_or_default(),
+		ignore_image_config: cmd.ignore_image_config,
 		..Default::default()
 	};
-	let outputs = run_formula(validated_formula.formula, &context)?;
-
-	for output in outputs {
-		let warpforge_executors::Output {
-			name,
-			digest: Digest::Sha384(digest),
-		} = output;
-		logln!("sha384:{digest} {name}");
+	let record = run_formula(validated_formula.formula, &context)?;
+
+	// Failed runs are recorded too, before reporting the failure.
+	log_results(&record.results);
+	let exit_code = check_exit_code(&record);
+	// Named after the formula file, since a directory can hold several formulas.
+	let stem = (path.as_ref().file_stem()).map_or("formula".into(), OsStr::to_string_lossy);
+	let record_path = parent.join(format!("{stem}.{MAGIC_FILENAME_RUNRECORD}"));
+	write_run_record(record_path, &RunRecordCapsule::V1(record))?;
+	Ok(exit_code?)
+}
+
+fn log_results<'a>(results: impl IntoIterator<Item = (&'a LocalLabel, &'a WareID)>) {
+	for (LocalLabel(name), ware_id) in results {
+		logln!("{ware_id} {name}");
 	}
+}
 
-	Ok(())
+/// Writes the record of a run next to the module or formula which ran.
+///
+/// Modules get `runrecord.json` in their directory, formulas `<name>.runrecord.json`
+/// next to their file `<name>.json`.
+fn write_run_record(path: impl AsRef<Path>, record: &impl Serialize) -> Result<(), Error> {
+	let path = path.as_ref();
+	let json = serde_json::to_string_pretty(record).map_err(|err| Error::RunRecordWrite {
+		cause: Box::new(err),
+	})?;
+	fs::write(path, json + "\n").map_err(|err| Error::RunRecordWrite {
+		cause: format!("failed to write '{}': {err}", path.display()).into(),
+	})
 }
 
 fn display_error(err: &warpforge_validate::Error, source: &str, path: impl AsRef<Path>) {
@@ -198,6 +267,23 @@ where
 	}
 }
 
+pub(crate) fn warphome() -> Result<PathBuf, Error> {
+	let user_home =
+		env::var("HOME").map_err(|e| Error::BizarreEnvironment { cause: Box::new(e) })?;
+	//TODO: check for a root workspace above $CWD before $HOME/.warphome
+	Ok(Path::new(&user_home).join(".warphome"))
+}
+
+/// The catalog kept in the given warphome.
+pub(crate) fn catalog_handle(warphome: &Path) -> FsHandle {
+	FsHandle::new(warphome.join("catalogs/warpsys"))
+}
+
+/// Registry credentials of the docker and podman auth config files of the user.
+fn auth_config() -> Result<AuthConfig, Error> {
+	AuthConfig::from_default_files().map_err(|e| Error::BizarreEnvironment { cause: Box::new(e) })
+}
+
 fn parent(path: impl AsRef<Path>) -> Result<PathBuf, Error> {
 	let parent = if path.as_ref().is_absolute() {
 		path.as_ref().parent().map(ToOwned::to_owned)
@@ -42,6 +42,10 @@ pub enum Error {
 	#[error("error accessing catalog: {cause}")]
 	CatalogAccess { cause: ErrorCause },
 
+	/// Failed to write the record of a run next to the module or formula which ran.
+	#[error("error writing run record: {cause}")]
+	RunRecordWrite { cause: ErrorCause },
+
 	// Transparent wrapper for executor errors.
 	#[error(transparent)]
 	Executor(#[from] warpforge_executors::Error),
@@ -56,6 +60,7 @@ impl Error {
 			Error::CatalogEntryNotExists { .. } => 14,
 			Error::CatalogAccess { .. } => 15,
 			Error::Executor(..) => 16,
+			Error::RunRecordWrite { .. } => 17,
 		}
 	}
 }
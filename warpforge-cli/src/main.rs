@@ -1,13 +1,10 @@
 use clap::error::ErrorKind;
 use clap::Parser;
-use std::env;
-use std::path;
 
 use warpforge_terminal::logln;
 use warpforge_terminal::Logger;
 
 mod cmds;
-mod dab;
 mod errors;
 
 use errors::*;
@@ -51,15 +48,9 @@ fn main2() -> Result<(), Error> {
 		Some(cmds::Subcommands::Run(cmd)) => return cmds::run::execute(&cli, cmd),
 		Some(cmds::Subcommands::Catalog(cmd)) => match &cmd.subcommand {
 			cmds::catalog::Subcommands::ReadItem(cmd) => {
-				let user_home = env::var("HOME")
-					.map_err(|e| Error::BizarreEnvironment { cause: Box::new(e) })?;
-
 				// Create the catalog data access broker.  Store in a box just so we can have dynamic dispatch.  (This is architecture astronauting, but I wanna know that I know how to do this.)
-				//TODO: check for a root workspace above $CWD before $HOME/.warphome
-				let catalog_handle: Box<dyn dab::catalog::Handle> =
-					Box::new(dab::catalog::FsHandle::new(
-						path::Path::new(&user_home).join(".warphome/catalogs/warpsys"),
-					));
+				let catalog_handle: Box<dyn warpforge_dab::catalog::Handle> =
+					Box::new(cmds::run::catalog_handle(&cmds::run::warphome()?));
 
 				let catalog_release = catalog_handle
 					.load_release(&cmd.catalog_ref.module_name, &cmd.catalog_ref.release_name)
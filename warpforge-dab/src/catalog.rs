@@ -0,0 +1,177 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use warpforge_api::catalog::{CatalogMirrors, CatalogMirrorsCapsule};
+use warpforge_api::catalog::{CatalogModule, CatalogRef, CatalogRelease};
+use warpforge_api::catalog::{ModuleName, ReleaseName};
+use warpforge_api::content::WareID;
+use warpforge_api::formula::WarehouseAddr;
+
+use std::error::Error as UndertypedError;
+
+/// Error of a catalog lookup.  Usually an IO or parse error.
+pub type Error = Box<dyn UndertypedError + Send + Sync>;
+
+pub trait Handle: Debug + Send + Sync {
+	fn load_module(&self, module_name: &ModuleName) -> Result<CatalogModule, Error>;
+	fn load_release(
+		&self,
+		module_name: &ModuleName,
+		release_name: &ReleaseName,
+	) -> Result<CatalogRelease, Error>;
+
+	/// Loads the mirrors of a module.
+	///
+	/// Modules don't need to have mirrors: in that case, empty mirrors are returned.
+	fn load_mirrors(&self, module_name: &ModuleName) -> Result<CatalogMirrors, Error>;
+
+	/// Looks up the ware referenced by the catalog reference, together with the
+	/// warehouses the mirrors of the module suggest to fetch it from.
+	///
+	/// Returns `None`, if the release does not contain the referenced item.
+	fn lookup(
+		&self,
+		catalog_ref: &CatalogRef,
+	) -> Result<Option<(WareID, Vec<WarehouseAddr>)>, Error> {
+		let release = self.load_release(&catalog_ref.module_name, &catalog_ref.release_name)?;
+		let Some(ware_id) = release.items.get(&catalog_ref.item_name) else {
+			return Ok(None);
+		};
+
+		let mut mirrors = self.load_mirrors(&catalog_ref.module_name)?;
+		let mut warehouses = mirrors.by_ware.swap_remove(ware_id).unwrap_or_default();
+		if let Some(by_packtype) = mirrors.by_module.get_mut(&catalog_ref.module_name) {
+			warehouses.extend(
+				by_packtype
+					.swap_remove(&ware_id.packtype)
+					.unwrap_or_default(),
+			);
+		}
+
+		Ok(Some((ware_id.to_owned(), warehouses)))
+	}
+}
+
+#[derive(Debug)]
+pub struct FsHandle {
+	root_path: PathBuf,
+}
+
+impl FsHandle {
+	pub fn new<P: AsRef<Path>>(path: P) -> Self {
+		Self {
+			root_path: path.as_ref().to_path_buf(),
+		}
+	}
+}
+
+impl Handle for FsHandle {
+	fn load_module(&self, module_name: &ModuleName) -> Result<CatalogModule, Error> {
+		let catmod_index_file_path: PathBuf =
+			self.root_path.join(&module_name.0).join("_module.json");
+		let reader = BufReader::new(File::open(catmod_index_file_path)?);
+		let result = serde_json::from_reader(reader)?;
+		// TODO validate the name doesn't conflict with the path we took to get here.
+		Ok(result)
+	}
+
+	fn load_release(
+		&self,
+		module_name: &ModuleName,
+		release_name: &ReleaseName,
+	) -> Result<CatalogRelease, Error> {
+		let catrel_file_path: PathBuf = self
+			.root_path
+			.join(&module_name.0)
+			.join("_releases")
+			.join(release_name.0.clone() + ".json");
+		let reader = BufReader::new(File::open(catrel_file_path)?);
+		let result = serde_json::from_reader(reader)?;
+		// TODO validate the name doesn't conflict with the path we took to get here.
+		Ok(result)
+	}
+
+	fn load_mirrors(&self, module_name: &ModuleName) -> Result<CatalogMirrors, Error> {
+		let catmirrors_file_path: PathBuf =
+			self.root_path.join(&module_name.0).join("_mirrors.json");
+		let file = match File::open(catmirrors_file_path) {
+			Ok(file) => file,
+			Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Default::default()),
+			Err(err) => return Err(err.into()),
+		};
+		let CatalogMirrorsCapsule::V1(result) = serde_json::from_reader(BufReader::new(file))?;
+		Ok(result)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::*;
+
+	const HASH: &str = "4z9DCTxoKkStqXQRwtf9nimpfQQ36dncJsTygqkfPLJJ7ctACnEuToBGuLxrSWNtbH";
+
+	fn write_catalog(root: &Path, with_mirrors: bool) {
+		let module_dir = root.join("warpsys.org/bash");
+		fs::create_dir_all(module_dir.join("_releases")).unwrap();
+		fs::write(
+			module_dir.join("_releases").join("v5.1.16.json"),
+			format!(
+				r#"{{ "releaseName": "v5.1.16", "items": {{ "amd64": "tar:{HASH}" }}, "metadata": {{}} }}"#
+			),
+		)
+		.unwrap();
+
+		if with_mirrors {
+			fs::write(
+				module_dir.join("_mirrors.json"),
+				format!(
+					r#"{{ "catalogmirrors.v1": {{
+						"byWare": {{ "tar:{HASH}": ["https://example.org/bash.tgz"] }},
+						"byModule": {{ "warpsys.org/bash": {{ "tar": ["ca+file:///warehouse"] }} }}
+					}} }}"#
+				),
+			)
+			.unwrap();
+		}
+	}
+
+	#[test]
+	fn lookup_with_mirrors() {
+		let temp_dir = tempfile::tempdir().unwrap();
+		write_catalog(temp_dir.path(), true);
+		let handle = FsHandle::new(temp_dir.path());
+
+		let catalog_ref = "warpsys.org/bash:v5.1.16:amd64".parse().unwrap();
+		let (ware_id, warehouses) = handle.lookup(&catalog_ref).unwrap().unwrap();
+
+		assert_eq!(ware_id.to_string(), format!("tar:{HASH}"));
+		assert_eq!(
+			warehouses,
+			vec![
+				WarehouseAddr("https://example.org/bash.tgz".into()),
+				WarehouseAddr("ca+file:///warehouse".into()),
+			]
+		);
+	}
+
+	#[test]
+	fn lookup_without_mirrors() {
+		let temp_dir = tempfile::tempdir().unwrap();
+		write_catalog(temp_dir.path(), false);
+		let handle = FsHandle::new(temp_dir.path());
+
+		let catalog_ref = "warpsys.org/bash:v5.1.16:amd64".parse().unwrap();
+		let (_, warehouses) = handle.lookup(&catalog_ref).unwrap().unwrap();
+		assert!(warehouses.is_empty());
+
+		let catalog_ref = "warpsys.org/bash:v5.1.16:arm64".parse().unwrap();
+		assert!(handle.lookup(&catalog_ref).unwrap().is_none());
+
+		let catalog_ref = "warpsys.org/bash:v0.0.0:amd64".parse().unwrap();
+		assert!(handle.lookup(&catalog_ref).is_err());
+	}
+}
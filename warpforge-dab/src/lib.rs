@@ -0,0 +1,3 @@
+//! Data access brokers: lookups in the stores warpforge keeps its data in.
+
+pub mod catalog;
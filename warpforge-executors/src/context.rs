@@ -1,23 +1,143 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::sync::Arc;
+
+use indexmap::IndexMap;
+use oci_unpack::{AuthConfig, Platform, PullConfig, RegistryMirror, RegistrySettings};
+use warpforge_api::content::WareID;
+use warpforge_api::formula::WarehouseAddr;
+use warpforge_dab::catalog::Handle;
+
+use crate::runtime::{self, ContainerRuntime};
 
 #[derive(Clone, Default, Debug)]
 pub struct Context {
 	/// Path to OCI Runtime executable used to run containers in this context.
+	///
+	/// Unless a [Self::runtime_backend] is specified, the file name of the executable
+	/// selects the runtime backend: `runc`, `crun` and `runsc` are supported.
 	pub runtime: PathBuf,
 
+	/// Runtime backend used to run containers, instead of the one selected by [Self::runtime].
+	pub runtime_backend: Option<Arc<dyn ContainerRuntime>>,
+
 	/// Absolute path that determines the host path of mounts.
 	/// This is used as the prefix, when a formula specifies a relative mount path.
 	///
 	/// If no [Self::mount_path] is configured, the formula must not use relative mount paths.
 	pub mount_path: Option<PathBuf>,
 
-	/// Path where outputs of a formula will be emitted.
+	/// Path where outputs of a formula are exported to, under their names.
 	///
-	/// If no [Self::output_path] is provided, the outputs will be created in the working directory.
+	/// If no [Self::output_path] is provided, the outputs are exported into the working
+	/// directory, unless a [Self::warehouse] is specified: then they are only stored there.
 	pub output_path: Option<PathBuf>,
 
+	/// Path to the local warehouse outputs of formulas are stored in.
+	///
+	/// Packed outputs are stored by hash, using the layout of `ca+file://` warehouses,
+	/// so they can be used as `ware:` inputs of later formulas via this warehouse.
+	pub warehouse: Option<PathBuf>,
+
 	/// Path to the image cache.
 	///
 	/// If no [Self::image_cache] is specified, images are always pulled freshly from the registry.
 	pub image_cache: Option<PathBuf>,
+
+	/// Registry credentials used to pull images, e.g. from the auth config files of docker
+	/// and podman (see [AuthConfig::from_default_files]).
+	///
+	/// If no [Self::auth_config] is specified, images are pulled anonymously.
+	pub auth_config: Option<AuthConfig>,
+
+	/// Platform of the images used, when an image reference points to a multi-platform image.
+	///
+	/// `oci:` inputs of plots are resolved to the manifest digest for this platform, so plots
+	/// run for different platforms record different pins.  Defaults to the platform of the host.
+	pub platform: Platform,
+
+	/// Mirrors images are pulled from, before falling back to their own registry.
+	///
+	/// The digests of `oci:` inputs resolved via a mirror are the ones served by the mirror.
+	pub registry_mirrors: Vec<RegistryMirror>,
+
+	/// Connection settings of registries and mirrors, e.g. plain HTTP for a local registry.
+	pub registries: HashMap<String, RegistrySettings>,
+
+	/// Ignore the environment and working directory configured by images.
+	///
+	/// By default, the environment variables of the image of the `/` input are set
+	/// (formula `$VAR` inputs take precedence), and its working directory is used.
+	/// With [Self::ignore_image_config], containers only depend on the formula and the
+	/// root filesystem of the image: they start in `/` with a fixed `PATH`.
+	pub ignore_image_config: bool,
+
+	/// Path to the local ware store.
+	///
+	/// Wares used as inputs are fetched from their warehouse, verified and unpacked into this
+	/// directory, so later runs using the same ware don't have to fetch it again.
+	///
+	/// If no [Self::ware_cache] is specified, wares are fetched freshly for every formula run.
+	pub ware_cache: Option<PathBuf>,
+
+	/// Further warehouses to fetch wares from, in order.
+	///
+	/// They are tried when the warehouse given by the formula context fails, or when it
+	/// gives none.  Plots fill them with the mirrors of their catalog inputs.
+	pub ware_mirrors: IndexMap<WareID, Vec<WarehouseAddr>>,
+
+	/// Path to the memo store.
+	///
+	/// Outputs of hermetic formulas (formulas not using mounts) are stored here by formula ID.
+	/// When the same formula is run again, the stored outputs are used instead of running it.
+	///
+	/// If no [Self::memo_path] is specified, formulas are always run.
+	pub memo_path: Option<PathBuf>,
+
+	/// Maximum number of plot steps run at the same time.
+	///
+	/// If no [Self::parallelism] is specified, the available parallelism of the host is used.
+	pub parallelism: Option<NonZeroUsize>,
+
+	/// Keep running plot steps after a step failed.
+	///
+	/// By default, no new steps are started once a step failed (steps already running are
+	/// still waited for). With [Self::keep_going], all steps not depending on a failed step
+	/// are still run.
+	pub keep_going: bool,
+
+	/// Catalog used to resolve `catalog:` inputs of plots.
+	///
+	/// The mirrors of the catalog modules are used to find warehouses for the resolved wares.
+	/// If no [Self::catalog] is specified, plots must not use catalog inputs.
+	pub catalog: Option<Arc<dyn Handle>>,
+
+	/// JSON Patch amending the OCI spec of all containers run in this context.
+	///
+	/// It is applied after the patches of the executor, and before the patch of the formula
+	/// context (see [warpforge_api::formula::FormulaContext::oci_spec_patch]).
+	pub spec_patch: json_patch::Patch,
+}
+
+impl Context {
+	/// Runtime backend used to run containers in this context.
+	pub fn container_runtime(&self) -> Arc<dyn ContainerRuntime> {
+		match &self.runtime_backend {
+			Some(backend) => backend.clone(),
+			None => runtime::from_executable(&self.runtime),
+		}
+	}
+
+	/// Configuration for pulling images in this context.
+	pub(crate) fn pull_config(&self) -> PullConfig {
+		PullConfig {
+			cache: self.image_cache.clone(),
+			auth_config: self.auth_config.clone(),
+			platform: self.platform.clone(),
+			mirrors: self.registry_mirrors.clone(),
+			registries: self.registries.clone(),
+			..PullConfig::default()
+		}
+	}
 }
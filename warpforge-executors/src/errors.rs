@@ -1,6 +1,6 @@
 pub type Result<T> = std::result::Result<T, Error>;
 
-type ErrorCause = Box<dyn ::std::error::Error>;
+type ErrorCause = Box<dyn ::std::error::Error + Send + Sync>;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
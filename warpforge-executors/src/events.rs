@@ -1,5 +1,5 @@
 /// Event is the type used to shuttle infomation produced by subprocesses.
-/// It contains either bytes from stdout, from stderr, or an exit code.
+/// It contains either bytes from stdout, from stderr, the resources used, or an exit code.
 ///
 /// In most usages, we buffer these to a full line before sending an event.
 /// In those cases, the linebreak byte will still be attached.
@@ -22,5 +22,7 @@ pub enum EventBody {
 		channel: i32,
 		val: String, // FIXME String is most certainly not the right type here.  Find the right tokio reader system to return either Bytes, Vec<u8>, OsStr, or something sensible like that.
 	},
+	/// Resources used by the container process.  Sent just before the exit code, if known.
+	ResourceUsage(warpforge_api::run_record::ResourceUsage),
 	ExitCode(Option<i32>),
 }
@@ -1,11 +1,11 @@
+use std::fs;
 use std::io::{BufRead, BufReader, Lines};
 use std::path::PathBuf;
-use std::process::Stdio;
+use std::process::{Child, Stdio};
 use std::thread;
-use std::{fs, process::Command};
 
 use crossbeam_channel::Sender;
-use str_cat::os_str_cat;
+use warpforge_api::run_record::ResourceUsage;
 
 use crate::{Error, Result};
 
@@ -26,64 +26,82 @@ pub struct Executor {
 }
 
 impl Executor {
-	pub fn run(&self, task: &crate::ContainerParams, outbox: Sender<crate::Event>) -> Result<()> {
+	/// Runs the container, returning its exit code (`None` if it didn't exit normally).
+	pub fn run(
+		&self,
+		task: &crate::ContainerParams,
+		outbox: Sender<crate::Event>,
+	) -> Result<Option<i32>> {
 		self.prep_bundledir(task)?;
-		self.container_exec(task, outbox)?;
-		Ok(())
+		self.container_exec(task, outbox)
 	}
 
 	fn prep_bundledir(&self, task: &crate::ContainerParams) -> Result<()> {
 		// Build the config data.
 		let mut spec = crate::oci::oci_spec_base();
 
-		use syscalls::{syscall, Sysno};
-		let uid = match unsafe { syscall!(Sysno::getuid) } {
-			Ok(uid) => uid,
-			Err(err) => {
-				eprintln!("syscall getuid() failed: {}", err);
-				0
-			}
-		};
-		let gid = match unsafe { syscall!(Sysno::getgid) } {
-			Ok(id) => id,
-			Err(err) => {
-				eprintln!("syscall getgid() failed: {}", err);
-				0
-			}
-		};
-
 		// todo: apply mutations here.
 		let p: json_patch::Patch = serde_json::from_value(serde_json::json!([
 			{ "op": "add", "path": "/process/args", "value": task.command },
 			{ "op": "replace", "path": "/root/path", "value": task.root_path }, // FIXME: time to get the rest of the supply chain implemented :D
-			{ "op": "add", "path": "/linux/uidMappings", "value":
-			   [{"containerID": 0, "hostID": uid, "size": 1}]},
-			{ "op": "add", "path": "/linux/gidMappings", "value":
-			   [{"containerID": 0, "hostID": gid, "size": 1}]},
-			{ "op": "add", "path": "/linux/namespaces/-", "value": {"type": "user"}},
 		]))
 		.unwrap();
 		json_patch::patch(&mut spec, &p).unwrap();
 
-		// add mount specs
-		use crate::oci::ToOCIMount;
-		for (_dest, ms) in task.mounts.iter() {
+		// Without network, the container gets its own network namespace: only loopback is up there.
+		if !task.network {
 			let p: json_patch::Patch = serde_json::from_value(serde_json::json!([
-				{ "op": "add", "path": "/mounts/-", "value": ms.to_oci_mount() },
+				{ "op": "add", "path": "/linux/namespaces/-", "value": {"type": "network"}},
 			]))
 			.unwrap();
 			json_patch::patch(&mut spec, &p).unwrap();
 		}
+		task.runtime.adjust_spec(&mut spec);
 
-		// add environment variables
-		for (var, val) in task.environment.iter() {
+		// add mount specs
+		use crate::oci::ToOCIMount;
+		for (dest, ms) in task.mounts.iter() {
+			// A mount at `/` replaces the root, so it has to come before all other mounts.
+			let path = if dest == "/" {
+				"/mounts/0"
+			} else {
+				"/mounts/-"
+			};
 			let p: json_patch::Patch = serde_json::from_value(serde_json::json!([
-				{ "op": "add", "path": "/process/env/-", "value": format!("{var}={val}")}
+				{ "op": "add", "path": path, "value": ms.to_oci_mount() },
 			]))
 			.unwrap();
 			json_patch::patch(&mut spec, &p).unwrap();
 		}
 
+		// add environment variables, replacing the ones set already (e.g. PATH)
+		let env = spec["process"]["env"].as_array_mut().unwrap();
+		for (var, val) in task.environment.iter() {
+			let prefix = format!("{var}=");
+			let value = serde_json::Value::from(format!("{var}={val}"));
+			match (env.iter_mut()).find(|e| e.as_str().is_some_and(|e| e.starts_with(&prefix))) {
+				Some(existing) => *existing = value,
+				None => env.push(value),
+			}
+		}
+		if let Some(working_dir) = &task.working_dir {
+			spec["process"]["cwd"] = working_dir.as_str().into();
+		}
+
+		// Apply the user patches last, so they can amend anything.
+		for (origin, patch) in &task.spec_patches {
+			json_patch::patch(&mut spec, patch).map_err(|err| {
+				let op = serde_json::to_string(&patch.0[err.operation]).unwrap_or_default();
+				Error::SystemSetupError {
+					msg: format!(
+						"failed to apply OCI spec patch of the {origin}: operation {} ({op})",
+						err.operation
+					),
+					cause: Box::new(err),
+				}
+			})?;
+		}
+
 		// Write it out.
 		let cfg_dir = self.ersatz_dir.join(&task.ident);
 		fs::create_dir_all(&cfg_dir).map_err(|e| {
@@ -126,13 +144,14 @@ impl Executor {
 		&self,
 		task: &crate::ContainerParams,
 		outbox: Sender<crate::Event>,
-	) -> Result<()> {
-		let mut cmd = Command::new(&task.runtime);
-		cmd.arg(os_str_cat!("--log=", self.log_file));
-		cmd.arg("--debug");
-		cmd.arg("run");
-		cmd.arg(os_str_cat!("--bundle=", self.ersatz_dir.join(&task.ident)));
-		cmd.arg(&task.ident); // container name.
+	) -> Result<Option<i32>> {
+		let bundle_dir = self.ersatz_dir.join(&task.ident);
+		let mut cmd = task.runtime.command(&crate::runtime::Bundle {
+			ident: &task.ident,
+			dir: &bundle_dir,
+			log_file: &self.log_file,
+			network: task.network,
+		})?;
 
 		cmd.stdin(Stdio::null());
 		cmd.stdout(Stdio::piped());
@@ -189,19 +208,56 @@ impl Executor {
 			cause: Box::new(e),
 		})?;
 
-		let status = child.wait().map_err(|err| Error::SystemRuntimeError {
-			msg: "failed to get child exit code".into(),
-			cause: Box::new(err),
-		})?;
+		let (exit_code, resource_usage) = Self::wait(child)?;
 
 		outbox
 			.send(crate::Event {
 				topic: task.ident.to_owned(),
-				body: crate::events::EventBody::ExitCode(status.code()),
+				body: crate::events::EventBody::ResourceUsage(resource_usage),
+			})
+			.expect("channel must not be closed");
+		outbox
+			.send(crate::Event {
+				topic: task.ident.to_owned(),
+				body: crate::events::EventBody::ExitCode(exit_code),
 			})
 			.expect("channel must not be closed");
 
-		Ok(())
+		Ok(exit_code)
+	}
+
+	/// Waits for the child to exit, collecting the resources used by it.
+	///
+	/// The resource usage of the runtime process includes the container processes:
+	/// the runtime waits for them, before it exits itself.
+	fn wait(child: Child) -> Result<(Option<i32>, ResourceUsage)> {
+		let mut status = 0;
+		// SAFETY: all-zero is a valid value for this plain C struct.
+		let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+		// The child handle is consumed here: nobody else may wait for (and reap) the process.
+		let pid = child.id() as libc::pid_t;
+		loop {
+			// SAFETY: pointers are valid for the duration of the call.
+			if unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) } >= 0 {
+				break;
+			}
+			let err = std::io::Error::last_os_error();
+			if err.kind() != std::io::ErrorKind::Interrupted {
+				return Err(Error::SystemRuntimeError {
+					msg: "failed to get child exit code".into(),
+					cause: Box::new(err),
+				});
+			}
+		}
+
+		let exit_code = libc::WIFEXITED(status).then(|| libc::WEXITSTATUS(status));
+		let micros = |time: libc::timeval| time.tv_sec as u64 * 1_000_000 + time.tv_usec as u64;
+		let resource_usage = ResourceUsage {
+			user_time_micros: micros(rusage.ru_utime),
+			system_time_micros: micros(rusage.ru_stime),
+			max_rss_kilobytes: rusage.ru_maxrss as u64,
+		};
+		Ok((exit_code, resource_usage))
 	}
 
 	fn send_container_output<T: BufRead>(
@@ -254,14 +310,17 @@ mod tests {
 		let (gather_chan, gather_chan_recv) = crossbeam_channel::bounded::<crate::Event>(32);
 		let params = crate::ContainerParams {
 			ident: "containernamegoeshere".into(),
-			runtime: "runc".into(),
+			runtime: crate::runtime::from_executable("runc"),
 			command: vec![
 				"/bin/sh".to_string(),
 				"-c".to_string(),
 				"echo $MSG".to_string(),
 			],
 			mounts: { IndexMap::new() },
+			working_dir: None,
 			root_path: bundle_path.join("rootfs"),
+			network: false,
+			spec_patches: Vec::new(),
 
 			environment: IndexMap::from([
 				("MSG".into(), "hello, from environment variables!".into()),
@@ -275,6 +334,7 @@ mod tests {
 					EventBody::Output { val, channel: 1 } => println!("[container] {val}"),
 					EventBody::Output { val, channel: 2 } => eprintln!("[container] {val}"),
 					EventBody::Output { .. } => panic!("invalid channel number"),
+					EventBody::ResourceUsage(_) => {}
 					EventBody::ExitCode(code) => {
 						assert_eq!(code, &Some(0));
 						break; // stop processing events
@@ -286,4 +346,157 @@ mod tests {
 		cfg.run(&params, gather_chan).expect("it didn't fail");
 		gather_handle.join().expect("gathering events failed");
 	}
+
+	#[test]
+	fn network_namespace_unless_host_network() {
+		let temp_dir = TempDir::new().unwrap();
+		let cfg = crate::execute::Executor {
+			ersatz_dir: temp_dir.path().join("run"),
+			log_file: temp_dir.path().join("log"),
+		};
+
+		for network in [false, true] {
+			let params = crate::ContainerParams {
+				ident: format!("network-{network}"),
+				runtime: crate::runtime::from_executable("runc"),
+				command: vec!["/bin/true".into()],
+				mounts: IndexMap::new(),
+				environment: IndexMap::new(),
+				working_dir: None,
+				root_path: temp_dir.path().join("rootfs"),
+				network,
+				spec_patches: Vec::new(),
+			};
+			cfg.prep_bundledir(&params).unwrap();
+
+			let config = std::fs::read(cfg.ersatz_dir.join(&params.ident).join("config.json"));
+			let spec: serde_json::Value = serde_json::from_slice(&config.unwrap()).unwrap();
+			let namespaces = spec["linux"]["namespaces"].as_array().unwrap();
+			let has_network_namespace = (namespaces.iter()).any(|ns| ns["type"] == "network");
+			assert_eq!(has_network_namespace, !network);
+		}
+	}
+
+	#[test]
+	fn root_mount_comes_first() {
+		let temp_dir = TempDir::new().unwrap();
+		let cfg = crate::execute::Executor {
+			ersatz_dir: temp_dir.path().join("run"),
+			log_file: temp_dir.path().join("log"),
+		};
+		let bind = |dest: &str| {
+			crate::MountSpec::new_bind(&Default::default(), "/tmp", dest, true).unwrap()
+		};
+		let params = crate::ContainerParams {
+			ident: "root-mount".into(),
+			runtime: crate::runtime::from_executable("runc"),
+			command: vec!["/bin/true".into()],
+			mounts: IndexMap::from([("/data".into(), bind("/data")), ("/".into(), bind("/"))]),
+			environment: IndexMap::new(),
+			working_dir: None,
+			root_path: temp_dir.path().join("rootfs"),
+			network: false,
+			spec_patches: Vec::new(),
+		};
+		cfg.prep_bundledir(&params).unwrap();
+
+		let config = std::fs::read(cfg.ersatz_dir.join(&params.ident).join("config.json"));
+		let spec: serde_json::Value = serde_json::from_slice(&config.unwrap()).unwrap();
+		let mounts = spec["mounts"].as_array().unwrap();
+		assert_eq!(mounts[0]["destination"], "/");
+		assert_eq!(mounts.last().unwrap()["destination"], "/data");
+	}
+
+	#[test]
+	fn environment_replaces_executor_variables() {
+		let temp_dir = TempDir::new().unwrap();
+		let cfg = crate::execute::Executor {
+			ersatz_dir: temp_dir.path().join("run"),
+			log_file: temp_dir.path().join("log"),
+		};
+		let params = crate::ContainerParams {
+			ident: "environment".into(),
+			runtime: crate::runtime::from_executable("runc"),
+			command: vec!["/bin/true".into()],
+			mounts: IndexMap::new(),
+			environment: IndexMap::from([
+				("PATH".into(), "/opt/bin".into()),
+				("VAR".into(), "value".into()),
+			]),
+			working_dir: Some("/work".into()),
+			root_path: temp_dir.path().join("rootfs"),
+			network: false,
+			spec_patches: Vec::new(),
+		};
+		cfg.prep_bundledir(&params).unwrap();
+
+		let config = std::fs::read(cfg.ersatz_dir.join(&params.ident).join("config.json"));
+		let spec: serde_json::Value = serde_json::from_slice(&config.unwrap()).unwrap();
+		let env = spec["process"]["env"].as_array().unwrap();
+		assert_eq!(*env, ["PATH=/opt/bin", "VAR=value"]);
+		assert_eq!(spec["process"]["cwd"], "/work");
+	}
+
+	#[test]
+	fn spec_patches_applied_last() {
+		let temp_dir = TempDir::new().unwrap();
+		let cfg = crate::execute::Executor {
+			ersatz_dir: temp_dir.path().join("run"),
+			log_file: temp_dir.path().join("log"),
+		};
+		let patch = |value| serde_json::from_value::<json_patch::Patch>(value).unwrap();
+		let mut params = crate::ContainerParams {
+			ident: "patched".into(),
+			runtime: crate::runtime::from_executable("runc"),
+			command: vec!["/bin/true".into()],
+			mounts: IndexMap::new(),
+			environment: IndexMap::from([("VAR".into(), "value".into())]),
+			working_dir: None,
+			root_path: temp_dir.path().join("rootfs"),
+			network: false,
+			spec_patches: vec![
+				(
+					"context",
+					patch(serde_json::json!([
+						{ "op": "replace", "path": "/process/rlimits/0/soft", "value": 4096 },
+						{ "op": "replace", "path": "/process/rlimits/0/hard", "value": 4096 },
+					])),
+				),
+				(
+					"formula context",
+					patch(serde_json::json!([
+						{ "op": "add", "path": "/process/capabilities/bounding/-", "value": "CAP_SYS_ADMIN" },
+						{ "op": "remove", "path": "/process/env/1" },
+					])),
+				),
+			],
+		};
+		cfg.prep_bundledir(&params).unwrap();
+
+		let config = std::fs::read(cfg.ersatz_dir.join(&params.ident).join("config.json"));
+		let spec: serde_json::Value = serde_json::from_slice(&config.unwrap()).unwrap();
+		assert_eq!(spec["process"]["rlimits"][0]["soft"], 4096);
+		assert_eq!(spec["process"]["rlimits"][0]["hard"], 4096);
+		let bounding = spec["process"]["capabilities"]["bounding"]
+			.as_array()
+			.unwrap();
+		assert_eq!(bounding.last().unwrap(), "CAP_SYS_ADMIN");
+		// The environment variable added by the executor was removed again.
+		assert_eq!(spec["process"]["env"].as_array().unwrap().len(), 1);
+
+		params.ident = "failing".into();
+		params.spec_patches = vec![(
+			"formula context",
+			patch(serde_json::json!([
+				{ "op": "test", "path": "/hostname", "value": "forge" },
+				{ "op": "remove", "path": "/does/not/exist" },
+			])),
+		)];
+		let err = cfg.prep_bundledir(&params).unwrap_err().to_string();
+		assert!(
+			err.contains("patch of the formula context: operation 1"),
+			"{err}"
+		);
+		assert!(err.contains("/does/not/exist"), "{err}");
+	}
 }
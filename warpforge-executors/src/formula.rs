@@ -1,22 +1,34 @@
 use crossbeam_channel::Sender;
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
 use oci_client::Reference;
-use oci_unpack::{pull_and_unpack, PullConfig};
+use oci_spec::image::ImageConfiguration;
+use oci_unpack::{
+	pull_and_unpack, pull_and_unpack_rootfs, LayerProgress, LayerState, ProgressCallback,
+	RootfsLock,
+};
 use rand::distributions::{Alphanumeric, DistString};
+use std::collections::HashMap;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::{fs, thread};
+use warpforge_api::content::WareID;
 use warpforge_api::formula::{
-	self, Action, ActionScript, FormulaAndContext, FormulaInput, GatherDirective, Mount,
-	SandboxPort,
+	self, Action, ActionScript, FormulaAndContext, FormulaContextCapsule, FormulaInput,
+	GatherDirective, Mount, SandboxPort, WarehouseAddr,
 };
 use warpforge_api::plot::LocalLabel;
-use warpforge_terminal::{logln, Bar};
+use warpforge_api::run_record::{ResourceUsage, RunRecord};
+use warpforge_terminal::{debug, logln, Bar};
 
 use crate::context::Context;
 use crate::events::EventBody;
 use crate::execute::Executor;
-use crate::pack::{pack_outputs, IntermediateOutput, OutputPacktype};
+use crate::fshash::Filters;
+use crate::memo::{self, MemoStore};
+use crate::pack::{pack_outputs, IntermediateOutput, OutputPacktype, OutputTargets};
+use crate::record;
+use crate::ware::WareStore;
 use crate::{to_string_or_panic, ContainerParams, Error, Event, MountSpec, Output, Result};
 
 pub struct Formula<'a> {
@@ -24,7 +36,130 @@ pub struct Formula<'a> {
 	pub(crate) context: &'a Context,
 }
 
-pub fn run_formula(formula: FormulaAndContext, context: &Context) -> Result<Vec<Output>> {
+/// Runs the formula and returns the record of the run.
+///
+/// If the outputs of the formula are memoized, the record of the run which
+/// produced them is returned instead.  The record is returned whatever the exit code
+/// of the container: runs which failed are only told apart by it (see [check_exit_code]).
+pub fn run_formula(formula: FormulaAndContext, context: &Context) -> Result<RunRecord> {
+	let formula_id =
+		(memo::is_hermetic(&formula.formula)).then(|| memo::formula_id(&formula.formula));
+	run_formula_memoized(formula, formula_id.as_deref(), context)
+}
+
+/// Runs the formula, unless outputs for the formula ID are memoized already.
+///
+/// The formula ID is passed separately, because a formula, which is not hermetic itself,
+/// may still be identified by another formula (see [memo::substitute_mounts]).
+/// Without formula ID, the formula is always run and its outputs are not memoized.
+pub(crate) fn run_formula_memoized(
+	formula: FormulaAndContext,
+	formula_id: Option<&str>,
+	context: &Context,
+) -> Result<RunRecord> {
+	let (outputs, network) = {
+		let formula::FormulaCapsule::V1(formula) = &formula.formula;
+		let mut outputs = Vec::new();
+		for (LocalLabel(name), gather) in &formula.outputs {
+			let packtype = OutputPacktype::parse(&gather.packtype)?;
+			outputs.push((
+				name.to_owned(),
+				packtype,
+				Filters::new(gather.filters.as_ref()),
+			));
+		}
+		(outputs, uses_network(&formula.action))
+	};
+	// Runtimes which don't need an image (like `runtime::Fake`) don't isolate the container,
+	// so their outputs aren't memoized.
+	let memo = match (&context.memo_path, formula_id) {
+		(Some(memo_path), Some(formula_id)) if context.container_runtime().needs_image() => {
+			let FormulaContextCapsule::V1(formula_context) = &formula.context;
+			let memo_key = memo::memo_key(
+				formula_id,
+				&context.spec_patch,
+				&formula_context.oci_spec_patch,
+				context.ignore_image_config,
+			);
+			Some((MemoStore::new(memo_path), memo_key))
+		}
+		_ => None,
+	};
+
+	let targets = OutputTargets::new(context);
+	if let Some((memo_store, memo_key)) = &memo {
+		if let Some(record) = memo_store.restore(memo_key, &outputs, &targets)? {
+			logln!("formula {memo_key}: using memoized outputs");
+			return Ok(record);
+		}
+	}
+
+	let formula_id = formula_id.map_or_else(|| memo::formula_id(&formula.formula), str::to_owned);
+	let start_time = record::unix_time();
+	let (results, exit_code, resource_usage) = run_formula_unmemoized(formula, context)?;
+	let record = RunRecord {
+		guid: record::new_guid(),
+		formula_id,
+		start_time,
+		end_time: record::unix_time(),
+		exit_code,
+		network,
+		resource_usage,
+		results: record::ware_ids(&results),
+	};
+
+	if let Some((memo_store, memo_key)) = &memo {
+		if record.exit_code == 0 {
+			memo_store.store(memo_key, &outputs, &record, &targets)?;
+		}
+	}
+	Ok(record)
+}
+
+/// Fails if the container of the run didn't exit with code zero.
+pub fn check_exit_code(record: &RunRecord) -> Result<()> {
+	match record.exit_code {
+		0 => Ok(()),
+		code => Err(Error::SystemRuntimeError {
+			msg: "container terminated non-zero exit code".into(),
+			cause: format!("{code}").into(),
+		}),
+	}
+}
+
+/// Parses the OCI spec patch of a formula context.
+fn parse_spec_patch(operations: &[serde_json::Value]) -> Result<json_patch::Patch> {
+	let mut patch = Vec::new();
+	for (index, operation) in operations.iter().enumerate() {
+		let operation = serde_json::from_value(operation.to_owned()).map_err(|err| {
+			Error::SystemSetupError {
+				msg: format!(
+					"formula context: invalid OCI spec patch operation {index} ({operation})"
+				),
+				cause: Box::new(err),
+			}
+		})?;
+		patch.push(operation);
+	}
+	Ok(json_patch::Patch(patch))
+}
+
+/// Whether the action opted into host networking.  Otherwise, the network is isolated.
+pub(crate) fn uses_network(action: &Action) -> bool {
+	match action {
+		Action::Echo => false,
+		Action::Execute(action) => action.network.unwrap_or(false),
+		Action::Script(action) => action.network.unwrap_or(false),
+	}
+}
+
+/// Runs the formula in a container, returning its outputs, exit code and resource usage.
+///
+/// Containers which didn't exit normally (e.g. killed by a signal) get exit code -1.
+fn run_formula_unmemoized(
+	formula: FormulaAndContext,
+	context: &Context,
+) -> Result<(Vec<Output>, i32, Option<ResourceUsage>)> {
 	let temporary_dir = tempfile::tempdir().map_err(|err| Error::SystemSetupError {
 		msg: "failed to setup temporary dir".into(),
 		cause: Box::new(err),
@@ -41,26 +176,22 @@ pub fn run_formula(formula: FormulaAndContext, context: &Context) -> Result<Vec<
 	let (event_sender, event_receiver) = crossbeam_channel::bounded::<Event>(32);
 
 	let event_handler = thread::spawn(move || {
+		let mut resource_usage = None;
 		while let Ok(event) = event_receiver.recv() {
-			match &event.body {
+			match event.body {
 				EventBody::Output { val, .. } => logln!("[container] {val}\n"),
-				EventBody::ExitCode(code) => return *code,
+				EventBody::ResourceUsage(usage) => resource_usage = Some(usage),
+				EventBody::ExitCode(code) => return (code, resource_usage),
 			}
 		}
 
-		None
+		(None, resource_usage)
 	});
 
 	let outputs = executor.run(formula, event_sender)?;
 
-	let exit_code = event_handler.join().unwrap();
-	match exit_code {
-		Some(0) => Ok(outputs),
-		_ => Err(Error::SystemRuntimeError {
-			msg: "container terminated non-zero exit code".into(),
-			cause: exit_code.map_or_else(|| "None".into(), |code| format!("{code}").into()),
-		}),
-	}
+	let (exit_code, resource_usage) = event_handler.join().unwrap();
+	Ok((outputs, exit_code.unwrap_or(-1), resource_usage))
 }
 
 impl<'a> Formula<'a> {
@@ -154,6 +285,7 @@ impl<'a> Formula<'a> {
 		outbox: Sender<Event>,
 	) -> Result<Vec<Output>> {
 		let formula::FormulaCapsule::V1(formula) = formula_and_context.formula;
+		let FormulaContextCapsule::V1(formula_context) = formula_and_context.context;
 
 		let progress = Bar::new(5, "setup container");
 
@@ -174,11 +306,15 @@ impl<'a> Formula<'a> {
 			return Err(Error::SystemSetupCauseless { msg });
 		};
 
-		let (mut mounts, environment) = self.setup_inputs(formula.inputs)?;
+		let formula_spec_patch = parse_spec_patch(&formula_context.oci_spec_patch)?;
+
+		let (mut mounts, mut environment) =
+			self.setup_inputs(formula.inputs, &formula_context.warehouses)?;
 
 		let outputs = self.setup_outputs(formula.outputs, &mut mounts)?;
 
 		// Handle Actions
+		let network = uses_network(&formula.action);
 		let command: Vec<String> = match &formula.action {
 			Action::Echo => vec![
 				"echo".to_string(),
@@ -194,44 +330,105 @@ impl<'a> Formula<'a> {
 		let random_suffix = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
 		let ident = format!("warpforge-{random_suffix}");
 
+		let runtime = self.context.container_runtime();
 		let bundle_path = self.executor.ersatz_dir.join(&ident);
-		let pull_config = PullConfig {
-			cache: self.context.image_cache.clone(),
-			..PullConfig::default()
-		};
-		let bundle = pull_and_unpack(&reference, &bundle_path, &pull_config).map_err(|err| {
-			Error::SystemSetupError {
-				msg: "failed to obtain image".into(),
-				cause: Box::new(err),
+		let mut working_dir = None;
+		// Held until the container exited.
+		let mut rootfs_lock = None;
+		if runtime.needs_image() {
+			let (manifest_digest, image_config, lock) =
+				self.setup_root(&reference, &bundle_path, &mut mounts)?;
+			rootfs_lock = lock;
+			if manifest_digest != reference_digest {
+				let msg = "digest of 'oci' input and actual image do not match".into();
+				return Err(Error::SystemSetupCauseless { msg });
+			}
+			if !self.context.ignore_image_config {
+				working_dir = apply_image_config(&image_config, &mut environment)?;
 			}
-		})?;
-		if bundle.manifest_digest != reference_digest {
-			let msg = "digest of 'oci' input and actual image do not match".into();
-			return Err(Error::SystemSetupCauseless { msg });
 		}
 
 		progress.set(3, "run container");
 
 		let params = ContainerParams {
 			ident,
-			runtime: self.context.runtime.clone(),
+			runtime,
 			command,
 			mounts,
 			environment,
+			working_dir,
 			root_path: bundle_path.join("rootfs"),
+			network,
+			spec_patches: vec![
+				("context", self.context.spec_patch.clone()),
+				("formula context", formula_spec_patch),
+			],
 		};
-		self.executor.run(&params, outbox)?;
+		let exit_code = self.executor.run(&params, outbox)?;
+		drop(rootfs_lock);
+		// The outputs of failed containers are not packed: the exit code tells the failure.
+		if exit_code != Some(0) {
+			return Ok(Vec::new());
+		}
 
 		progress.set(5, "pack outputs");
 
-		pack_outputs(&self.context.output_path, &outputs)
+		pack_outputs(&OutputTargets::new(self.context), &outputs)
+	}
+
+	/// Provides the image of the `/` input as root filesystem of the bundle, returning its
+	/// manifest digest and configuration.
+	///
+	/// With an image cache, the image is unpacked only once into the cache and mounted as
+	/// lower directory of an overlayfs at `/`, with an upper directory for this run.
+	/// The returned lock keeps the unpacked image in the cache, so it must be held until
+	/// the container exited.  Otherwise, the image is unpacked into the bundle.
+	fn setup_root(
+		&self,
+		reference: &Reference,
+		bundle_path: &Path,
+		mounts: &mut IndexMap<String, MountSpec>,
+	) -> Result<(String, ImageConfiguration, Option<RootfsLock>)> {
+		let pull_config = oci_unpack::PullConfig {
+			progress: Some(layer_progress_bars()),
+			..self.context.pull_config()
+		};
+		let map_err = |err| Error::SystemSetupError {
+			msg: "failed to obtain image".into(),
+			cause: Box::new(err),
+		};
+
+		if self.context.image_cache.is_none() {
+			let bundle = pull_and_unpack(reference, bundle_path, &pull_config).map_err(map_err)?;
+			return Ok((bundle.manifest_digest, bundle.config, None));
+		}
+
+		let rootfs = pull_and_unpack_rootfs(reference, &pull_config).map_err(map_err)?;
+		// The root of the spec only needs to exist: the overlayfs is mounted onto it.
+		fs::create_dir_all(bundle_path.join("rootfs")).map_err(|err| Error::SystemSetupError {
+			msg: "failed to create rootfs directory of bundle".into(),
+			cause: Box::new(err),
+		})?;
+		let run_dir = &self.executor.ersatz_dir;
+		let mount_spec = MountSpec::new_overlayfs(self.context, &rootfs.path, "/", run_dir)?;
+		mounts.shift_insert(0, "/".into(), mount_spec);
+		Ok((rootfs.manifest_digest, rootfs.config, Some(rootfs.lock)))
 	}
 
 	/// Create all input mounts and collect environment variable inputs.
+	///
+	/// Ware inputs are fetched from the given warehouses (or the ware mirrors of the context),
+	/// unless they are available locally already.
 	fn setup_inputs(
 		&self,
 		formula_inputs: IndexMap<SandboxPort, FormulaInput>,
+		warehouses: &IndexMap<WareID, WarehouseAddr>,
 	) -> Result<(IndexMap<String, MountSpec>, IndexMap<String, String>)> {
+		let ware_store = match &self.context.ware_cache {
+			Some(ware_cache) => WareStore::new(ware_cache),
+			None => WareStore::new(self.executor.ersatz_dir.join("wares")),
+		};
+
 		let mut mounts = IndexMap::new();
 		let mut environment = IndexMap::new();
 
@@ -258,7 +455,17 @@ impl<'a> Formula<'a> {
 				}
 				Some("/") => {
 					match input {
-						FormulaInput::Ware(_ware_id) => todo!(),
+						FormulaInput::Ware(ware_id) => {
+							let mirrors = self.context.ware_mirrors.get(&ware_id);
+							let addrs = (warehouses.get(&ware_id).into_iter())
+								.chain(mirrors.into_iter().flatten())
+								.collect::<IndexSet<_>>();
+							let addrs = addrs.into_iter().collect::<Vec<_>>();
+							let ware_path = ware_store.obtain(&ware_id, &addrs)?;
+							let mount_spec =
+								MountSpec::new_bind(self.context, ware_path, &port, true)?;
+							mounts.insert(port, mount_spec);
+						}
 						FormulaInput::Mount(Mount::ReadOnly(host_path)) => {
 							let mount_spec =
 								MountSpec::new_bind(self.context, host_path, &port, true)?;
@@ -312,6 +519,7 @@ impl<'a> Formula<'a> {
 				GatherDirective {
 					from: SandboxPort(port),
 					packtype,
+					filters,
 				},
 			) = output;
 
@@ -337,9 +545,81 @@ impl<'a> Formula<'a> {
 				name,
 				host_path: output_dir,
 				packtype: OutputPacktype::parse(&packtype)?,
+				filters: Filters::new(filters.as_ref()),
 			});
 		}
 
 		Ok(outputs)
 	}
 }
+
+/// Merges the environment of an image config into the environment of a formula,
+/// returning the working directory of the image, if it specifies one.
+///
+/// Variables of the formula take precedence over the ones of the image.
+/// Containers only map root to the invoking user, so images configured to run as another
+/// user are rejected, unless their config is ignored.
+pub(crate) fn apply_image_config(
+	image_config: &ImageConfiguration,
+	environment: &mut IndexMap<String, String>,
+) -> Result<Option<String>> {
+	let Some(config) = image_config.config() else {
+		return Ok(None);
+	};
+	if let Some(user) = (config.user().as_deref()).filter(|user| !is_root(user)) {
+		let msg = format!(
+			"image runs as user '{user}', but containers only run as root \
+			 (ignoring the image config runs it as root anyway)"
+		);
+		return Err(Error::SystemSetupCauseless { msg });
+	}
+
+	let mut merged: IndexMap<String, String> = (config.env().iter().flatten())
+		.filter_map(|var| var.split_once('='))
+		.map(|(name, value)| (name.to_owned(), value.to_owned()))
+		.collect();
+	merged.extend(environment.drain(..));
+	*environment = merged;
+
+	Ok(config.working_dir().clone().filter(|dir| !dir.is_empty()))
+}
+
+/// Whether the user of an image config (`user[:group]`, by name or id) is root.
+fn is_root(user: &str) -> bool {
+	user.is_empty() || user.split(':').all(|part| part == "0" || part == "root")
+}
+
+/// Progress callback showing a bar for each layer being downloaded.
+///
+/// Bars are removed as soon as their layer is done, layers found in the cache are only logged.
+fn layer_progress_bars() -> ProgressCallback {
+	let bars = Mutex::new(HashMap::new());
+	ProgressCallback::new(move |layer: &LayerProgress| {
+		let mut bars = bars.lock().unwrap();
+		match layer.state {
+			LayerState::Downloading => {
+				let bar = bars.entry(layer.index).or_insert_with(|| {
+					let text = format!(
+						"layer {}/{} {}",
+						layer.index + 1,
+						layer.layers,
+						layer.digest
+					);
+					Bar::new(layer.size, text)
+				});
+				bar.set_position(layer.downloaded);
+			}
+			LayerState::Done => {
+				bars.remove(&layer.index);
+			}
+			LayerState::Cached => {
+				debug!(
+					"layer {}/{} {}: cached",
+					layer.index + 1,
+					layer.layers,
+					layer.digest
+				);
+			}
+		}
+	})
+}
@@ -0,0 +1,280 @@
+//! Hashing of filesystem trees, as used to identify `tar` wares.
+//!
+//! Wares are identified by the filesystem they contain rather than by the bytes of
+//! their tarball: the tree is walked in path order, the metadata of every entry is filtered
+//! (by default uid, gid and mtime are replaced by fixed values, see [Filters]), and the sha384
+//! digest over the records of all entries is encoded as base58, e.g. `4z9DCT...`.
+//! Tarballs written by [crate::pack::tar_dir] carry exactly the filtered metadata, so the
+//! filesystem unpacked from them hashes to the same ware ID (see [crate::ware::hash_unpacked]).
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha384};
+use warpforge_api::formula::{FilterMap, IdFilter, MtimeFilter, SetidFilter, StickyFilter};
+
+use crate::{Error, Result};
+
+/// Default owner of all entries of packed wares.
+const UID: u64 = 1000;
+/// Default group of all entries of packed wares.
+const GID: u64 = 1000;
+/// Default modification time of all entries of packed wares: 2010-01-01T00:00:00Z.
+const MTIME: u64 = 1262304000;
+
+const SETID_BITS: u32 = 0o6000;
+const STICKY_BIT: u32 = 0o1000;
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum EntryKind {
+	Dir,
+	File,
+	Symlink,
+}
+
+/// Filters applied to the metadata of entries, resolved from the [FilterMap] of an output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Filters {
+	/// Owner of all entries, or `None` to keep the owner of each file.
+	pub(crate) uid: Option<u64>,
+	/// Group of all entries, or `None` to keep the group of each file.
+	pub(crate) gid: Option<u64>,
+	/// Modification time of all entries, or `None` to keep the mtime of each file.
+	pub(crate) mtime: Option<u64>,
+	pub(crate) sticky: StickyFilter,
+	pub(crate) setid: SetidFilter,
+}
+
+impl Default for Filters {
+	fn default() -> Self {
+		Self {
+			uid: Some(UID),
+			gid: Some(GID),
+			mtime: Some(MTIME),
+			sticky: StickyFilter::Keep,
+			setid: SetidFilter::Reject,
+		}
+	}
+}
+
+impl Filters {
+	/// Filters of an output, with the defaults for all filters it doesn't set.
+	pub(crate) fn new(filters: Option<&FilterMap>) -> Self {
+		let default = Self::default();
+		let Some(filters) = filters else {
+			return default;
+		};
+		let id = |filter, default| match filter {
+			None => default,
+			Some(IdFilter::Keep) => None,
+			Some(IdFilter::Id(id)) => Some(id as u64),
+		};
+		Self {
+			uid: id(filters.uid, default.uid),
+			gid: id(filters.gid, default.gid),
+			mtime: match filters.mtime {
+				None => default.mtime,
+				Some(MtimeFilter::Keep) => None,
+				Some(MtimeFilter::Epoch) => Some(0),
+			},
+			sticky: filters.sticky.unwrap_or(default.sticky),
+			setid: filters.setid.unwrap_or(default.setid),
+		}
+	}
+}
+
+/// Entry of a filesystem tree, with the metadata preserved by wares.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Entry {
+	/// Path relative to the root of the tree, which itself is `.`.
+	pub(crate) path: PathBuf,
+	pub(crate) kind: EntryKind,
+	/// Permission bits, including the setuid, setgid and sticky bits.
+	pub(crate) mode: u32,
+	pub(crate) uid: u64,
+	pub(crate) gid: u64,
+	pub(crate) mtime: u64,
+	pub(crate) size: u64,
+	pub(crate) link_target: Option<PathBuf>,
+}
+
+/// Lists the entries of a filesystem tree in path order, starting with the root itself,
+/// with their metadata filtered.
+///
+/// Only directories, regular files and symlinks can be part of wares:
+/// any other file type is an error, just like setuid and setgid files,
+/// unless the filters keep or strip these bits.
+pub(crate) fn walk(root: impl AsRef<Path>, filters: &Filters) -> Result<Vec<Entry>> {
+	let map_io_err = |path: &Path, err| Error::SystemRuntimeError {
+		msg: format!("failed to read '{}'", path.display()),
+		cause: Box::new(err),
+	};
+
+	let mut entries = Vec::new();
+	let mut pending = vec![PathBuf::from(".")];
+	while let Some(path) = pending.pop() {
+		let host_path = root.as_ref().join(&path);
+		let metadata =
+			fs::symlink_metadata(&host_path).map_err(|err| map_io_err(&host_path, err))?;
+		let file_type = metadata.file_type();
+		let kind = if file_type.is_dir() {
+			EntryKind::Dir
+		} else if file_type.is_file() {
+			EntryKind::File
+		} else if file_type.is_symlink() {
+			EntryKind::Symlink
+		} else {
+			let kind = if file_type.is_fifo() {
+				"fifo"
+			} else if file_type.is_socket() {
+				"socket"
+			} else {
+				"device"
+			};
+			let msg = format!("cannot pack {kind} '{}'", host_path.display());
+			return Err(Error::SystemRuntimeError {
+				msg,
+				cause: io::Error::from(io::ErrorKind::Unsupported).into(),
+			});
+		};
+
+		let link_target = match kind {
+			EntryKind::Symlink => {
+				Some(fs::read_link(&host_path).map_err(|err| map_io_err(&host_path, err))?)
+			}
+			_ => None,
+		};
+		let mut mode = metadata.mode() & 0o7777;
+		if mode & SETID_BITS != 0 && kind != EntryKind::Symlink {
+			match filters.setid {
+				SetidFilter::Reject => {
+					let msg = format!("cannot pack setuid or setgid '{}'", host_path.display());
+					return Err(Error::SystemRuntimeError {
+						msg,
+						cause: io::Error::from(io::ErrorKind::PermissionDenied).into(),
+					});
+				}
+				SetidFilter::Strip => mode &= !SETID_BITS,
+				SetidFilter::Keep => {}
+			}
+		}
+		if filters.sticky == StickyFilter::Strip {
+			mode &= !STICKY_BIT;
+		}
+
+		entries.push(Entry {
+			path: path.clone(),
+			kind,
+			mode,
+			uid: filters.uid.unwrap_or(metadata.uid() as u64),
+			gid: filters.gid.unwrap_or(metadata.gid() as u64),
+			mtime: (filters.mtime).unwrap_or(metadata.mtime().max(0) as u64),
+			size: if kind == EntryKind::File {
+				metadata.len()
+			} else {
+				0
+			},
+			link_target,
+		});
+
+		if kind == EntryKind::Dir {
+			let mut children = Vec::new();
+			for child in fs::read_dir(&host_path).map_err(|err| map_io_err(&host_path, err))? {
+				let child = child.map_err(|err| map_io_err(&host_path, err))?;
+				children.push(child.file_name());
+			}
+			// Reversed, so the children are popped in order.
+			children.sort_unstable_by(|a, b| b.cmp(a));
+			pending.extend(children.into_iter().map(|name| path.join(name)));
+		}
+	}
+	Ok(entries)
+}
+
+/// Hash of a filesystem tree, identifying it as `tar` ware.
+pub(crate) fn hash_tree(root: impl AsRef<Path>, filters: &Filters) -> Result<String> {
+	let entries = walk(&root, filters)?;
+	hash_entries(root, &entries)
+}
+
+/// Hash of the entries of a filesystem tree (see [walk]).
+///
+/// Every entry contributes a record of its path, kind, mode, (filtered) owner and mtime,
+/// link target and the sha384 digest of its content.
+pub(crate) fn hash_entries(root: impl AsRef<Path>, entries: &[Entry]) -> Result<String> {
+	let mut digester = Sha384::new();
+	for entry in entries {
+		let content = match entry.kind {
+			EntryKind::File => {
+				let path = root.as_ref().join(&entry.path);
+				let mut content = Sha384::new();
+				File::open(&path)
+					.and_then(|mut file| io::copy(&mut file, &mut content))
+					.map_err(|err| Error::SystemRuntimeError {
+						msg: format!("failed to hash '{}'", path.display()),
+						cause: Box::new(err),
+					})?;
+				format!("{:x}", content.finalize())
+			}
+			EntryKind::Dir | EntryKind::Symlink => String::new(),
+		};
+		let kind = match entry.kind {
+			EntryKind::Dir => "dir",
+			EntryKind::File => "file",
+			EntryKind::Symlink => "symlink",
+		};
+		let link_target = (entry.link_target.as_deref()).unwrap_or(Path::new(""));
+
+		// Fields are separated by NUL, which can't be part of paths.
+		digester.update(entry.path.as_os_str().as_encoded_bytes());
+		write!(
+			digester,
+			"\0{kind}\0{:o}\0{}\0{}\0{}\0",
+			entry.mode, entry.uid, entry.gid, entry.mtime
+		)
+		.expect("writing to a digester never fails");
+		digester.update(link_target.as_os_str().as_encoded_bytes());
+		writeln!(digester, "\0{content}").expect("writing to a digester never fails");
+	}
+	Ok(encode_sha384(&digester.finalize()))
+}
+
+/// Encodes a sha384 digest as used by ware IDs.
+///
+/// The digest is encoded as base58 without any multihash prefix, like the ware IDs of the
+/// warpsys catalog.
+pub(crate) fn encode_sha384(digest: &[u8]) -> String {
+	base58(digest)
+}
+
+/// Encodes bytes as base58, using the alphabet of bitcoin (and multibase).
+pub(crate) fn base58(bytes: &[u8]) -> String {
+	// Digits of the number in base 58, least significant first.
+	let mut digits: Vec<u8> = Vec::with_capacity(bytes.len() * 138 / 100 + 1);
+	for &byte in bytes {
+		let mut carry = byte as u32;
+		for digit in digits.iter_mut() {
+			carry += (*digit as u32) << 8;
+			*digit = (carry % 58) as u8;
+			carry /= 58;
+		}
+		while carry > 0 {
+			digits.push((carry % 58) as u8);
+			carry /= 58;
+		}
+	}
+
+	// Every leading zero byte is encoded as a leading '1'.
+	let zeros = bytes.iter().take_while(|&&byte| byte == 0).count();
+	let encoded = std::iter::repeat_n(b'1', zeros).chain(
+		digits
+			.iter()
+			.rev()
+			.map(|&digit| BASE58_ALPHABET[digit as usize]),
+	);
+	String::from_utf8(encoded.collect()).expect("alphabet is ASCII")
+}
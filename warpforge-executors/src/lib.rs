@@ -1,17 +1,25 @@
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use context::Context;
 use indexmap::IndexMap;
+use runtime::ContainerRuntime;
+use warpforge_api::content::WareID;
 
 pub mod context;
 mod errors;
 mod events;
 pub mod execute;
 pub mod formula;
+mod fshash;
+mod memo;
 mod oci;
 mod pack;
 pub mod plot;
+mod record;
+pub mod runtime;
+mod ware;
 
 #[cfg(test)]
 mod tests;
@@ -28,24 +36,27 @@ pub use events::Event;
 /// Generating any tempdirs for overlayFSes also should've happened already.
 pub struct ContainerParams {
 	ident: String,
-	/// OCI compatible container runtime.
-	runtime: PathBuf,
+	/// Runtime running the container.
+	runtime: Arc<dyn ContainerRuntime>,
 	command: Vec<String>,
 	/// Mounts, mapped by destination.
 	mounts: IndexMap<String, MountSpec>,
+	/// Environment variables, replacing variables of the same name set by the executor.
 	environment: IndexMap<String, String>,
+	/// Working directory of the command, instead of `/`.
+	working_dir: Option<String>,
 	root_path: PathBuf,
+	/// Whether the container uses the network of the host, instead of an isolated network.
+	network: bool,
+	/// User patches of the OCI spec, applied in order, by the name of their origin.
+	spec_patches: Vec<(&'static str, json_patch::Patch)>,
 }
 
 #[derive(PartialEq, Hash, Clone, Debug)]
 pub struct Output {
 	pub name: String,
-	pub digest: Digest,
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub enum Digest {
-	Sha384(String),
+	/// Identifies the packed output, e.g. to use it as `ware:` input of another formula.
+	pub ware_id: WareID,
 }
 
 pub struct MountSpec {
@@ -0,0 +1,283 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use indexmap::IndexMap;
+use json_patch::Patch;
+use serde_json::{json, Map, Value};
+use sha2::{Digest as _, Sha384};
+use warpforge_api::formula::{FormulaCapsule, FormulaInput, Mount};
+use warpforge_api::plot::LocalLabel;
+use warpforge_api::run_record::{RunRecord, RunRecordCapsule};
+
+use crate::formula::uses_network;
+use crate::fshash::Filters;
+use crate::pack::{store_ware_file, tar_dir, OutputPacktype, OutputTargets};
+use crate::ware::{hash_unpacked, unpack_tar, warehouse_subpath};
+use crate::{Error, Result};
+
+const MEMO_FILE: &str = "memo.json";
+const OUTPUTS_DIR: &str = "outputs";
+
+/// Identity of a formula: the sha384 digest of its canonical serialization.
+///
+/// The canonical serialization is compact JSON with all object keys sorted,
+/// so the ID does not depend on the order the formula was written in.
+pub(crate) fn formula_id(formula: &FormulaCapsule) -> String {
+	let value = serde_json::to_value(formula).expect("formulas always serialize");
+	let canonical = serde_json::to_vec(&canonicalize(value)).expect("values always serialize");
+	format!("{:x}", Sha384::digest(canonical))
+}
+
+fn canonicalize(value: Value) -> Value {
+	match value {
+		Value::Object(object) => {
+			let mut entries = object.into_iter().collect::<Vec<_>>();
+			entries.sort_by(|(left, _), (right, _)| left.cmp(right));
+			let object = (entries.into_iter())
+				.map(|(key, value)| (key, canonicalize(value)))
+				.collect::<Map<_, _>>();
+			Value::Object(object)
+		}
+		Value::Array(array) => Value::Array(array.into_iter().map(canonicalize).collect()),
+		value => value,
+	}
+}
+
+/// Key of the memo of a formula, whose container spec is amended by patches.
+///
+/// Patches and ignoring the image configuration can change the results of a formula,
+/// so they are part of the key. Without either, the key is the formula ID.
+pub(crate) fn memo_key(
+	formula_id: &str,
+	context_patch: &Patch,
+	formula_patch: &[Value],
+	ignore_image_config: bool,
+) -> String {
+	if context_patch.0.is_empty() && formula_patch.is_empty() && !ignore_image_config {
+		return formula_id.to_owned();
+	}
+	let key = json!([
+		formula_id,
+		context_patch,
+		formula_patch,
+		ignore_image_config
+	]);
+	let canonical = serde_json::to_vec(&canonicalize(key)).expect("values always serialize");
+	format!("{:x}", Sha384::digest(canonical))
+}
+
+/// Whether the results of the formula only depend on the formula itself.
+///
+/// Mounts make a formula depend on the state of the host, and host networking on
+/// whatever it downloads, so neither kind of formula is memoized.
+pub(crate) fn is_hermetic(formula: &FormulaCapsule) -> bool {
+	let FormulaCapsule::V1(formula) = formula;
+	!uses_network(&formula.action)
+		&& (formula.inputs.values()).all(|input| !matches!(input, FormulaInput::Mount(_)))
+}
+
+/// Replaces read-only mounts of known directories with the wares of their contents.
+///
+/// Plot steps receive the outputs of other steps as mounts.  Since those outputs are
+/// identified by their digests, the formula is hermetic again after this replacement.
+pub(crate) fn substitute_mounts(
+	formula: &FormulaCapsule,
+	digests: &IndexMap<PathBuf, String>,
+) -> FormulaCapsule {
+	let FormulaCapsule::V1(formula) = formula;
+	let mut formula = formula.to_owned();
+	for input in formula.inputs.values_mut() {
+		let FormulaInput::Mount(Mount::ReadOnly(host_path)) = input else {
+			continue;
+		};
+		if let Some(digest) = digests.get(Path::new(host_path)) {
+			*input = FormulaInput::Ware(format!("tar:{digest}").parse().expect("valid ware id"));
+		}
+	}
+	FormulaCapsule::V1(formula)
+}
+
+/// Store of the outputs of formulas which ran before, by formula ID.
+///
+/// Every memo is a directory `<root>/<formula ID>` containing a `memo.json` with the
+/// run record of the formula, and a copy of every output in `outputs/`.
+/// Directory outputs are stored as tar archives.
+pub(crate) struct MemoStore {
+	root: PathBuf,
+}
+
+impl MemoStore {
+	pub(crate) fn new(root: impl AsRef<Path>) -> Self {
+		Self {
+			root: root.as_ref().to_owned(),
+		}
+	}
+
+	/// Places the memoized outputs of the formula in the warehouse and the export directory.
+	///
+	/// Returns the record of the run which produced the outputs, or `None`,
+	/// if the formula is not memoized yet, or the memo is damaged.
+	/// Outputs of damaged memos are not placed anywhere.
+	pub(crate) fn restore(
+		&self,
+		formula_id: &str,
+		outputs: &[(String, OutputPacktype, Filters)],
+		targets: &OutputTargets,
+	) -> Result<Option<RunRecord>> {
+		let memo_dir = self.root.join(formula_id);
+		let Ok(file) = File::open(memo_dir.join(MEMO_FILE)) else {
+			return Ok(None);
+		};
+		let Ok(RunRecordCapsule::V1(record)) = serde_json::from_reader(BufReader::new(file)) else {
+			return Ok(None);
+		};
+
+		for dir in [&targets.warehouse, &targets.export_dir]
+			.into_iter()
+			.flatten()
+		{
+			fs::create_dir_all(dir).map_err(|err| Error::SystemRuntimeError {
+				msg: "failed to create directory".into(),
+				cause: Box::new(err),
+			})?;
+		}
+
+		// Outputs are restored into a staging directory first, and only moved
+		// into the export directory once all of them match the memo.
+		let staging = tempfile::Builder::new()
+			.prefix(".restore-")
+			.tempdir_in(targets.export_dir.as_ref().unwrap_or(&self.root))
+			.map_err(|err| Error::SystemRuntimeError {
+				msg: "failed to create directory".into(),
+				cause: Box::new(err),
+			})?;
+
+		let mut restored = Vec::new();
+		for (name, packtype, filters) in outputs {
+			let Some(expected) = record.results.get(&LocalLabel(name.to_owned())) else {
+				return Ok(None);
+			};
+			if expected.packtype.0 != packtype.ware_packtype() {
+				return Ok(None);
+			}
+
+			let source = memo_dir.join(OUTPUTS_DIR).join(name);
+			let staged = staging.path().join(name);
+			let digest = match packtype {
+				OutputPacktype::None => {
+					let reader = File::open(&source).map(BufReader::new);
+					let Ok(tar_paths) = reader.and_then(|reader| unpack_tar(reader, &staged))
+					else {
+						return Ok(None);
+					};
+					hash_unpacked(&staged, &tar_paths, filters)?
+				}
+				OutputPacktype::TarGzip => {
+					// The packed file is only unpacked to verify its hash.
+					let unpacked_dir = staging.path().join(".unpacked");
+					let unpacked = unpacked_dir.join(name);
+					let reader = File::open(&source).map(BufReader::new);
+					if fs::copy(&source, &staged).is_err()
+						|| fs::create_dir_all(&unpacked_dir).is_err()
+					{
+						return Ok(None);
+					}
+					let Ok(tar_paths) = reader.and_then(|reader| unpack_tar(reader, &unpacked))
+					else {
+						return Ok(None);
+					};
+					hash_unpacked(&unpacked, &tar_paths, filters)?
+				}
+			};
+			if digest.as_ref() != Some(&expected.hash) {
+				return Ok(None);
+			}
+			restored.push((name, staged, source, &expected.hash));
+		}
+
+		for (name, staged, source, hash) in restored {
+			if let Some(export_dir) = &targets.export_dir {
+				fs::rename(&staged, export_dir.join(name)).map_err(|err| {
+					Error::SystemRuntimeError {
+						msg: format!("failed to export output '{name}'"),
+						cause: Box::new(err),
+					}
+				})?;
+			}
+			if let Some(warehouse) = &targets.warehouse {
+				store_ware_file(warehouse, hash, &source)?;
+			}
+		}
+
+		Ok(Some(record))
+	}
+
+	/// Memoizes the outputs of a formula, which were just packed into the output targets.
+	pub(crate) fn store(
+		&self,
+		formula_id: &str,
+		outputs: &[(String, OutputPacktype, Filters)],
+		record: &RunRecord,
+		targets: &OutputTargets,
+	) -> Result<()> {
+		let memo_dir = self.root.join(formula_id);
+		if memo_dir.exists() {
+			return Ok(());
+		}
+
+		let map_io_err = |err| Error::SystemRuntimeError {
+			msg: format!("failed to memoize formula '{formula_id}'"),
+			cause: Box::new(err),
+		};
+
+		fs::create_dir_all(&self.root).map_err(map_io_err)?;
+		// Fill a staging directory first, so memos are complete or not there at all.
+		let staging = tempfile::Builder::new()
+			.prefix(".memo-")
+			.tempdir_in(&self.root)
+			.map_err(map_io_err)?;
+		let staging_outputs = staging.path().join(OUTPUTS_DIR);
+		fs::create_dir(&staging_outputs).map_err(map_io_err)?;
+
+		for (name, packtype, filters) in outputs {
+			let target = staging_outputs.join(name);
+			// With a warehouse, the packed outputs are there already.
+			let ware = record.results.get(&LocalLabel(name.to_owned()));
+			if let (Some(warehouse), Some(ware_id)) = (&targets.warehouse, ware) {
+				let source = warehouse.join(warehouse_subpath(&ware_id.hash));
+				fs::copy(source, &target).map_err(map_io_err)?;
+				continue;
+			}
+
+			let source = (targets.export_dir.clone().unwrap_or_default()).join(name);
+			match packtype {
+				OutputPacktype::None => {
+					let writer = File::create(&target)
+						.map(BufWriter::new)
+						.map_err(map_io_err)?;
+					tar_dir(&source, writer, filters)?;
+				}
+				OutputPacktype::TarGzip => {
+					fs::copy(&source, &target).map_err(map_io_err)?;
+				}
+			}
+		}
+
+		let capsule = RunRecordCapsule::V1(record.to_owned());
+		let memo_file = File::create(staging.path().join(MEMO_FILE)).map_err(map_io_err)?;
+		serde_json::to_writer_pretty(memo_file, &capsule).map_err(|err| {
+			Error::SystemRuntimeError {
+				msg: format!("failed to memoize formula '{formula_id}'"),
+				cause: Box::new(err),
+			}
+		})?;
+
+		match fs::rename(staging.path(), &memo_dir) {
+			Ok(()) => Ok(()),
+			// Someone else memoized the same formula in the meantime.
+			Err(_) if memo_dir.exists() => Ok(()),
+			Err(err) => Err(map_io_err(err)),
+		}
+	}
+}
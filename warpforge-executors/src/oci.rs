@@ -23,7 +23,7 @@ impl ToOCIMount for crate::MountSpec {
 // (Rust has a "lazy_static" feature, but it's a crate rather than core, and doesn't seem essential here.)
 //
 // We use json values because that's what they are when they get sent to the subprocesses.
-// We also (plan to -- future work) let users amend these values by a simple JSON Patch API.
+// We also let users amend these values by a simple JSON Patch API (see `Context::spec_patch`).
 // So, overall, KISS means "just treat it like JSON all the way through".
 //
 // (Yes, there is a crate for OCI spec stuff: https://github.com/containers/oci-spec-rs --
@@ -94,3 +94,34 @@ pub fn oci_spec_base() -> serde_json::Value {
 		}
 	})
 }
+
+/// Adds a user namespace, mapping root in the container to the current user.
+///
+/// Rootless operation with `runc` (and `crun`) requires this.
+pub(crate) fn add_user_namespace(spec: &mut serde_json::Value) {
+	use syscalls::{syscall, Sysno};
+	let uid = match unsafe { syscall!(Sysno::getuid) } {
+		Ok(uid) => uid,
+		Err(err) => {
+			eprintln!("syscall getuid() failed: {}", err);
+			0
+		}
+	};
+	let gid = match unsafe { syscall!(Sysno::getgid) } {
+		Ok(id) => id,
+		Err(err) => {
+			eprintln!("syscall getgid() failed: {}", err);
+			0
+		}
+	};
+
+	let p: json_patch::Patch = serde_json::from_value(json!([
+		{ "op": "add", "path": "/linux/uidMappings", "value":
+		   [{"containerID": 0, "hostID": uid, "size": 1}]},
+		{ "op": "add", "path": "/linux/gidMappings", "value":
+		   [{"containerID": 0, "hostID": gid, "size": 1}]},
+		{ "op": "add", "path": "/linux/namespaces/-", "value": {"type": "user"}},
+	]))
+	.unwrap();
+	json_patch::patch(spec, &p).unwrap();
+}
@@ -1,22 +1,26 @@
 use std::{
 	fs::{self, File},
-	io::{BufWriter, Write},
+	io::{self, BufWriter, Write},
 	path::{Path, PathBuf},
 };
 
 use flate2::{write::GzEncoder, Compression};
-use oci_unpack::tee::WriteExt;
-use sha2::{Digest, Sha384};
-use warpforge_api::content::Packtype;
+use tempfile::NamedTempFile;
+use warpforge_api::content::{Packtype, WareID};
 
+use crate::context::Context;
+use crate::fshash::{self, Entry, EntryKind, Filters};
+use crate::ware::warehouse_subpath;
 use crate::{Error, Output, Result};
 
 pub(crate) struct IntermediateOutput {
 	pub(crate) name: String,
 	pub(crate) host_path: PathBuf,
 	pub(crate) packtype: OutputPacktype,
+	pub(crate) filters: Filters,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum OutputPacktype {
 	None,
 	TarGzip,
@@ -34,10 +38,39 @@ impl OutputPacktype {
 			}
 		})
 	}
+
+	/// Packtype of the ware IDs identifying packed outputs.
+	///
+	/// Outputs are identified by the hash of their filesystem tree whether they are
+	/// compressed or not, so their ware IDs don't depend on the compressor.
+	pub(crate) fn ware_packtype(&self) -> &'static str {
+		"tar"
+	}
+}
+
+/// Where the outputs of a formula or plot are placed.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OutputTargets {
+	/// Content-addressed warehouse the packed outputs are stored in.
+	pub(crate) warehouse: Option<PathBuf>,
+	/// Directory outputs are exported to under their name, for humans (and plot steps).
+	pub(crate) export_dir: Option<PathBuf>,
+}
+
+impl OutputTargets {
+	/// Targets of the outputs in a context: see [Context::warehouse] and [Context::output_path].
+	pub(crate) fn new(context: &Context) -> Self {
+		let export_dir = (context.output_path.clone())
+			.or_else(|| context.warehouse.is_none().then(PathBuf::new));
+		Self {
+			warehouse: context.warehouse.clone(),
+			export_dir,
+		}
+	}
 }
 
 pub(crate) fn pack_outputs(
-	output_dir: &Option<PathBuf>,
+	targets: &OutputTargets,
 	outputs: &[IntermediateOutput],
 ) -> Result<Vec<Output>> {
 	if outputs.is_empty() {
@@ -46,78 +79,202 @@ pub(crate) fn pack_outputs(
 
 	let mut results = Vec::new();
 
-	let target_dir = output_dir.clone().unwrap_or_default();
-	fs::create_dir_all(&target_dir).map_err(|err| Error::SystemRuntimeError {
-		msg: "failed to create directory".into(),
-		cause: Box::new(err),
-	})?;
+	for dir in [&targets.warehouse, &targets.export_dir]
+		.into_iter()
+		.flatten()
+	{
+		fs::create_dir_all(dir).map_err(|err| Error::SystemRuntimeError {
+			msg: "failed to create directory".into(),
+			cause: Box::new(err),
+		})?;
+	}
 
 	for output in outputs {
 		let IntermediateOutput {
 			name,
 			host_path,
 			packtype,
+			filters,
 		} = output;
 
-		let target = target_dir.join(name);
-		let output = match packtype {
+		// The packed ware is written to a temporary file in the warehouse first,
+		// which is moved into place once its hash is known.
+		let staged = (targets.warehouse.as_ref())
+			.map(|warehouse| stage_ware(warehouse))
+			.transpose()?;
+		let export = targets.export_dir.as_ref().map(|dir| dir.join(name));
+		let hash = match packtype {
 			OutputPacktype::None => {
-				// TODO: Handle ErrorKind::CrossesDevices: we should handle move between mounts.
-				fs::rename(host_path, &target).map_err(|err| Error::SystemRuntimeError {
-					msg: "failed to move output dir to target".into(),
-					cause: Box::new(err),
-				})?;
-				tar_dir_hash_only(name, target)?
+				let hash = match &staged {
+					Some(staged) => tar_dir_to_file(host_path, staged.path(), filters)?,
+					None => hash_dir(host_path, filters)?,
+				};
+				if let Some(target) = &export {
+					// TODO: Handle ErrorKind::CrossesDevices: we should handle move between mounts.
+					fs::rename(host_path, target).map_err(|err| Error::SystemRuntimeError {
+						msg: "failed to move output dir to target".into(),
+						cause: Box::new(err),
+					})?;
+				}
+				hash
 			}
-			OutputPacktype::TarGzip => tgz_dir_to_file(name, host_path, &target)?,
+			OutputPacktype::TarGzip => match (&staged, &export) {
+				(Some(staged), export) => {
+					let hash = tgz_dir_to_file(host_path, staged.path(), filters)?;
+					if let Some(target) = export {
+						fs::copy(staged.path(), target).map_err(|err| {
+							Error::SystemRuntimeError {
+								msg: "failed to export output file".into(),
+								cause: Box::new(err),
+							}
+						})?;
+					}
+					hash
+				}
+				(None, Some(target)) => tgz_dir_to_file(host_path, target, filters)?,
+				(None, None) => unreachable!("outputs are exported without warehouse"),
+			},
 		};
-		results.push(output);
+
+		if let (Some(warehouse), Some(staged)) = (&targets.warehouse, staged) {
+			store_ware(warehouse, &hash, staged)?;
+		}
+		let ware_id = WareID {
+			packtype: Packtype(packtype.ware_packtype().into()),
+			hash,
+		};
+		results.push(Output {
+			name: name.to_owned(),
+			ware_id,
+		});
 	}
 
 	Ok(results)
 }
 
-pub(crate) fn tar_dir_hash_only(name: &str, source_dir: impl AsRef<Path>) -> Result<Output> {
-	let mut digester = Sha384::new();
-	tar_dir(&source_dir, &mut digester)?;
+/// Creates a temporary file for a packed ware in the warehouse.
+pub(crate) fn stage_ware(warehouse: &Path) -> Result<NamedTempFile> {
+	tempfile::Builder::new()
+		.prefix(".pack-")
+		.tempfile_in(warehouse)
+		.map_err(|err| Error::SystemRuntimeError {
+			msg: "failed to create file in warehouse".into(),
+			cause: Box::new(err),
+		})
+}
+
+/// Moves a staged packed ware into its place in the warehouse.
+///
+/// If the warehouse contains the ware already, the staged file is dropped.
+pub(crate) fn store_ware(warehouse: &Path, hash: &str, staged: NamedTempFile) -> Result<()> {
+	let target = warehouse.join(warehouse_subpath(hash));
+	if target.is_file() {
+		return Ok(());
+	}
+	let map_io_err = |err| Error::SystemRuntimeError {
+		msg: format!("failed to store ware '{hash}' in warehouse"),
+		cause: Box::new(err),
+	};
+	fs::create_dir_all(target.parent().expect("ware path has shard dirs")).map_err(map_io_err)?;
+	staged
+		.persist(&target)
+		.map_err(|err| map_io_err(err.error))?;
+	Ok(())
+}
 
-	let digest = crate::Digest::Sha384(format!("{:x}", digester.finalize()));
-	let name = name.to_owned();
-	Ok(Output { name, digest })
+/// Copies a packed ware into the warehouse, unless the warehouse contains it already.
+pub(crate) fn store_ware_file(warehouse: &Path, hash: &str, source: &Path) -> Result<()> {
+	if warehouse.join(warehouse_subpath(hash)).is_file() {
+		return Ok(());
+	}
+	let staged = stage_ware(warehouse)?;
+	fs::copy(source, staged.path()).map_err(|err| Error::SystemRuntimeError {
+		msg: format!("failed to copy ware '{hash}' into warehouse"),
+		cause: Box::new(err),
+	})?;
+	store_ware(warehouse, hash, staged)
 }
 
+/// Hash of a directory tree, as used to identify `tar` wares (see [fshash]).
+pub(crate) fn hash_dir(source_dir: impl AsRef<Path>, filters: &Filters) -> Result<String> {
+	fshash::hash_tree(source_dir, filters)
+}
+
+/// Writes the tar stream of a directory into a file, returning its hash (see [hash_dir]).
+pub(crate) fn tar_dir_to_file(
+	source_dir: impl AsRef<Path>,
+	target_file: impl AsRef<Path>,
+	filters: &Filters,
+) -> Result<String> {
+	let entries = fshash::walk(&source_dir, filters)?;
+	write_tar(&source_dir, &entries, create_output_file(target_file)?)?;
+	fshash::hash_entries(source_dir, &entries)
+}
+
+/// Writes the gzipped tar stream of a directory into a file, returning its hash (see [hash_dir]).
 pub(crate) fn tgz_dir_to_file(
-	name: &str,
 	source_dir: impl AsRef<Path>,
 	target_file: impl AsRef<Path>,
-) -> Result<Output> {
-	let writer = File::create(target_file)
+	filters: &Filters,
+) -> Result<String> {
+	let entries = fshash::walk(&source_dir, filters)?;
+	let writer = GzEncoder::new(create_output_file(target_file)?, Compression::fast());
+	write_tar(&source_dir, &entries, writer)?;
+	fshash::hash_entries(source_dir, &entries)
+}
+
+fn create_output_file(path: impl AsRef<Path>) -> Result<BufWriter<File>> {
+	File::create(path)
 		.map(BufWriter::new)
 		.map_err(|err| Error::SystemRuntimeError {
 			msg: "failed to create output file".into(),
 			cause: Box::new(err),
-		})?;
-
-	let mut digester = Sha384::new();
-	let writer = writer.tee(&mut digester);
-
-	let writer = GzEncoder::new(writer, Compression::fast());
-
-	tar_dir(source_dir, writer)?;
+		})
+}
 
-	let digest = crate::Digest::Sha384(format!("{:x}", digester.finalize()));
-	let name = name.to_owned();
-	Ok(Output { name, digest })
+/// Writes the tar stream of a directory, with the entries in path order and their
+/// metadata filtered like for hashing (see [fshash]).
+pub(crate) fn tar_dir(
+	source_dir: impl AsRef<Path>,
+	writer: impl Write,
+	filters: &Filters,
+) -> Result<()> {
+	let entries = fshash::walk(&source_dir, filters)?;
+	write_tar(source_dir, &entries, writer)
 }
 
-pub(crate) fn tar_dir(source_dir: impl AsRef<Path>, writer: impl Write) -> Result<()> {
+fn write_tar(source_dir: impl AsRef<Path>, entries: &[Entry], writer: impl Write) -> Result<()> {
 	let mut archive = tar::Builder::new(writer);
-	archive.mode(tar::HeaderMode::Deterministic);
-	archive
-		.append_dir_all("", source_dir)
-		.and_then(|_| archive.finish())
-		.map_err(|err| Error::SystemRuntimeError {
-			msg: "failed to pack output".into(),
+	for entry in entries {
+		let mut header = tar::Header::new_gnu();
+		header.set_mode(entry.mode);
+		header.set_uid(entry.uid);
+		header.set_gid(entry.gid);
+		header.set_mtime(entry.mtime);
+		header.set_size(entry.size);
+		let appended = match entry.kind {
+			EntryKind::Dir => {
+				header.set_entry_type(tar::EntryType::Directory);
+				archive.append_data(&mut header, &entry.path, io::empty())
+			}
+			EntryKind::File => {
+				header.set_entry_type(tar::EntryType::Regular);
+				File::open(source_dir.as_ref().join(&entry.path))
+					.and_then(|file| archive.append_data(&mut header, &entry.path, file))
+			}
+			EntryKind::Symlink => {
+				header.set_entry_type(tar::EntryType::Symlink);
+				let target = entry.link_target.as_ref().expect("symlinks have targets");
+				archive.append_link(&mut header, &entry.path, target)
+			}
+		};
+		appended.map_err(|err| Error::SystemRuntimeError {
+			msg: format!("failed to pack '{}'", entry.path.display()),
 			cause: Box::new(err),
-		})
+		})?;
+	}
+	archive.finish().map_err(|err| Error::SystemRuntimeError {
+		msg: "failed to pack output".into(),
+		cause: Box::new(err),
+	})
 }
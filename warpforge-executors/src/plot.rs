@@ -1,22 +1,40 @@
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
 use indexmap::{IndexMap, IndexSet};
 use oci_client::Reference;
-use oci_unpack::{pull_image_manifest, PullConfig};
+use oci_unpack::pull_image_manifest;
 use tempfile::TempDir;
+use warpforge_api::catalog::CatalogRef;
+use warpforge_api::content::WareID;
 use warpforge_api::formula::{
 	Formula, FormulaAndContext, FormulaCapsule, FormulaContext, FormulaContextCapsule,
-	FormulaInput, GatherDirective, Mount, SandboxPort,
+	FormulaInput, GatherDirective, Mount, SandboxPort, WarehouseAddr,
+};
+use warpforge_api::plot::{
+	LocalLabel, Pipe, Plot, PlotCapsule, PlotInput, PlotOutput, Step, StepName,
 };
-use warpforge_api::plot::{LocalLabel, Plot, PlotCapsule, PlotInput, PlotOutput, Step, StepName};
+use warpforge_api::run_record::{PlotRunRecord, StepRunRecord};
 use warpforge_terminal::{logln, Bar};
 
 use crate::context::Context;
-use crate::formula::run_formula;
-use crate::pack::{pack_outputs, IntermediateOutput, OutputPacktype};
-use crate::{to_string_or_panic, Error, Output, Result};
+use crate::formula::{check_exit_code, run_formula_memoized};
+use crate::fshash::Filters;
+use crate::memo;
+use crate::pack::{pack_outputs, IntermediateOutput, OutputPacktype, OutputTargets};
+use crate::record;
+use crate::{to_string_or_panic, Error, Result};
 
 const OUTPUTS_DIR: &str = "outputs";
 
-pub fn run_plot(plot: PlotCapsule, context: &Context) -> Result<Vec<Output>> {
+/// Directory (within the directory of a sub-plot step) containing the steps of the sub-plot.
+const STEPS_DIR: &str = "steps";
+
+/// Runs all steps of the plot and returns the record of the run.
+pub fn run_plot(plot: PlotCapsule, context: &Context) -> Result<PlotRunRecord> {
 	let PlotCapsule::V1(plot) = &plot;
 
 	let graph = PlotGraph::new(plot);
@@ -27,26 +45,119 @@ pub fn run_plot(plot: PlotCapsule, context: &Context) -> Result<Vec<Output>> {
 		cause: Box::new(err),
 	})?;
 
+	let output_digests = Mutex::new(IndexMap::new());
+	let slots = StepSlots::new(parallelism(context));
 	PlotExecutor {
 		context,
 		plot,
 		graph,
-		temp_dir,
+		work_dir: temp_dir.path().to_owned(),
+		output_digests: &output_digests,
+		slots: &slots,
 	}
 	.run()
 }
 
-#[allow(unused)]
+/// Maximum number of steps running at the same time: see [Context::parallelism].
+fn parallelism(context: &Context) -> usize {
+	(context.parallelism)
+		.or_else(|| thread::available_parallelism().ok())
+		.map_or(1, NonZeroUsize::get)
+}
+
+/// Limits the number of protoformula steps running at the same time,
+/// across a plot and all of its sub-plots.
+struct StepSlots {
+	free: Mutex<usize>,
+	released: Condvar,
+}
+
+impl StepSlots {
+	fn new(count: usize) -> Self {
+		Self {
+			free: Mutex::new(count),
+			released: Condvar::new(),
+		}
+	}
+
+	/// Blocks until a slot is free. The slot is released when the returned guard is dropped.
+	fn acquire(&self) -> StepSlot<'_> {
+		let mut free = self.free.lock().unwrap();
+		while *free == 0 {
+			free = self.released.wait(free).unwrap();
+		}
+		*free -= 1;
+		StepSlot(self)
+	}
+}
+
+struct StepSlot<'a>(&'a StepSlots);
+
+impl Drop for StepSlot<'_> {
+	fn drop(&mut self) {
+		*self.0.free.lock().unwrap() += 1;
+		self.0.released.notify_one();
+	}
+}
+
 struct PlotExecutor<'a> {
 	context: &'a Context,
 	plot: &'a Plot,
 	graph: PlotGraph<'a>,
-	temp_dir: TempDir,
+
+	/// Directory containing a directory for each step.
+	work_dir: PathBuf,
+
+	/// Digests of the outputs of all steps which ran, by host path.
+	/// Shared with the executors of sub-plots.
+	output_digests: &'a Mutex<IndexMap<PathBuf, String>>,
+
+	/// Slots for running protoformula steps, shared with the executors of sub-plots.
+	slots: &'a StepSlots,
 }
 
 impl<'a> PlotExecutor<'a> {
-	fn run(&self) -> Result<Vec<Output>> {
+	fn run(&self) -> Result<PlotRunRecord> {
+		let start_time = record::unix_time();
+		let steps = self.run_steps()?;
+
+		let mut outputs = Vec::new();
+		for (LocalLabel(name), PlotOutput::Pipe(pipe)) in &self.plot.outputs {
+			let (host_path, step_output) = (self.step_output(&pipe.step_name, &pipe.label))
+				.map_err(|err| Error::SystemSetupError {
+					msg: format!("output '{name}'"),
+					cause: Box::new(err),
+				})?;
+			let packtype = OutputPacktype::parse(&step_output.packtype)?;
+			outputs.push(IntermediateOutput {
+				name: name.to_owned(),
+				host_path,
+				packtype,
+				filters: Filters::new(step_output.filters.as_ref()),
+			});
+		}
+
+		let results = pack_outputs(&OutputTargets::new(self.context), &outputs)?;
+		Ok(PlotRunRecord {
+			guid: record::new_guid(),
+			start_time,
+			end_time: record::unix_time(),
+			steps,
+			results: record::ware_ids(&results),
+		})
+	}
+
+	/// Runs all steps of the plot, with up to [Context::parallelism] steps at the same time,
+	/// including the steps of sub-plots.
+	///
+	/// A step is started as soon as all steps it depends on are done.
+	/// When a step fails, no further steps are started, unless [Context::keep_going] is set:
+	/// then only the steps depending on the failed step are skipped.
+	///
+	/// Returns the records of all steps, in plot order.
+	fn run_steps(&self) -> Result<IndexMap<StepName, StepRunRecord>> {
 		let progress = Bar::new(self.plot.steps.len() as u64, "");
+		let parallelism = parallelism(self.context);
 
 		let mut parents = self.graph.parents.clone();
 		let mut next_steps = (self.graph.nodes.keys().cloned())
@@ -55,69 +166,119 @@ impl<'a> PlotExecutor<'a> {
 				None => true,
 			})
 			.collect::<Vec<_>>();
+		// Steps are popped from the back: start them in plot order.
+		next_steps.reverse();
+
+		let mut failures = Vec::new();
+		let mut records = IndexMap::new();
+		thread::scope(|scope| {
+			let (step_sender, step_receiver) = crossbeam_channel::unbounded::<&str>();
+			let (done_sender, done_receiver) = crossbeam_channel::unbounded();
+			for _ in 0..parallelism.min(self.graph.nodes.len()) {
+				let step_receiver = step_receiver.clone();
+				let done_sender = done_sender.clone();
+				scope.spawn(move || {
+					while let Ok(step_name) = step_receiver.recv() {
+						let step_progress = Bar::new(1, format!("step '{step_name}'"));
+						let result = self.run_step_catch_panic(step_name);
+						step_progress.set_position(1);
+						if done_sender.send((step_name, result)).is_err() {
+							break;
+						}
+					}
+				});
+			}
 
-		// TODO: Run multiple steps in parallel, when possible.
-		let mut completed_count = 0;
-		while let Some(step_name) = next_steps.pop() {
-			progress.set_text(step_name);
+			let mut running = 0;
+			let mut completed_count = 0;
+			loop {
+				let stopped = !failures.is_empty() && !self.context.keep_going;
+				while running < parallelism && !stopped {
+					let Some(step_name) = next_steps.pop() else {
+						break;
+					};
+					step_sender
+						.send(step_name)
+						.expect("workers live as long as the sender");
+					running += 1;
+				}
+				if running == 0 {
+					break;
+				}
+				progress.set_text(format!("{running} step(s) running"));
 
-			self.run_step(step_name)?;
+				let (step_name, result) =
+					(done_receiver.recv()).expect("workers live as long as steps are running");
+				running -= 1;
+				completed_count += 1;
+				progress.set_position(completed_count);
 
-			completed_count += 1;
-			progress.set_position(completed_count);
+				match result {
+					Ok(record) => {
+						records.insert(step_name, record);
+					}
+					Err(err) => {
+						logln!("{err}");
+						failures.push((step_name, err));
+						continue;
+					}
+				}
 
-			let Some(children) = self.graph.children.get(step_name) else {
-				continue;
-			};
-			for &child in children {
-				let child_parents = &mut parents[child];
-				let removed = child_parents.swap_remove(step_name);
-				if removed && child_parents.is_empty() {
-					next_steps.push(child);
+				let Some(children) = self.graph.children.get(step_name) else {
+					continue;
+				};
+				for &child in children {
+					let child_parents = &mut parents[child];
+					let removed = child_parents.swap_remove(step_name);
+					if removed && child_parents.is_empty() {
+						next_steps.push(child);
+					}
 				}
 			}
-		}
 
-		let mut outputs = Vec::new();
-		for (LocalLabel(name), PlotOutput::Pipe(pipe)) in &self.plot.outputs {
-			let Some(Step::Protoformula(step)) = self.plot.steps.get(&pipe.step_name) else {
-				let msg = format!("output '{name}': step '{}' not found", pipe.step_name);
-				return Err(Error::SystemSetupCauseless { msg });
-			};
-			let Some(step_output) = step.outputs.get(&pipe.label) else {
-				let msg = format!(
-					"output '{name}': step '{}' has no output named '{}'",
-					pipe.step_name, pipe.label,
-				);
-				return Err(Error::SystemSetupCauseless { msg });
-			};
-
-			let host_path = (self.temp_dir.path())
-				.join(&pipe.step_name)
-				.join(OUTPUTS_DIR)
-				.join(&pipe.label.0);
-			outputs.push(IntermediateOutput {
-				name: name.to_owned(),
-				host_path,
-				packtype: OutputPacktype::parse(&step_output.packtype)?,
-			});
+			// Closing the channel lets idle workers return.
+			drop(step_sender);
+		});
+
+		let mut failures = failures.into_iter();
+		match (failures.next(), failures.len()) {
+			(None, _) => Ok((self.plot.steps.keys())
+				.filter_map(|name| {
+					let record = records.swap_remove(name.0.as_str())?;
+					Some((name.to_owned(), record))
+				})
+				.collect()),
+			(Some((_, err)), 0) => Err(err),
+			(Some((step_name, err)), more) => {
+				let msg = format!("step '{step_name}' and {more} more step(s) failed");
+				let cause = Box::new(err);
+				Err(Error::SystemRuntimeError { msg, cause })
+			}
 		}
+	}
 
-		pack_outputs(&self.context.output_path, &outputs)
+	/// Runs a step, turning a panic into an error, so a failing worker can't stall the plot.
+	fn run_step_catch_panic(&self, step_name: &str) -> Result<StepRunRecord> {
+		panic::catch_unwind(AssertUnwindSafe(|| self.run_step(step_name))).unwrap_or_else(|_| {
+			let msg = format!("failed step '{step_name}': panicked");
+			Err(Error::CatchallCauseless { msg })
+		})
 	}
 
-	fn run_step(&self, step_name: &str) -> Result<()> {
-		let Step::Protoformula(step) = self.graph.nodes[step_name] else {
-			todo!(); // TODO: Implement sub-plots.
+	fn run_step(&self, step_name: &str) -> Result<StepRunRecord> {
+		let step = match self.graph.nodes[step_name] {
+			Step::Protoformula(step) => step,
+			Step::Plot(sub_plot) => return self.run_sub_plot(step_name, sub_plot),
 		};
 
-		let step_dir = self.temp_dir.path().join(step_name);
-		let context = Context {
+		let step_dir = self.work_dir.join(step_name);
+		let mut context = Context {
 			output_path: Some(step_dir.join(OUTPUTS_DIR)),
 			..self.context.clone()
 		};
 
 		let mut inputs = IndexMap::new();
+		let mut warehouses = IndexMap::new();
 		for (port, input) in &step.inputs {
 			let input = match input {
 				PlotInput::Mount(mount) => FormulaInput::Mount(mount.to_owned()),
@@ -125,14 +286,7 @@ impl<'a> PlotExecutor<'a> {
 				PlotInput::Ware(ware_id) => FormulaInput::Ware(ware_id.to_owned()),
 				PlotInput::Pipe(pipe) => {
 					if pipe.step_name.is_empty() {
-						let Some(plot_input) = self.plot.inputs.get(&pipe.label) else {
-							let msg = format!(
-								"invalid plot (step '{step_name}'): input '{}' not found",
-								pipe.label
-							);
-							return Err(Error::SystemSetupCauseless { msg });
-						};
-						match plot_input {
+						match self.plot_input(step_name, &pipe.label)? {
 							PlotInput::Mount(mount) => FormulaInput::Mount(mount.to_owned()),
 							PlotInput::Ware(ware_id) => FormulaInput::Ware(ware_id.to_owned()),
 							PlotInput::Literal(literal) => {
@@ -145,18 +299,22 @@ impl<'a> PlotExecutor<'a> {
 								let msg = "invalid plot: plot inputs may not contain pipes".into();
 								return Err(Error::SystemSetupCauseless { msg });
 							}
-							_ => todo!(),
+							PlotInput::CatalogRef(catalog_ref) => FormulaInput::Ware(
+								resolve_catalog_ref(self.context, catalog_ref, &mut warehouses)?,
+							),
+							PlotInput::Ingest(_ingest) => todo!(),
 						}
 					} else {
-						let path = (self.temp_dir.path())
-							.join(&pipe.step_name)
-							.join(OUTPUTS_DIR)
-							.join(&pipe.label.0);
+						let (path, _) = self.step_output(&pipe.step_name, &pipe.label)?;
 						FormulaInput::Mount(Mount::ReadOnly(to_string_or_panic(path)))
 					}
 				}
 				PlotInput::OCIReference(reference) => self.transform_oci_input(port, reference)?,
-				PlotInput::CatalogRef(_catalog_ref) => todo!(),
+				PlotInput::CatalogRef(catalog_ref) => FormulaInput::Ware(resolve_catalog_ref(
+					self.context,
+					catalog_ref,
+					&mut warehouses,
+				)?),
 				PlotInput::Ingest(_ingest) => todo!(),
 			};
 
@@ -168,6 +326,7 @@ impl<'a> PlotExecutor<'a> {
 				let output = GatherDirective {
 					from: output.from.to_owned(),
 					packtype: None,
+					filters: output.filters.to_owned(),
 				};
 				(label.to_owned(), output)
 			})
@@ -178,28 +337,112 @@ impl<'a> PlotExecutor<'a> {
 			action: step.action.clone(),
 			outputs,
 		};
+		// The formula context names one warehouse per ware: further mirrors are tried after it.
+		let formula_warehouses = (warehouses.iter())
+			.filter_map(|(ware_id, addrs)| Some((ware_id.to_owned(), addrs.first()?.to_owned())))
+			.collect();
+		context.ware_mirrors.extend(warehouses);
 		let formula = FormulaAndContext {
 			formula: FormulaCapsule::V1(formula),
 			context: FormulaContextCapsule::V1(FormulaContext {
-				warehouses: IndexMap::with_capacity(0),
+				warehouses: formula_warehouses,
+				oci_spec_patch: Vec::new(),
 			}),
 		};
-		let outputs = run_formula(formula, &context).map_err(|err| {
-			let msg = format!("failed step '{step_name}'");
+		// Outputs of other steps are mounted, but they can be identified by their digests.
+		let memo_key =
+			memo::substitute_mounts(&formula.formula, &self.output_digests.lock().unwrap());
+		let formula_id = memo::is_hermetic(&memo_key).then(|| memo::formula_id(&memo_key));
+		let slot = self.slots.acquire();
+		let record = run_formula_memoized(formula, formula_id.as_deref(), &context)
+			.and_then(|record| check_exit_code(&record).map(|()| record))
+			.map_err(|err| {
+				let msg = format!("failed step '{step_name}'");
+				let cause = Box::new(err);
+				Error::SystemRuntimeError { msg, cause }
+			})?;
+		drop(slot);
+
+		logln!("step '{step_name}'");
+		let outputs_dir = step_dir.join(OUTPUTS_DIR);
+		let mut output_digests = self.output_digests.lock().unwrap();
+		for (LocalLabel(name), ware_id) in &record.results {
+			logln!("  {ware_id} {name}");
+			output_digests.insert(outputs_dir.join(name), ware_id.hash.to_owned());
+		}
+
+		Ok(StepRunRecord::Protoformula(record))
+	}
+
+	/// Runs a sub-plot in its own executor, which shares the step slots of this one.
+	///
+	/// Pipes in the inputs of the sub-plot refer to this plot: they are resolved
+	/// before running the sub-plot, so its steps only see plain inputs.
+	fn run_sub_plot(&self, step_name: &str, sub_plot: &Plot) -> Result<StepRunRecord> {
+		let start_time = record::unix_time();
+		let mut inputs = IndexMap::new();
+		for (label, input) in &sub_plot.inputs {
+			let input = match input {
+				PlotInput::Pipe(pipe) if pipe.step_name.is_empty() => {
+					self.plot_input(step_name, &pipe.label)?.to_owned()
+				}
+				PlotInput::Pipe(pipe) => {
+					let (path, _) = self.step_output(&pipe.step_name, &pipe.label)?;
+					PlotInput::Mount(Mount::ReadOnly(to_string_or_panic(path)))
+				}
+				input => input.to_owned(),
+			};
+			inputs.insert(label.to_owned(), input);
+		}
+
+		let sub_plot = Plot {
+			inputs,
+			..sub_plot.to_owned()
+		};
+		let steps = PlotExecutor {
+			context: self.context,
+			plot: &sub_plot,
+			graph: PlotGraph::new(&sub_plot),
+			work_dir: self.work_dir.join(step_name).join(STEPS_DIR),
+			output_digests: self.output_digests,
+			slots: self.slots,
+		}
+		.run_steps()
+		.map_err(|err| {
+			let msg = format!("failed sub-plot '{step_name}'");
 			let cause = Box::new(err);
 			Error::SystemRuntimeError { msg, cause }
 		})?;
 
-		logln!("step '{step_name}'");
-		for output in outputs {
-			let Output {
-				name,
-				digest: crate::Digest::Sha384(digest),
-			} = output;
-			logln!("  sha384:{digest} {name}");
-		}
+		// Outputs of sub-plots are not packed: they are the outputs of their steps.
+		let results = (sub_plot.outputs.iter())
+			.filter_map(|(label, PlotOutput::Pipe(pipe))| {
+				Some((label.to_owned(), pipe_ware_id(&steps, pipe)?))
+			})
+			.collect();
+		Ok(StepRunRecord::Plot(PlotRunRecord {
+			guid: record::new_guid(),
+			start_time,
+			end_time: record::unix_time(),
+			steps,
+			results,
+		}))
+	}
 
-		Ok(())
+	fn plot_input(&self, step_name: &str, label: &LocalLabel) -> Result<&'a PlotInput> {
+		self.plot.inputs.get(label).ok_or_else(|| {
+			let msg = format!("invalid plot (step '{step_name}'): input '{label}' not found");
+			Error::SystemSetupCauseless { msg }
+		})
+	}
+
+	/// Host path and gather directive of an output of a step.
+	fn step_output(
+		&self,
+		step_name: &str,
+		label: &LocalLabel,
+	) -> Result<(PathBuf, &'a GatherDirective)> {
+		step_output(self.plot, &self.work_dir, step_name, label)
 	}
 
 	fn transform_oci_input(&self, port: &SandboxPort, reference: &str) -> Result<FormulaInput> {
@@ -216,10 +459,7 @@ impl<'a> PlotExecutor<'a> {
 
 		// Resolve digest if it was not specified.
 		if reference.digest().is_none() {
-			let pull_config = PullConfig {
-				cache: self.context.image_cache.clone(),
-				..PullConfig::default()
-			};
+			let pull_config = self.context.pull_config();
 			let digest = pull_image_manifest(&reference, &pull_config).map_err(|err| {
 				let msg = "failed to resolve OCI Reference".into();
 				let cause = Box::new(err);
@@ -232,6 +472,88 @@ impl<'a> PlotExecutor<'a> {
 	}
 }
 
+/// Looks up the ware referenced by a catalog input in the catalog of the context.
+///
+/// If the catalog knows mirrors for the ware, they are all added to the warehouses, in order.
+pub(crate) fn resolve_catalog_ref(
+	context: &Context,
+	catalog_ref: &CatalogRef,
+	warehouses: &mut IndexMap<WareID, Vec<WarehouseAddr>>,
+) -> Result<WareID> {
+	let Some(catalog) = &context.catalog else {
+		let msg = format!("input 'catalog:{catalog_ref}': no catalog configured");
+		return Err(Error::SystemSetupCauseless { msg });
+	};
+
+	let lookup = catalog
+		.lookup(catalog_ref)
+		.map_err(|cause| Error::SystemSetupError {
+			msg: format!("input 'catalog:{catalog_ref}': failed to access catalog"),
+			cause,
+		})?;
+	let Some((ware_id, mirrors)) = lookup else {
+		let msg = format!("input 'catalog:{catalog_ref}': item not found in catalog");
+		return Err(Error::SystemSetupCauseless { msg });
+	};
+
+	if !mirrors.is_empty() {
+		warehouses.insert(ware_id.clone(), mirrors);
+	}
+	Ok(ware_id)
+}
+
+/// Host path and gather directive of an output of a step in a plot.
+///
+/// Outputs of sub-plot steps are followed through the sub-plot, to the step producing them.
+fn step_output<'p>(
+	plot: &'p Plot,
+	work_dir: &Path,
+	step_name: &str,
+	label: &LocalLabel,
+) -> Result<(PathBuf, &'p GatherDirective)> {
+	let step_dir = work_dir.join(step_name);
+	match plot.steps.get(&StepName(step_name.to_owned())) {
+		Some(Step::Protoformula(step)) => {
+			let Some(gather) = step.outputs.get(label) else {
+				let msg = format!("step '{step_name}' has no output named '{label}'");
+				return Err(Error::SystemSetupCauseless { msg });
+			};
+			Ok((step_dir.join(OUTPUTS_DIR).join(&label.0), gather))
+		}
+		Some(Step::Plot(sub_plot)) => {
+			let Some(PlotOutput::Pipe(pipe)) = sub_plot.outputs.get(label) else {
+				let msg = format!("sub-plot '{step_name}' has no output named '{label}'");
+				return Err(Error::SystemSetupCauseless { msg });
+			};
+			if pipe.step_name.is_empty() {
+				let msg = format!(
+					"sub-plot '{step_name}': output '{label}' must be produced by a step of the sub-plot"
+				);
+				return Err(Error::SystemSetupCauseless { msg });
+			}
+			step_output(
+				sub_plot,
+				&step_dir.join(STEPS_DIR),
+				&pipe.step_name,
+				&pipe.label,
+			)
+		}
+		None => {
+			let msg = format!("step '{step_name}' not found");
+			Err(Error::SystemSetupCauseless { msg })
+		}
+	}
+}
+
+/// Ware ID of the output a pipe refers to, according to the records of the steps.
+fn pipe_ware_id(steps: &IndexMap<StepName, StepRunRecord>, pipe: &Pipe) -> Option<WareID> {
+	let results = match steps.get(&StepName(pipe.step_name.to_owned()))? {
+		StepRunRecord::Protoformula(record) => &record.results,
+		StepRunRecord::Plot(record) => &record.results,
+	};
+	results.get(&pipe.label).cloned()
+}
+
 #[derive(Debug)]
 pub(crate) struct PlotGraph<'a> {
 	nodes: IndexMap<&'a str, &'a Step>,
@@ -247,28 +569,28 @@ impl<'a> PlotGraph<'a> {
 
 		for (StepName(name), step) in &plot.steps {
 			nodes.insert(name.as_str(), step);
-			match step {
-				Step::Plot(_sub_plot) => todo!(),
-				Step::Protoformula(protoformula) => {
-					for (_, input) in &protoformula.inputs {
-						let PlotInput::Pipe(pipe) = input else {
-							continue;
-						};
-
-						if pipe.step_name.is_empty() {
-							continue;
-						}
+			let inputs: Box<dyn Iterator<Item = &PlotInput>> = match step {
+				// Pipes into the inputs of a sub-plot refer to the steps of this plot.
+				Step::Plot(sub_plot) => Box::new(sub_plot.inputs.values()),
+				Step::Protoformula(protoformula) => Box::new(protoformula.inputs.values()),
+			};
+			for input in inputs {
+				let PlotInput::Pipe(pipe) = input else {
+					continue;
+				};
 
-						parents
-							.entry(name.as_str())
-							.or_insert_with(IndexSet::new)
-							.insert(pipe.step_name.as_str());
-						children
-							.entry(pipe.step_name.as_str())
-							.or_insert_with(IndexSet::new)
-							.insert(name.as_str());
-					}
+				if pipe.step_name.is_empty() {
+					continue;
 				}
+
+				parents
+					.entry(name.as_str())
+					.or_insert_with(IndexSet::new)
+					.insert(pipe.step_name.as_str());
+				children
+					.entry(pipe.step_name.as_str())
+					.or_insert_with(IndexSet::new)
+					.insert(name.as_str());
 			}
 		}
 
@@ -282,6 +604,23 @@ impl<'a> PlotGraph<'a> {
 	pub(crate) fn validate(&self) -> Result<()> {
 		self.validate_dependencies_exist()?;
 		self.validate_no_cycles()?;
+		self.validate_sub_plots()?;
+		Ok(())
+	}
+
+	/// Validates the step graphs of all sub-plots, recursively.
+	pub(crate) fn validate_sub_plots(&self) -> Result<()> {
+		for (&name, &step) in &self.nodes {
+			let Step::Plot(sub_plot) = step else {
+				continue;
+			};
+			PlotGraph::new(sub_plot)
+				.validate()
+				.map_err(|err| Error::SystemSetupError {
+					msg: format!("invalid plot: sub-plot '{name}'"),
+					cause: Box::new(err),
+				})?;
+		}
 		Ok(())
 	}
 
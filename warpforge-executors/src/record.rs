@@ -0,0 +1,40 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use indexmap::IndexMap;
+use rand::RngCore;
+use warpforge_api::content::WareID;
+use warpforge_api::plot::LocalLabel;
+
+use crate::Output;
+
+/// Generates a random (version 4) UUID, identifying a single run.
+pub(crate) fn new_guid() -> String {
+	let mut bytes = [0u8; 16];
+	rand::thread_rng().fill_bytes(&mut bytes);
+	bytes[6] = (bytes[6] & 0x0f) | 0x40;
+	bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+	let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+	format!(
+		"{}-{}-{}-{}-{}",
+		&hex[0..8],
+		&hex[8..12],
+		&hex[12..16],
+		&hex[16..20],
+		&hex[20..32]
+	)
+}
+
+/// Current time in seconds since the unix epoch.
+pub(crate) fn unix_time() -> u64 {
+	(SystemTime::now().duration_since(UNIX_EPOCH))
+		.map(|duration| duration.as_secs())
+		.unwrap_or_default()
+}
+
+/// Identifies the packed outputs of a formula by their ware IDs.
+pub(crate) fn ware_ids(results: &[Output]) -> IndexMap<LocalLabel, WareID> {
+	(results.iter())
+		.map(|Output { name, ware_id }| (LocalLabel(name.to_owned()), ware_id.to_owned()))
+		.collect()
+}
@@ -0,0 +1,187 @@
+//! Container runtimes, which run the OCI bundles prepared by the [Executor](crate::execute::Executor).
+//!
+//! The OCI runtimes differ in the spec they accept and in their command line,
+//! so each runtime adjusts the generated spec and builds its own command.
+
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use serde_json::Value;
+use str_cat::os_str_cat;
+
+use crate::{Error, Result};
+
+/// Runtime running the containers of formulas.
+pub trait ContainerRuntime: Debug + Send + Sync {
+	/// Adjusts the generated OCI spec, before it is written into the bundle.
+	fn adjust_spec(&self, _spec: &mut Value) {}
+
+	/// Whether the runtime needs the image of the `/` input as root filesystem.
+	fn needs_image(&self) -> bool {
+		true
+	}
+
+	/// Command running the container of the bundle.
+	fn command(&self, bundle: &Bundle) -> Result<Command>;
+}
+
+/// OCI bundle prepared for a container run.
+pub struct Bundle<'a> {
+	/// Name of the container.
+	pub ident: &'a str,
+	/// Directory containing the `config.json` of the container.
+	pub dir: &'a Path,
+	/// File the runtime should write its logs to.
+	pub log_file: &'a Path,
+	/// Whether the container uses the network of the host.
+	///
+	/// Otherwise, the spec contains a network namespace, in which only loopback is available.
+	pub network: bool,
+}
+
+/// Chooses the runtime by the file name of the executable: `runsc`, or else a runc-style one.
+pub fn from_executable(path: impl AsRef<Path>) -> Arc<dyn ContainerRuntime> {
+	let path = path.as_ref().to_owned();
+	match path.file_name().and_then(|name| name.to_str()) {
+		Some("runsc") => Arc::new(Runsc { path }),
+		_ => Arc::new(Runc { path }),
+	}
+}
+
+/// runc, or any runtime accepting the same command line, like crun.
+///
+/// Both need a user namespace to run rootless.
+#[derive(Debug)]
+pub struct Runc {
+	pub path: PathBuf,
+}
+
+impl ContainerRuntime for Runc {
+	fn adjust_spec(&self, spec: &mut Value) {
+		crate::oci::add_user_namespace(spec);
+	}
+
+	fn command(&self, bundle: &Bundle) -> Result<Command> {
+		let mut cmd = Command::new(&self.path);
+		cmd.arg(os_str_cat!("--log=", bundle.log_file));
+		cmd.arg("--debug");
+		cmd.arg("run");
+		cmd.arg(os_str_cat!("--bundle=", bundle.dir));
+		cmd.arg(bundle.ident); // container name.
+		Ok(cmd)
+	}
+}
+
+/// gVisor's runtime.
+///
+/// The user namespace is left out of the spec: with it, the gofer process fails to launch.
+/// gVisor sets up its own user namespace when running rootless, and it ignores uid and gid
+/// mappings anyways.  The network is configured on the command line instead of in the spec:
+/// without host network, the sandbox only gets loopback.
+#[derive(Debug)]
+pub struct Runsc {
+	pub path: PathBuf,
+}
+
+impl ContainerRuntime for Runsc {
+	fn command(&self, bundle: &Bundle) -> Result<Command> {
+		let mut cmd = Command::new(&self.path);
+		cmd.arg(os_str_cat!("--log=", bundle.log_file));
+		cmd.arg("--debug");
+		cmd.arg("--rootless");
+		cmd.arg("--ignore-cgroups");
+		cmd.arg(match bundle.network {
+			true => "--network=host",
+			false => "--network=none",
+		});
+		cmd.arg("run");
+		cmd.arg(os_str_cat!("--bundle=", bundle.dir));
+		cmd.arg(bundle.ident);
+		Ok(cmd)
+	}
+}
+
+/// Runs the command of the container directly on the host, without any isolation.
+///
+/// Meant for fast tests of the machinery around containers: no image is needed.
+/// Bind mounts are emulated by replacing their destinations with their sources, where
+/// a process argument or environment variable starts with them.  Paths used in any other
+/// way (e.g. within scripts) are not replaced, and the working directory of the process
+/// is the bundle directory, unless it is a mount.  The network can't be isolated either.
+#[derive(Debug, Default)]
+pub struct Fake;
+
+impl ContainerRuntime for Fake {
+	fn needs_image(&self) -> bool {
+		false
+	}
+
+	fn command(&self, bundle: &Bundle) -> Result<Command> {
+		let file = File::open(bundle.dir.join("config.json")).map_err(|err| {
+			Error::SystemRuntimeError {
+				msg: "failed to read bundle config file".into(),
+				cause: Box::new(err),
+			}
+		})?;
+		let spec: Value = serde_json::from_reader(BufReader::new(file)).map_err(|err| {
+			Error::SystemRuntimeError {
+				msg: "failed to parse bundle config file".into(),
+				cause: Box::new(err),
+			}
+		})?;
+
+		let mut binds = Vec::new();
+		for mount in spec["mounts"].as_array().into_iter().flatten() {
+			let is_bind = (mount["options"].as_array().into_iter().flatten())
+				.any(|option| option == "rbind" || option == "bind");
+			if let (true, Some(destination), Some(source)) = (
+				is_bind,
+				mount["destination"].as_str(),
+				mount["source"].as_str(),
+			) {
+				binds.push((destination, source));
+			}
+		}
+		// Longest destinations first, so nested mounts win.
+		binds.sort_by_key(|(destination, _)| std::cmp::Reverse(destination.len()));
+		let host_path = |path: &str| -> OsString {
+			for (destination, source) in &binds {
+				if let Some(rest) = path.strip_prefix(destination) {
+					if rest.is_empty() || rest.starts_with('/') {
+						return os_str_cat!(source, rest);
+					}
+				}
+			}
+			path.into()
+		};
+
+		let args = (spec["process"]["args"].as_array().into_iter().flatten())
+			.filter_map(Value::as_str)
+			.collect::<Vec<_>>();
+		let Some((program, args)) = args.split_first() else {
+			let msg = "bundle config file contains no process arguments".into();
+			return Err(Error::SystemSetupCauseless { msg });
+		};
+
+		let mut cmd = Command::new(host_path(program));
+		cmd.args(args.iter().map(|arg| host_path(arg)));
+		cmd.env_clear();
+		for var in
+			(spec["process"]["env"].as_array().into_iter().flatten()).filter_map(Value::as_str)
+		{
+			if let Some((name, value)) = var.split_once('=') {
+				cmd.env(name, host_path(value));
+			}
+		}
+		match spec["process"]["cwd"].as_str().map(host_path) {
+			Some(cwd) if Path::new(&cwd).is_dir() && cwd != "/" => cmd.current_dir(cwd),
+			_ => cmd.current_dir(bundle.dir),
+		};
+		Ok(cmd)
+	}
+}
@@ -1,4 +1,9 @@
-use std::{env, path::PathBuf, thread};
+use std::{
+	env,
+	path::{Path, PathBuf},
+	sync::Once,
+	thread,
+};
 
 use tempfile::TempDir;
 use warpforge_api::formula::FormulaAndContext;
@@ -8,7 +13,10 @@ use crate::{
 };
 
 mod formula;
+mod fshash;
+mod memo;
 mod plot;
+mod ware;
 
 #[derive(PartialEq, Debug)]
 struct RunOutput {
@@ -27,7 +35,9 @@ fn default_context() -> Context {
 	let runtime = env::var("WARPFORGE_TEST_RUNTIME")
 		.unwrap_or("runc".into())
 		.into();
-	let image_cache = Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../.images"));
+	let image_cache = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../.images");
+	import_test_images(&image_cache);
+	let image_cache = Some(image_cache);
 	Context {
 		runtime,
 		image_cache,
@@ -35,6 +45,25 @@ fn default_context() -> Context {
 	}
 }
 
+/// Imports the images of the OCI image layout or `docker save` archive at
+/// `$WARPFORGE_TEST_IMAGES` into the image cache, so tests can run without network access.
+///
+/// Images named only by a tag are imported into `$WARPFORGE_TEST_IMAGES_REPOSITORY`.
+fn import_test_images(image_cache: &Path) {
+	static IMPORT: Once = Once::new();
+	IMPORT.call_once(|| {
+		let Ok(path) = env::var("WARPFORGE_TEST_IMAGES") else {
+			return;
+		};
+		let repository = env::var("WARPFORGE_TEST_IMAGES_REPOSITORY").ok();
+		let imported = oci_unpack::import_images(path, image_cache, repository.as_deref())
+			.expect("failed to import test images");
+		for name in imported.skipped {
+			eprintln!("skipped test image named only by tag '{name}', without repository");
+		}
+	});
+}
+
 fn run_formula_collect_output(
 	formula_and_context: FormulaAndContext,
 	context: &Context,
@@ -58,6 +87,7 @@ fn run_formula_collect_output(
 					println!("[container:{channel}] {line}");
 					outputs.push(RunOutputLine { channel, line });
 				}
+				EventBody::ResourceUsage(usage) => println!("[container-usage] {usage:?}"),
 				EventBody::ExitCode(code) => {
 					println!("[container-exit] {code:?}");
 					exit_code = code;
@@ -1,3 +1,5 @@
+mod fake_runtime;
+mod image_config;
 mod mount_overlayfs;
 mod output;
 mod simple_echo;
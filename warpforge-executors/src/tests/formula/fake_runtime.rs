@@ -0,0 +1,48 @@
+use std::{fs, sync::Arc};
+
+use serde_json::json;
+use tempfile::TempDir;
+use warpforge_api::formula::FormulaAndContext;
+
+use crate::{
+	context::Context,
+	formula::{check_exit_code, run_formula},
+	runtime,
+};
+
+const IMAGE: &str = "oci:docker.io/library/busybox@sha256:22f27168517de1f58dae0ad51eacf1527e7e7ccc47512d3946f56bdbe913f564";
+
+#[test]
+fn failed_run_is_recorded() {
+	let formula_and_context: FormulaAndContext = serde_json::from_value(json!({
+		"formula": {
+			"formula.v1": {
+				"inputs": { "/": IMAGE, "$OUT": "literal:/out" },
+				"action": {
+					"exec": {
+						"command": ["/bin/sh", "-c", "echo partial > \"$OUT/file.txt\"; exit 3"]
+					}
+				},
+				"outputs": {
+					"out": { "from": "/out" }
+				}
+			}
+		},
+		"context": { "context.v1": { "warehouses": {} } }
+	}))
+	.unwrap();
+
+	let temp_dir = TempDir::new().unwrap();
+	let context = Context {
+		runtime_backend: Some(Arc::new(runtime::Fake)),
+		output_path: Some(temp_dir.path().join("outputs")),
+		..Default::default()
+	};
+	let record = run_formula(formula_and_context, &context).unwrap();
+
+	assert_eq!(record.exit_code, 3);
+	assert!(check_exit_code(&record).is_err());
+	// Outputs of failed runs are not packed.
+	assert!(record.results.is_empty());
+	assert!(fs::read_dir(temp_dir.path().join("outputs")).is_err());
+}
@@ -0,0 +1,55 @@
+use indexmap::IndexMap;
+use oci_spec::image::ImageConfiguration;
+use serde_json::json;
+
+use crate::{formula::apply_image_config, Error};
+
+fn image_config(config: serde_json::Value) -> ImageConfiguration {
+	serde_json::from_value(json!({
+		"architecture": "amd64",
+		"os": "linux",
+		"config": config,
+		"rootfs": { "type": "layers", "diff_ids": [] },
+		"history": [],
+	}))
+	.unwrap()
+}
+
+#[test]
+fn image_config_is_applied() {
+	let config = image_config(json!({
+		"Env": ["PATH=/usr/bin", "HOME=/root"],
+		"WorkingDir": "/work",
+		"User": "0:0",
+	}));
+	let mut environment = IndexMap::from([("PATH".into(), "/bin".into())]);
+
+	let working_dir = apply_image_config(&config, &mut environment).unwrap();
+	assert_eq!(working_dir.as_deref(), Some("/work"));
+	let expected = [("PATH", "/bin"), ("HOME", "/root")];
+	let expected: IndexMap<_, _> = (expected.iter())
+		.map(|(var, value)| (var.to_string(), value.to_string()))
+		.collect();
+	assert_eq!(environment, expected);
+}
+
+#[test]
+fn root_users_are_accepted() {
+	for user in ["", "root", "0", "root:root", "0:root"] {
+		let config = image_config(json!({ "User": user }));
+		let result = apply_image_config(&config, &mut IndexMap::new());
+		assert!(result.is_ok(), "{user}");
+	}
+}
+
+#[test]
+fn other_users_are_rejected() {
+	for user in ["1000", "nobody", "0:100", "root:users"] {
+		let config = image_config(json!({ "User": user }));
+		let result = apply_image_config(&config, &mut IndexMap::new());
+		assert!(
+			matches!(&result, Err(Error::SystemSetupCauseless { msg }) if msg.contains(user)),
+			"{user}: {result:?}"
+		);
+	}
+}
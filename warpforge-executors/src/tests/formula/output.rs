@@ -8,7 +8,7 @@ use warpforge_api::formula::FormulaAndContext;
 
 use crate::{
 	tests::{default_context, run_formula_collect_output},
-	Digest, Output,
+	Output,
 };
 
 #[test]
@@ -51,16 +51,25 @@ fn tgz_output() {
 	let result = run_formula_collect_output(formula_and_context, &context).unwrap();
 
 	assert_eq!(result.exit_code, Some(0));
-	assert_eq!(result.outputs, vec![Output {
-		name: "output.tgz".into(),
-		digest: Digest::Sha384("64518bf7b504749270619507457adc3c86d46ccbb86c8b06508591aed483c1a5db728086dba261ff05f453dfd2c315d5".into())
-	}]);
+	assert_eq!(
+		result.outputs,
+		vec![Output {
+			name: "output.tgz".into(),
+			ware_id: "tar:6wjr581cmkZRbuhqgQEkYPsKb99Kku4JErJiXYcmiYtQGipjFc6XRzyz7zbuPyABKv"
+				.parse()
+				.unwrap(),
+		}]
+	);
 
 	// Unpack output.tar and check contents.
 	let reader = File::open(temp_dir.path().join("output.tgz")).unwrap();
 	let reader = GzDecoder::new(reader);
 	let mut archive = Archive::new(reader);
 	let mut entries = archive.entries().unwrap();
+	let root = entries.next().unwrap().unwrap();
+	assert_eq!(root.path().unwrap(), PathBuf::from("."));
+	assert_eq!(root.header().uid().unwrap(), 1000);
+	assert_eq!(root.header().mtime().unwrap(), 1262304000);
 	let mut entry = entries.next().unwrap().unwrap();
 
 	assert_eq!(entry.path().unwrap(), PathBuf::from("test.txt"));
@@ -117,14 +126,21 @@ fn multiple_tgz_outputs() {
 	let result = run_formula_collect_output(formula_and_context, &context).unwrap();
 
 	assert_eq!(result.exit_code, Some(0));
-	assert_eq!(result.outputs, vec![
-		Output {
-			name: "output_1.tgz".into(),
-			digest: Digest::Sha384("dbdb8a42228f80b47f18dceab1994e59820ef26fd5b74db9e7298b77907ba25c00f6920d893670d0bc366c2ed4052047".into())
-		},
-		Output {
-			name: "output_2.tgz".into(),
-			digest: Digest::Sha384("885741449883286ea479ac9e71a7cab2b8f75cf25960f88168c56f3645f39c206f59932e20735f91e39ccb892f62b529".into())
-		},
-	]);
+	assert_eq!(
+		result.outputs,
+		vec![
+			Output {
+				name: "output_1.tgz".into(),
+				ware_id: "tar:3HWLjmZHshk3XjZgrxtYkgXxgKTvHXZhUJoveWbrLzAV1AnxM1b7D9ZUeHmHAhyEJd"
+					.parse()
+					.unwrap(),
+			},
+			Output {
+				name: "output_2.tgz".into(),
+				ware_id: "tar:5LdYg6RTWdHZzUYUpKAKPVayp8mX32ZBo7gpk2xBoWBPLLQVgDsC74gMpHBXYzXWd6"
+					.parse()
+					.unwrap(),
+			},
+		]
+	);
 }
@@ -83,3 +83,44 @@ fn formula_script() {
 		}],
 	);
 }
+
+#[test]
+fn formula_env_overrides_image_env() {
+	let formula_and_context: FormulaAndContext = serde_json::from_value(json!({
+		"formula": {
+			"formula.v1": {
+				"inputs": {
+					"/": "oci:docker.io/library/busybox@sha256:22f27168517de1f58dae0ad51eacf1527e7e7ccc47512d3946f56bdbe913f564",
+					"$PATH": "literal:/bin",
+				},
+				"action": {
+					"exec": {
+						"command": [
+							"/bin/sh",
+							"-c",
+							"echo $PATH",
+						]
+					}
+				},
+				"outputs": {},
+			}
+		},
+		"context": {
+			"context.v1": {
+				"warehouses": {}
+			}
+		}
+	}))
+	.expect("failed to parse formula json");
+
+	let result = run_formula_collect_output(formula_and_context, &default_context()).unwrap();
+
+	assert_eq!(result.exit_code, Some(0));
+	assert_eq!(
+		result.console,
+		vec![RunOutputLine {
+			channel: 1,
+			line: "/bin".into(),
+		}],
+	);
+}
@@ -0,0 +1,215 @@
+use std::{
+	fs::{self, File},
+	os::unix::fs::{symlink, PermissionsExt},
+	time::{Duration, SystemTime},
+};
+
+use tempfile::TempDir;
+use warpforge_api::formula::{FilterMap, IdFilter, MtimeFilter, SetidFilter, StickyFilter};
+
+use crate::{
+	fshash::{base58, encode_sha384, hash_tree, walk, Filters},
+	pack::tar_dir,
+	ware::unpack_tar,
+};
+
+fn create_tree(temp_dir: &TempDir) -> std::path::PathBuf {
+	let root = temp_dir.path().join("tree");
+	fs::create_dir_all(root.join("bin")).unwrap();
+	fs::write(root.join("hello.txt"), "hello, ware!\n").unwrap();
+	fs::write(root.join("bin").join("tool"), "#!/bin/sh\n").unwrap();
+	fs::set_permissions(
+		root.join("bin").join("tool"),
+		fs::Permissions::from_mode(0o755),
+	)
+	.unwrap();
+	symlink("bin/tool", root.join("tool")).unwrap();
+	root
+}
+
+#[test]
+fn base58_encoding() {
+	assert_eq!(base58(b""), "");
+	assert_eq!(base58(b"Hello World!"), "2NEpo7TZRRrLZSi2U");
+	assert_eq!(base58(&[0, 0, 1]), "112");
+}
+
+#[test]
+fn sha384_encoded_as_base58() {
+	// A digest of 48 bytes takes at most 66 base58 characters.
+	assert_eq!(encode_sha384(&[0xff; 48]).len(), 66);
+}
+
+/// Decodes base58, the inverse of [base58].
+fn base58_decode(encoded: &str) -> Vec<u8> {
+	const ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+	// Bytes of the number, least significant first.
+	let mut bytes: Vec<u8> = Vec::new();
+	for c in encoded.chars() {
+		let mut carry = ALPHABET.find(c).unwrap() as u32;
+		for byte in bytes.iter_mut() {
+			carry += (*byte as u32) * 58;
+			*byte = carry as u8;
+			carry >>= 8;
+		}
+		while carry > 0 {
+			bytes.push(carry as u8);
+			carry >>= 8;
+		}
+	}
+	let zeros = encoded.chars().take_while(|&c| c == '1').count();
+	bytes.extend(std::iter::repeat_n(0, zeros));
+	bytes.reverse();
+	bytes
+}
+
+#[test]
+fn catalog_ware_ids_are_plain_sha384() {
+	// Ware IDs of the warpsys catalog.
+	for hash in [
+		"4z9DCTxoKkStqXQRwtf9nimpfQQ36dbndDsAPCQgECfbXt3edanUrsVKCjE9TkX2v9",
+		"4z9DCTxoKkStqXQRwtf9nimpfQQ36dncJsTygqkfPLJJ7ctACnEuToBGuLxrSWNtbH",
+	] {
+		let digest = base58_decode(hash);
+		// Just the digest, without the multihash prefix of sha2-384 (0x20, 0x30).
+		assert_eq!(digest.len(), 48, "{hash}");
+		assert_ne!(digest[..2], [0x20, 0x30], "{hash}");
+		assert_eq!(encode_sha384(&digest), hash);
+	}
+}
+
+#[test]
+fn hash_ignores_mtime() {
+	let temp_dir = TempDir::new().unwrap();
+	let root = create_tree(&temp_dir);
+	let hash = hash_tree(&root, &Filters::default()).unwrap();
+
+	let file = File::options()
+		.write(true)
+		.open(root.join("hello.txt"))
+		.unwrap();
+	file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(42))
+		.unwrap();
+	assert_eq!(hash_tree(&root, &Filters::default()).unwrap(), hash);
+}
+
+#[test]
+fn hash_depends_on_mode_and_content() {
+	let temp_dir = TempDir::new().unwrap();
+	let root = create_tree(&temp_dir);
+	let hash = hash_tree(&root, &Filters::default()).unwrap();
+
+	fs::set_permissions(root.join("hello.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+	let hash_mode = hash_tree(&root, &Filters::default()).unwrap();
+	assert_ne!(hash_mode, hash);
+
+	fs::write(root.join("hello.txt"), "hello, other ware!\n").unwrap();
+	assert_ne!(hash_tree(&root, &Filters::default()).unwrap(), hash_mode);
+}
+
+#[test]
+fn unpacked_tar_has_same_hash() {
+	let temp_dir = TempDir::new().unwrap();
+	let root = create_tree(&temp_dir);
+	fs::set_permissions(&root, fs::Permissions::from_mode(0o700)).unwrap();
+
+	let mut packed = Vec::new();
+	tar_dir(&root, &mut packed, &Filters::default()).unwrap();
+	let unpacked = temp_dir.path().join("unpacked");
+	unpack_tar(&packed[..], &unpacked).unwrap();
+
+	let filters = Filters::default();
+	assert_eq!(
+		hash_tree(&unpacked, &filters).unwrap(),
+		hash_tree(&root, &filters).unwrap()
+	);
+}
+
+#[test]
+fn filters_default_when_unset() {
+	let filters = Filters::new(Some(&FilterMap {
+		uid: Some(IdFilter::Id(0)),
+		mtime: Some(MtimeFilter::Keep),
+		..Default::default()
+	}));
+	assert_eq!(
+		filters,
+		Filters {
+			uid: Some(0),
+			mtime: None,
+			..Filters::default()
+		}
+	);
+	assert_eq!(Filters::new(None), Filters::default());
+}
+
+#[test]
+fn mtime_kept_by_filter() {
+	let temp_dir = TempDir::new().unwrap();
+	let root = create_tree(&temp_dir);
+	let filters = Filters {
+		mtime: None,
+		..Default::default()
+	};
+	let hash = hash_tree(&root, &filters).unwrap();
+
+	let file = File::options()
+		.write(true)
+		.open(root.join("hello.txt"))
+		.unwrap();
+	file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(42))
+		.unwrap();
+	assert_ne!(hash_tree(&root, &filters).unwrap(), hash);
+
+	// Kept metadata survives packing and unpacking too.
+	let mut packed = Vec::new();
+	tar_dir(&root, &mut packed, &filters).unwrap();
+	let unpacked = temp_dir.path().join("unpacked");
+	unpack_tar(&packed[..], &unpacked).unwrap();
+	assert_eq!(
+		hash_tree(&unpacked, &filters).unwrap(),
+		hash_tree(&root, &filters).unwrap()
+	);
+}
+
+#[test]
+fn setid_filter() {
+	let temp_dir = TempDir::new().unwrap();
+	let root = create_tree(&temp_dir);
+	let tool = root.join("bin").join("tool");
+	let hash = hash_tree(&root, &Filters::default()).unwrap();
+	fs::set_permissions(&tool, fs::Permissions::from_mode(0o4755)).unwrap();
+
+	assert!(hash_tree(&root, &Filters::default()).is_err());
+
+	let strip = Filters {
+		setid: SetidFilter::Strip,
+		..Default::default()
+	};
+	assert_eq!(hash_tree(&root, &strip).unwrap(), hash);
+
+	let keep = Filters {
+		setid: SetidFilter::Keep,
+		..Default::default()
+	};
+	let entries = walk(&root, &keep).unwrap();
+	let entry = entries
+		.iter()
+		.find(|entry| entry.path.ends_with("bin/tool"));
+	assert_eq!(entry.unwrap().mode, 0o4755);
+}
+
+#[test]
+fn sticky_filter() {
+	let temp_dir = TempDir::new().unwrap();
+	let root = create_tree(&temp_dir);
+	let hash = hash_tree(&root, &Filters::default()).unwrap();
+	fs::set_permissions(root.join("bin"), fs::Permissions::from_mode(0o1755)).unwrap();
+
+	assert_ne!(hash_tree(&root, &Filters::default()).unwrap(), hash);
+	let strip = Filters {
+		sticky: StickyFilter::Strip,
+		..Default::default()
+	};
+	assert_eq!(hash_tree(&root, &strip).unwrap(), hash);
+}
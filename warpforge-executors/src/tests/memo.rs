@@ -0,0 +1,317 @@
+use std::{fs, path::PathBuf, sync::Arc};
+
+use indexmap::IndexMap;
+use serde_json::json;
+use tempfile::TempDir;
+use warpforge_api::formula::{FormulaAndContext, FormulaCapsule};
+use warpforge_api::plot::LocalLabel;
+use warpforge_api::run_record::RunRecord;
+
+use crate::{
+	context::Context,
+	formula::run_formula,
+	fshash::Filters,
+	memo::{formula_id, is_hermetic, memo_key, substitute_mounts, MemoStore},
+	pack::{hash_dir, tgz_dir_to_file, OutputPacktype, OutputTargets},
+	runtime,
+	ware::warehouse_subpath,
+};
+
+const IMAGE: &str = "oci:docker.io/library/busybox@sha256:22f27168517de1f58dae0ad51eacf1527e7e7ccc47512d3946f56bdbe913f564";
+
+fn formula(inputs: serde_json::Value) -> FormulaCapsule {
+	serde_json::from_value(json!({
+		"formula.v1": {
+			"inputs": inputs,
+			"action": {
+				"exec": {
+					"command": ["/bin/cp", "-R", "/in", "/out"]
+				}
+			},
+			"outputs": {
+				"out": { "from": "/out" }
+			}
+		}
+	}))
+	.unwrap()
+}
+
+fn record(formula_id: &str, digest: &str) -> RunRecord {
+	RunRecord {
+		guid: "4c3cee4e-bd4d-4bd7-8b8c-3a7a2c0e7f3b".into(),
+		formula_id: formula_id.into(),
+		start_time: 1700000000,
+		end_time: 1700000042,
+		exit_code: 0,
+		network: false,
+		resource_usage: None,
+		results: IndexMap::from([(
+			LocalLabel("out".into()),
+			format!("tar:{digest}").parse().unwrap(),
+		)]),
+	}
+}
+
+#[test]
+fn formula_id_ignores_key_order() {
+	let formula_a = formula(json!({ "/": IMAGE, "$A": "literal:a", "$B": "literal:b" }));
+	let formula_b = formula(json!({ "$B": "literal:b", "/": IMAGE, "$A": "literal:a" }));
+	let formula_c = formula(json!({ "/": IMAGE, "$A": "literal:a", "$B": "literal:c" }));
+
+	assert_eq!(formula_id(&formula_a), formula_id(&formula_b));
+	assert_ne!(formula_id(&formula_a), formula_id(&formula_c));
+	assert_eq!(formula_id(&formula_a).len(), 96);
+}
+
+#[test]
+fn mounts_are_not_hermetic() {
+	let mounted = formula(json!({ "/": IMAGE, "/in": "mount:ro:/some/output" }));
+	assert!(!is_hermetic(&mounted));
+
+	let digests = IndexMap::from([(PathBuf::from("/some/output"), "abcdef".to_owned())]);
+	let substituted = substitute_mounts(&mounted, &digests);
+	assert!(is_hermetic(&substituted));
+	assert_eq!(
+		formula_id(&substituted),
+		formula_id(&formula(json!({ "/": IMAGE, "/in": "ware:tar:abcdef" })))
+	);
+
+	let unknown = formula(json!({ "/": IMAGE, "/in": "mount:ro:/other" }));
+	assert!(!is_hermetic(&substitute_mounts(&unknown, &digests)));
+}
+
+#[test]
+fn memo_key_includes_spec_patches() {
+	let id = formula_id(&formula(json!({ "/": IMAGE })));
+	let no_patch = json_patch::Patch::default();
+	assert_eq!(memo_key(&id, &no_patch, &[], false), id);
+
+	let operation = json!({ "op": "replace", "path": "/hostname", "value": "other" });
+	let patch: json_patch::Patch = serde_json::from_value(json!([operation])).unwrap();
+	let context_key = memo_key(&id, &patch, &[], false);
+	let formula_key = memo_key(&id, &no_patch, &[operation], false);
+	assert_ne!(context_key, id);
+	assert_ne!(formula_key, id);
+	assert_ne!(context_key, formula_key);
+}
+
+#[test]
+fn memo_key_includes_ignore_image_config() {
+	let id = formula_id(&formula(json!({ "/": IMAGE })));
+	let no_patch = json_patch::Patch::default();
+	let ignoring_key = memo_key(&id, &no_patch, &[], true);
+	assert_ne!(ignoring_key, id);
+
+	let operations = [json!({ "op": "replace", "path": "/hostname", "value": "other" })];
+	assert_ne!(
+		memo_key(&id, &no_patch, &operations, true),
+		memo_key(&id, &no_patch, &operations, false)
+	);
+}
+
+#[test]
+fn network_is_not_hermetic() {
+	let networked: FormulaCapsule = serde_json::from_value(json!({
+		"formula.v1": {
+			"inputs": { "/": IMAGE },
+			"action": {
+				"exec": {
+					"command": ["/bin/wget", "https://example.com"],
+					"network": true
+				}
+			},
+			"outputs": {}
+		}
+	}))
+	.unwrap();
+	assert!(!is_hermetic(&networked));
+	assert!(is_hermetic(&formula(json!({ "/": IMAGE }))));
+}
+
+#[test]
+fn store_and_restore() {
+	let temp_dir = TempDir::new().unwrap();
+	let store = MemoStore::new(temp_dir.path().join("memos"));
+
+	let targets = OutputTargets {
+		export_dir: Some(temp_dir.path().join("first")),
+		..Default::default()
+	};
+	let output = temp_dir.path().join("first").join("out");
+	fs::create_dir_all(&output).unwrap();
+	fs::write(output.join("file.txt"), "memoized\n").unwrap();
+	let digest = hash_dir(&output, &Filters::default()).unwrap();
+
+	let outputs = [("out".to_owned(), OutputPacktype::None, Filters::default())];
+	let record = record("id", &digest);
+	assert!(store.restore("id", &outputs, &targets).unwrap().is_none());
+	store.store("id", &outputs, &record, &targets).unwrap();
+
+	let restore_targets = OutputTargets {
+		export_dir: Some(temp_dir.path().join("second")),
+		..Default::default()
+	};
+	let restored = store.restore("id", &outputs, &restore_targets).unwrap();
+	assert_eq!(restored, Some(record));
+	assert_eq!(
+		fs::read_to_string(temp_dir.path().join("second/out/file.txt")).unwrap(),
+		"memoized\n"
+	);
+}
+
+#[test]
+fn store_and_restore_tgz() {
+	let temp_dir = TempDir::new().unwrap();
+	let store = MemoStore::new(temp_dir.path().join("memos"));
+
+	let targets = OutputTargets {
+		export_dir: Some(temp_dir.path().join("first")),
+		..Default::default()
+	};
+	let source = temp_dir.path().join("source");
+	fs::create_dir_all(&source).unwrap();
+	fs::write(source.join("file.txt"), "memoized\n").unwrap();
+	fs::create_dir_all(temp_dir.path().join("first")).unwrap();
+	let packed = temp_dir.path().join("first").join("out");
+	let digest = tgz_dir_to_file(&source, &packed, &Filters::default()).unwrap();
+
+	let outputs = [(
+		"out".to_owned(),
+		OutputPacktype::TarGzip,
+		Filters::default(),
+	)];
+	let record = record("id", &digest);
+	store.store("id", &outputs, &record, &targets).unwrap();
+
+	let restore_targets = OutputTargets {
+		export_dir: Some(temp_dir.path().join("second")),
+		..Default::default()
+	};
+	let restored = store.restore("id", &outputs, &restore_targets).unwrap();
+	assert_eq!(restored, Some(record));
+	assert_eq!(
+		fs::read(temp_dir.path().join("second/out")).unwrap(),
+		fs::read(&packed).unwrap()
+	);
+}
+
+#[test]
+fn damaged_memo_restores_nothing() {
+	let temp_dir = TempDir::new().unwrap();
+	let store = MemoStore::new(temp_dir.path().join("memos"));
+
+	let targets = OutputTargets {
+		export_dir: Some(temp_dir.path().join("first")),
+		..Default::default()
+	};
+	let mut record = record("id", "");
+	record.results.clear();
+	let mut outputs = Vec::new();
+	for name in ["a", "b"] {
+		let output = temp_dir.path().join("first").join(name);
+		fs::create_dir_all(&output).unwrap();
+		fs::write(output.join("file.txt"), format!("{name}\n")).unwrap();
+		let digest = hash_dir(&output, &Filters::default()).unwrap();
+		record.results.insert(
+			LocalLabel(name.into()),
+			format!("tar:{digest}").parse().unwrap(),
+		);
+		outputs.push((name.to_owned(), OutputPacktype::None, Filters::default()));
+	}
+	store.store("id", &outputs, &record, &targets).unwrap();
+	fs::write(temp_dir.path().join("memos/id/outputs/b"), "damaged").unwrap();
+
+	let export_dir = temp_dir.path().join("second");
+	let restore_targets = OutputTargets {
+		export_dir: Some(export_dir.clone()),
+		..Default::default()
+	};
+	assert!(store
+		.restore("id", &outputs, &restore_targets)
+		.unwrap()
+		.is_none());
+	assert_eq!(fs::read_dir(&export_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn run_formula_memo_hit() {
+	let temp_dir = TempDir::new().unwrap();
+	let memo_path = temp_dir.path().join("memos");
+	let formula = formula(json!({ "/": IMAGE, "$MSG": "literal:hello" }));
+
+	// Memoize outputs for the formula, without ever running it.
+	let targets = OutputTargets {
+		export_dir: Some(temp_dir.path().join("source")),
+		..Default::default()
+	};
+	let output = temp_dir.path().join("source").join("out");
+	fs::create_dir_all(&output).unwrap();
+	fs::write(output.join("file.txt"), "hello\n").unwrap();
+	let record = record(
+		&formula_id(&formula),
+		&hash_dir(&output, &Filters::default()).unwrap(),
+	);
+	let outputs = [("out".to_owned(), OutputPacktype::None, Filters::default())];
+	(MemoStore::new(&memo_path))
+		.store(&formula_id(&formula), &outputs, &record, &targets)
+		.unwrap();
+
+	let formula_and_context: FormulaAndContext = serde_json::from_value(json!({
+		"formula": formula,
+		"context": { "context.v1": { "warehouses": {} } }
+	}))
+	.unwrap();
+	let context = Context {
+		// Running a container would fail: the outputs have to come from the memo.
+		runtime: temp_dir.path().join("no-runtime"),
+		output_path: Some(temp_dir.path().join("outputs")),
+		warehouse: Some(temp_dir.path().join("warehouse")),
+		memo_path: Some(memo_path),
+		..Default::default()
+	};
+
+	assert_eq!(run_formula(formula_and_context, &context).unwrap(), record);
+	assert_eq!(
+		fs::read_to_string(temp_dir.path().join("outputs/out/file.txt")).unwrap(),
+		"hello\n"
+	);
+	let ware_id = &record.results[&LocalLabel("out".into())];
+	let ware_path = (temp_dir.path().join("warehouse")).join(warehouse_subpath(&ware_id.hash));
+	assert!(ware_path.is_file());
+}
+
+#[test]
+fn fake_runtime_is_not_memoized() {
+	let temp_dir = TempDir::new().unwrap();
+	let memo_path = temp_dir.path().join("memos");
+	let formula_and_context: FormulaAndContext = serde_json::from_value(json!({
+		"formula": {
+			"formula.v1": {
+				"inputs": { "/": IMAGE, "$OUT": "literal:/out" },
+				"action": {
+					"exec": {
+						"command": ["/bin/sh", "-c", "echo hello > \"$OUT/file.txt\""]
+					}
+				},
+				"outputs": {
+					"out": { "from": "/out" }
+				}
+			}
+		},
+		"context": { "context.v1": { "warehouses": {} } }
+	}))
+	.unwrap();
+	let context = Context {
+		runtime_backend: Some(Arc::new(runtime::Fake)),
+		output_path: Some(temp_dir.path().join("outputs")),
+		memo_path: Some(memo_path.clone()),
+		..Default::default()
+	};
+
+	run_formula(formula_and_context, &context).unwrap();
+	assert_eq!(
+		fs::read_to_string(temp_dir.path().join("outputs/out/file.txt")).unwrap(),
+		"hello\n"
+	);
+	assert!(!memo_path.exists() || fs::read_dir(&memo_path).unwrap().count() == 0);
+}
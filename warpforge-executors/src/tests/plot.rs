@@ -1,2 +1,6 @@
+mod catalog;
+mod fake_runtime;
 mod invalid_step_graph;
+mod parallel_steps;
 mod simple_steps;
+mod sub_plot;
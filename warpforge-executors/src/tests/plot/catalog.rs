@@ -0,0 +1,68 @@
+use std::{fs, path::Path, sync::Arc};
+
+use indexmap::IndexMap;
+use tempfile::TempDir;
+use warpforge_api::formula::WarehouseAddr;
+use warpforge_dab::catalog::FsHandle;
+
+use crate::{context::Context, plot::resolve_catalog_ref};
+
+const HASH: &str = "4z9DCTxoKkStqXQRwtf9nimpfQQ36dncJsTygqkfPLJJ7ctACnEuToBGuLxrSWNtbH";
+
+fn catalog_context(root: &Path) -> Context {
+	let module_dir = root.join("warpsys.org/busybox");
+	fs::create_dir_all(module_dir.join("_releases")).unwrap();
+	fs::write(
+		module_dir.join("_releases").join("v1.35.0.json"),
+		format!(
+			r#"{{ "releaseName": "v1.35.0", "items": {{ "amd64": "tar:{HASH}" }}, "metadata": {{}} }}"#
+		),
+	)
+	.unwrap();
+	fs::write(
+		module_dir.join("_mirrors.json"),
+		r#"{ "catalogmirrors.v1": { "byModule": { "warpsys.org/busybox": { "tar": ["ca+file:///warehouse", "ca+https://mirror.example"] } } } }"#,
+	)
+	.unwrap();
+
+	Context {
+		catalog: Some(Arc::new(FsHandle::new(root))),
+		..Default::default()
+	}
+}
+
+#[test]
+fn resolve_catalog_input() {
+	let temp_dir = TempDir::new().unwrap();
+	let context = catalog_context(temp_dir.path());
+
+	let mut warehouses = IndexMap::new();
+	let catalog_ref = "warpsys.org/busybox:v1.35.0:amd64".parse().unwrap();
+	let ware_id = resolve_catalog_ref(&context, &catalog_ref, &mut warehouses).unwrap();
+
+	assert_eq!(ware_id.to_string(), format!("tar:{HASH}"));
+	assert_eq!(
+		warehouses,
+		IndexMap::from([(
+			ware_id,
+			vec![
+				WarehouseAddr("ca+file:///warehouse".into()),
+				WarehouseAddr("ca+https://mirror.example".into()),
+			]
+		)])
+	);
+}
+
+#[test]
+fn resolve_catalog_input_missing() {
+	let temp_dir = TempDir::new().unwrap();
+	let context = catalog_context(temp_dir.path());
+
+	let mut warehouses = IndexMap::new();
+	let catalog_ref = "warpsys.org/busybox:v1.35.0:arm64".parse().unwrap();
+	assert!(resolve_catalog_ref(&context, &catalog_ref, &mut warehouses).is_err());
+
+	let catalog_ref = "warpsys.org/busybox:v1.35.0:amd64".parse().unwrap();
+	assert!(resolve_catalog_ref(&Context::default(), &catalog_ref, &mut warehouses).is_err());
+	assert!(warehouses.is_empty());
+}
@@ -0,0 +1,230 @@
+use std::{fs, path::Path, sync::Arc};
+
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use warpforge_api::formula::WarehouseAddr;
+use warpforge_api::plot::{LocalLabel, PlotCapsule};
+
+use crate::{
+	context::Context,
+	plot::run_plot,
+	runtime,
+	ware::{warehouse_subpath, WareStore},
+};
+
+const IMAGE: &str = "oci:docker.io/library/busybox@sha256:22f27168517de1f58dae0ad51eacf1527e7e7ccc47512d3946f56bdbe913f564";
+
+fn shell_step(inputs: Value, command: &str) -> Value {
+	json!({
+		"protoformula": {
+			"inputs": inputs,
+			"action": {
+				"exec": {
+					"command": ["/bin/sh", "-c", command]
+				}
+			},
+			"outputs": {
+				"out": { "from": "/out" }
+			}
+		}
+	})
+}
+
+#[test]
+fn plot_with_fake_runtime() {
+	let plot: PlotCapsule = serde_json::from_value(json!({
+		"plot.v1": {
+			"inputs": {
+				"image": IMAGE
+			},
+			"steps": {
+				"a": shell_step(
+					json!({ "/": "pipe::image", "$OUT": "literal:/out" }),
+					"echo a > \"$OUT/a\"",
+				),
+				"b": shell_step(
+					json!({ "/": "pipe::image", "$OUT": "literal:/out" }),
+					"echo b > \"$OUT/b\"",
+				),
+				"gather": shell_step(
+					json!({
+						"/": "pipe::image",
+						"/a": "pipe:a:out",
+						"/b": "pipe:b:out",
+						"$A": "literal:/a/a",
+						"$B": "literal:/b/b",
+						"$OUT": "literal:/out",
+					}),
+					"cat \"$A\" \"$B\" > \"$OUT/all\"",
+				),
+			},
+			"outputs": {
+				"all": "pipe:gather:out"
+			}
+		}
+	}))
+	.unwrap();
+
+	let temp_dir = TempDir::new().unwrap();
+	let context = Context {
+		runtime_backend: Some(Arc::new(runtime::Fake)),
+		output_path: Some(temp_dir.path().to_owned()),
+		..Default::default()
+	};
+
+	let record = run_plot(plot, &context).unwrap();
+
+	assert_eq!(record.steps.len(), 3);
+	let all = fs::read_to_string(temp_dir.path().join("all").join("all")).unwrap();
+	assert_eq!(all, "a\nb\n");
+}
+
+#[test]
+fn plot_outputs_stored_in_warehouse() {
+	let plot: PlotCapsule = serde_json::from_value(json!({
+		"plot.v1": {
+			"inputs": {
+				"image": IMAGE
+			},
+			"steps": {
+				"a": shell_step(
+					json!({ "/": "pipe::image", "$OUT": "literal:/out" }),
+					"echo a > \"$OUT/a\"",
+				),
+			},
+			"outputs": {
+				"a": "pipe:a:out"
+			}
+		}
+	}))
+	.unwrap();
+
+	let temp_dir = TempDir::new().unwrap();
+	let warehouse = temp_dir.path().join("warehouse");
+	let context = Context {
+		runtime_backend: Some(Arc::new(runtime::Fake)),
+		warehouse: Some(warehouse.clone()),
+		..Default::default()
+	};
+
+	let record = run_plot(plot, &context).unwrap();
+
+	let ware_id = &record.results[&LocalLabel("a".into())];
+	assert_eq!(ware_id.packtype.0, "tar");
+	assert!(warehouse.join(warehouse_subpath(&ware_id.hash)).is_file());
+	// Without output path, outputs are not exported into the working directory.
+	assert!(!Path::new("a").exists());
+
+	// The output can be used as ware input right away.
+	let warehouse = WarehouseAddr(format!("ca+file://{}", warehouse.display()));
+	let store = WareStore::new(temp_dir.path().join("store"));
+	let unpacked = store.obtain(ware_id, &[&warehouse]).unwrap();
+	assert_eq!(fs::read_to_string(unpacked.join("a")).unwrap(), "a\n");
+}
+
+#[test]
+fn sub_plot_steps_share_parallelism() {
+	// Every step registers itself in a shared directory while it runs,
+	// and records how many steps were running at the same time.
+	let temp_dir = TempDir::new().unwrap();
+	let running = temp_dir.path().join("running");
+	fs::create_dir(&running).unwrap();
+	let step = |name: &str| {
+		shell_step(
+			json!({
+				"/": "pipe::image",
+				"$OUT": "literal:/out",
+				"$RUNNING": format!("literal:{}", running.display()),
+			}),
+			&format!(
+				"touch \"$RUNNING/{name}\"; sleep 0.3; ls \"$RUNNING\" | wc -l > \"$OUT/count\"; \
+				 rm \"$RUNNING/{name}\""
+			),
+		)
+	};
+	let plot: PlotCapsule = serde_json::from_value(json!({
+		"plot.v1": {
+			"inputs": {
+				"image": IMAGE
+			},
+			"steps": {
+				"a": step("a"),
+				"nested": {
+					"plot": {
+						"inputs": {
+							"image": "pipe::image"
+						},
+						"steps": {
+							"b": step("b"),
+							"c": step("c"),
+						},
+						"outputs": {
+							"b": "pipe:b:out",
+							"c": "pipe:c:out"
+						}
+					}
+				},
+			},
+			"outputs": {
+				"a": "pipe:a:out",
+				"b": "pipe:nested:b",
+				"c": "pipe:nested:c"
+			}
+		}
+	}))
+	.unwrap();
+
+	let context = Context {
+		runtime_backend: Some(Arc::new(runtime::Fake)),
+		output_path: Some(temp_dir.path().join("outputs")),
+		parallelism: Some(2.try_into().unwrap()),
+		..Default::default()
+	};
+	let record = run_plot(plot, &context).unwrap();
+	assert_eq!(record.steps.len(), 2);
+
+	for name in ["a", "b", "c"] {
+		let count_file = temp_dir.path().join("outputs").join(name).join("count");
+		let count = fs::read_to_string(count_file).unwrap();
+		assert!(
+			count.trim().parse::<usize>().unwrap() <= 2,
+			"step '{name}': {count}"
+		);
+	}
+}
+
+#[test]
+fn runtime_selected_by_executable() {
+	let bundle = runtime::Bundle {
+		ident: "test",
+		dir: Path::new("/bundle"),
+		log_file: Path::new("/log"),
+		network: false,
+	};
+
+	let context = Context {
+		runtime: "/usr/local/bin/crun".into(),
+		..Default::default()
+	};
+	let command = context.container_runtime().command(&bundle).unwrap();
+	assert_eq!(command.get_program(), "/usr/local/bin/crun");
+	assert_eq!(
+		command.get_args().collect::<Vec<_>>(),
+		["--log=/log", "--debug", "run", "--bundle=/bundle", "test"]
+	);
+
+	let context = Context {
+		runtime: "runsc".into(),
+		..Default::default()
+	};
+	let command = context.container_runtime().command(&bundle).unwrap();
+	assert_eq!(command.get_program(), "runsc");
+	assert!(command.get_args().any(|arg| arg == "--network=none"));
+
+	let context = Context {
+		runtime: "runsc".into(),
+		runtime_backend: Some(Arc::new(runtime::Fake)),
+		..Default::default()
+	};
+	assert!(!context.container_runtime().needs_image());
+}
@@ -147,3 +147,106 @@ fn invalid_edges() {
 	assert!(graph.validate().is_err());
 	assert!(graph.validate_dependencies_exist().is_err());
 }
+
+#[test]
+fn sub_plot_edges() {
+	let PlotCapsule::V1(plot) = serde_json::from_value(json!({
+		"plot.v1": {
+			"inputs": {},
+			"steps": {
+				"create": {
+					"protoformula": {
+						"inputs": {
+							"/": "oci:docker.io/library/busybox@sha256:22f27168517de1f58dae0ad51eacf1527e7e7ccc47512d3946f56bdbe913f564",
+						},
+						"action": {
+							"exec": {
+								"command": ["/bin/touch", "/out/test.txt"]
+							}
+						},
+						"outputs": {
+							"out": { "from": "/out" }
+						}
+					}
+				},
+				"nested": {
+					"plot": {
+						"inputs": {
+							"in": "pipe:create:out",
+							"other": "pipe:invalid:out"
+						},
+						"steps": {},
+						"outputs": {}
+					}
+				}
+			},
+			"outputs": {}
+		}
+	}))
+	.unwrap();
+
+	let graph = PlotGraph::new(&plot);
+	assert!(graph.validate().is_err());
+	assert!(graph.validate_dependencies_exist().is_err());
+}
+
+#[test]
+fn sub_plot_cyclic_graph() {
+	let PlotCapsule::V1(plot) = serde_json::from_value(json!({
+		"plot.v1": {
+			"inputs": {},
+			"steps": {
+				"nested": {
+					"plot": {
+						"inputs": {},
+						"steps": {
+							"a": {
+								"protoformula": {
+									"inputs": {
+										"/in": "pipe:b:out"
+									},
+									"action": {
+										"exec": {
+											"command": ["/bin/cp", "-R", "/in", "/out"]
+										}
+									},
+									"outputs": {
+										"out": { "from": "/out" }
+									}
+								}
+							},
+							"b": {
+								"protoformula": {
+									"inputs": {
+										"/in": "pipe:a:out"
+									},
+									"action": {
+										"exec": {
+											"command": ["/bin/cp", "-R", "/in", "/out"]
+										}
+									},
+									"outputs": {
+										"out": { "from": "/out" }
+									}
+								}
+							}
+						},
+						"outputs": {
+							"out": "pipe:b:out"
+						}
+					}
+				}
+			},
+			"outputs": {
+				"out": "pipe:nested:out"
+			}
+		}
+	}))
+	.unwrap();
+
+	let graph = PlotGraph::new(&plot);
+	assert!(graph.validate_dependencies_exist().is_ok());
+	assert!(graph.validate_no_cycles().is_ok());
+	assert!(graph.validate_sub_plots().is_err());
+	assert!(graph.validate().is_err());
+}
@@ -0,0 +1,107 @@
+use std::{fs, num::NonZeroUsize};
+
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use warpforge_api::plot::PlotCapsule;
+
+use crate::{context::Context, plot::run_plot, tests::default_context};
+
+fn script_step(inputs: Value, contents: &[&str]) -> Value {
+	json!({
+		"protoformula": {
+			"inputs": inputs,
+			"action": {
+				"script": {
+					"interpreter": "/bin/sh",
+					"contents": contents,
+				}
+			},
+			"outputs": {
+				"out": { "from": "/out" }
+			}
+		}
+	})
+}
+
+#[test]
+fn plot_fan_out_steps() {
+	let plot: PlotCapsule = serde_json::from_value(json!({
+		"plot.v1": {
+			"inputs": {
+				"image": "oci:docker.io/busybox:latest"
+			},
+			"steps": {
+				"a": script_step(json!({ "/": "pipe::image" }), &["echo a > /out/a"]),
+				"b": script_step(json!({ "/": "pipe::image" }), &["echo b > /out/b"]),
+				"c": script_step(json!({ "/": "pipe::image" }), &["echo c > /out/c"]),
+				"gather": script_step(
+					json!({
+						"/": "pipe::image",
+						"/a": "pipe:a:out",
+						"/b": "pipe:b:out",
+						"/c": "pipe:c:out",
+					}),
+					&["cat /a/a /b/b /c/c > /out/all"],
+				),
+			},
+			"outputs": {
+				"all": "pipe:gather:out"
+			}
+		}
+	}))
+	.unwrap();
+
+	let temp_dir = TempDir::new().unwrap();
+	let context = Context {
+		output_path: Some(temp_dir.path().to_owned()),
+		parallelism: NonZeroUsize::new(2),
+		..default_context()
+	};
+
+	run_plot(plot, &context).unwrap();
+
+	let all = fs::read_to_string(temp_dir.path().join("all").join("all")).unwrap();
+	assert_eq!(all, "a\nb\nc\n");
+}
+
+#[test]
+fn plot_keep_going_after_failed_step() {
+	let temp_dir = TempDir::new().unwrap();
+	let marker_dir = temp_dir.path().join("marker");
+	fs::create_dir(&marker_dir).unwrap();
+	let marker_mount = format!("mount:rw:{}", marker_dir.to_str().unwrap());
+
+	let plot: PlotCapsule = serde_json::from_value(json!({
+		"plot.v1": {
+			"inputs": {
+				"image": "oci:docker.io/busybox:latest"
+			},
+			"steps": {
+				"fail": script_step(json!({ "/": "pipe::image" }), &["exit 1"]),
+				"dependent": script_step(
+					json!({ "/": "pipe::image", "/in": "pipe:fail:out", "/marker": marker_mount }),
+					&["touch /marker/dependent"],
+				),
+				"sibling": script_step(
+					json!({ "/": "pipe::image", "/marker": marker_mount }),
+					&["sleep 1", "touch /marker/sibling"],
+				),
+			},
+			"outputs": {}
+		}
+	}))
+	.unwrap();
+
+	let context = Context {
+		output_path: Some(temp_dir.path().join("outputs")),
+		parallelism: NonZeroUsize::new(1),
+		keep_going: true,
+		..default_context()
+	};
+
+	let err = run_plot(plot, &context).unwrap_err();
+
+	assert!(err.to_string().contains("step 'fail'"), "{err}");
+	assert!(marker_dir.join("sibling").exists());
+	assert!(!marker_dir.join("dependent").exists());
+}
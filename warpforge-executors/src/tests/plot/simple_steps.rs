@@ -1,8 +1,9 @@
+use indexmap::IndexMap;
 use serde_json::json;
 use tempfile::TempDir;
-use warpforge_api::plot::PlotCapsule;
+use warpforge_api::plot::{LocalLabel, PlotCapsule};
 
-use crate::{context::Context, plot::run_plot, tests::default_context, Output};
+use crate::{context::Context, plot::run_plot, tests::default_context};
 
 #[test]
 fn plot_simple_steps() {
@@ -82,12 +83,18 @@ fn plot_simple_steps() {
 		..default_context()
 	};
 
-	let outputs = run_plot(plot, &context).unwrap();
+	let record = run_plot(plot, &context).unwrap();
 
-	assert_eq!(outputs, vec![Output{
-		name: "output.tgz".into(),
-		digest: crate::Digest::Sha384("4616df8a46fccfa6e418d623b19ea20545cef530b369b0071a8b691d3bf2a9628d4350f065ab60f37a96515921c5e8e4".into()),
-	}]);
+	assert_eq!(record.steps.len(), 3);
+	assert_eq!(
+		record.results,
+		IndexMap::from([(
+			LocalLabel("output.tgz".into()),
+			"tar:9MSFkXDDkmCSL5M4qh17ZNfpcfJfs1qrQ5GviVGArHsXKTMJKocqf4DPW2s5xe25tb"
+				.parse()
+				.unwrap(),
+		)])
+	);
 }
 
 #[test]
@@ -165,10 +172,15 @@ fn plot_simple_steps_mangled_order() {
 		..default_context()
 	};
 
-	let outputs = run_plot(plot, &context).unwrap();
+	let record = run_plot(plot, &context).unwrap();
 
-	assert_eq!(outputs, vec![Output{
-		name: "output.tar".into(),
-		digest: crate::Digest::Sha384("bd00d1ecdaa6988962460b5288953ba8c504f876bd2134b95aa3ef3df993f7fbc6be147898fc94b5f5cff476584d0fd4".into()),
-	}]);
+	assert_eq!(
+		record.results,
+		IndexMap::from([(
+			LocalLabel("output.tar".into()),
+			"tar:3xbK6jzmNoh6LxifaJUiXKWVU8gEyqgEyJKqjCwrL2K871kH4QVkKZ6ntdjto5Mbdf"
+				.parse()
+				.unwrap(),
+		)])
+	);
 }
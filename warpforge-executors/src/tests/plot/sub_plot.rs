@@ -0,0 +1,114 @@
+use std::fs;
+
+use serde_json::json;
+use tempfile::TempDir;
+use warpforge_api::plot::{LocalLabel, PlotCapsule, StepName};
+use warpforge_api::run_record::StepRunRecord;
+
+use crate::{context::Context, plot::run_plot, tests::default_context};
+
+#[test]
+fn plot_sub_plot() {
+	let plot: PlotCapsule = serde_json::from_value(json!({
+		"plot.v1": {
+			"inputs": {
+				"image": "oci:docker.io/busybox:latest",
+				"$MSG": "literal:hello, sub-plot!"
+			},
+			"steps": {
+				"create": {
+					"protoformula": {
+						"inputs": {
+							"/": "pipe::image",
+							"$MSG": "pipe::$MSG"
+						},
+						"action": {
+							"script": {
+								"interpreter": "/bin/sh",
+								"contents": [
+									"echo \"$MSG\" > /out/test.txt"
+								]
+							}
+						},
+						"outputs": {
+							"out": { "from": "/out" }
+						}
+					}
+				},
+				"nested": {
+					"plot": {
+						"inputs": {
+							"image": "pipe::image",
+							"created": "pipe:create:out"
+						},
+						"steps": {
+							"copy": {
+								"protoformula": {
+									"inputs": {
+										"/": "pipe::image",
+										"/in": "pipe::created"
+									},
+									"action": {
+										"script": {
+											"interpreter": "/bin/sh",
+											"contents": [
+												"cp /in/test.txt /out/copied.txt"
+											]
+										}
+									},
+									"outputs": {
+										"copied": { "from": "/out" }
+									}
+								}
+							}
+						},
+						"outputs": {
+							"copied": "pipe:copy:copied"
+						}
+					}
+				},
+				"output": {
+					"protoformula": {
+						"inputs": {
+							"/": "pipe::image",
+							"/in": "pipe:nested:copied"
+						},
+						"action": {
+							"exec": {
+								"command": ["/bin/cp", "/in/copied.txt", "/out/result.txt"]
+							}
+						},
+						"outputs": {
+							"out": { "from": "/out" }
+						}
+					}
+				}
+			},
+			"outputs": {
+				"result": "pipe:output:out",
+				"copied": "pipe:nested:copied"
+			}
+		}
+	}))
+	.unwrap();
+
+	let temp_dir = TempDir::new().unwrap();
+	let context = Context {
+		output_path: Some(temp_dir.path().to_owned()),
+		..default_context()
+	};
+
+	let record = run_plot(plot, &context).unwrap();
+
+	let result = fs::read_to_string(temp_dir.path().join("result").join("result.txt")).unwrap();
+	assert_eq!(result, "hello, sub-plot!\n");
+	let copied = fs::read_to_string(temp_dir.path().join("copied").join("copied.txt")).unwrap();
+	assert_eq!(copied, "hello, sub-plot!\n");
+
+	let Some(StepRunRecord::Plot(nested)) = record.steps.get(&StepName("nested".into())) else {
+		panic!("missing record of sub-plot step");
+	};
+	assert!(nested.steps.contains_key(&StepName("copy".into())));
+	let copied = LocalLabel("copied".into());
+	assert_eq!(nested.results.get(&copied), record.results.get(&copied));
+}
@@ -0,0 +1,251 @@
+use std::{
+	fs,
+	io::{Read, Write},
+	net::TcpListener,
+	os::unix::fs::PermissionsExt,
+	path::Path,
+	thread,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use tempfile::TempDir;
+use warpforge_api::{
+	content::{Packtype, WareID},
+	formula::{FilterMap, SetidFilter, StickyFilter, WarehouseAddr},
+};
+
+use crate::{
+	fshash::Filters,
+	pack::{hash_dir, tar_dir, tgz_dir_to_file},
+	ware::{warehouse_subpath, WareStore},
+};
+
+/// Packs the directory as gzipped tarball and returns its WareID together with the packed bytes.
+fn pack_ware(source_dir: impl AsRef<Path>) -> (WareID, Vec<u8>) {
+	let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+	tar_dir(&source_dir, &mut encoder, &Filters::default()).unwrap();
+	let packed = encoder.finish().unwrap();
+
+	let ware_id = WareID {
+		packtype: Packtype("tar".into()),
+		hash: hash_dir(&source_dir, &Filters::default()).unwrap(),
+	};
+	(ware_id, packed)
+}
+
+fn create_ware(temp_dir: &TempDir) -> (WareID, Vec<u8>) {
+	let source_dir = temp_dir.path().join("source");
+	fs::create_dir_all(source_dir.join("bin")).unwrap();
+	fs::write(source_dir.join("hello.txt"), "hello, ware!\n").unwrap();
+	fs::write(source_dir.join("bin").join("tool"), "#!/bin/sh\n").unwrap();
+	pack_ware(&source_dir)
+}
+
+/// Serves the given body for a single HTTP request and returns the URL to request.
+fn serve_once(body: Vec<u8>) -> String {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	thread::spawn(move || {
+		let (mut stream, _) = listener.accept().unwrap();
+		let mut request = [0; 4096];
+		let _ = stream.read(&mut request).unwrap();
+		let header = format!(
+			"HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+			body.len()
+		);
+		stream.write_all(header.as_bytes()).unwrap();
+		stream.write_all(&body).unwrap();
+	});
+	format!("http://{addr}/ware.tgz")
+}
+
+#[test]
+fn fetch_from_file_warehouse() {
+	let temp_dir = TempDir::new().unwrap();
+	let (ware_id, packed) = create_ware(&temp_dir);
+
+	let warehouse = temp_dir.path().join("warehouse");
+	let ware_path = warehouse.join(warehouse_subpath(&ware_id.hash));
+	fs::create_dir_all(ware_path.parent().unwrap()).unwrap();
+	fs::write(&ware_path, packed).unwrap();
+
+	let warehouse = WarehouseAddr(format!("ca+file://{}", warehouse.display()));
+	let store = WareStore::new(temp_dir.path().join("store"));
+	let unpacked = store.obtain(&ware_id, &[&warehouse]).unwrap();
+
+	assert_eq!(unpacked, store.path(&ware_id));
+	assert_eq!(
+		fs::read_to_string(unpacked.join("hello.txt")).unwrap(),
+		"hello, ware!\n"
+	);
+
+	// Second time around, the ware is found in the store without consulting the warehouse.
+	fs::remove_file(&ware_path).unwrap();
+	assert_eq!(store.obtain(&ware_id, &[&warehouse]).unwrap(), unpacked);
+}
+
+#[test]
+fn fall_back_to_later_warehouse() {
+	let temp_dir = TempDir::new().unwrap();
+	let (ware_id, packed) = create_ware(&temp_dir);
+
+	let warehouse = temp_dir.path().join("warehouse");
+	let ware_path = warehouse.join(warehouse_subpath(&ware_id.hash));
+	fs::create_dir_all(ware_path.parent().unwrap()).unwrap();
+	fs::write(&ware_path, packed).unwrap();
+
+	let empty = WarehouseAddr(format!(
+		"ca+file://{}",
+		temp_dir.path().join("empty").display()
+	));
+	let mirror = WarehouseAddr(format!("ca+file://{}", warehouse.display()));
+	let store = WareStore::new(temp_dir.path().join("store"));
+	let unpacked = store.obtain(&ware_id, &[&empty, &mirror]).unwrap();
+
+	assert!(unpacked.join("bin").join("tool").is_file());
+}
+
+#[test]
+fn fetch_from_http() {
+	let temp_dir = TempDir::new().unwrap();
+	let (ware_id, packed) = create_ware(&temp_dir);
+
+	let url = serve_once(packed);
+	let warehouse = WarehouseAddr(url);
+	let store = WareStore::new(temp_dir.path().join("store"));
+	let unpacked = store.obtain(&ware_id, &[&warehouse]).unwrap();
+
+	assert!(unpacked.join("bin").join("tool").is_file());
+	assert_eq!(
+		hash_dir(&unpacked, &Filters::default()).unwrap(),
+		ware_id.hash
+	);
+}
+
+#[test]
+fn fetch_tgz_ware() {
+	let temp_dir = TempDir::new().unwrap();
+	let source_dir = temp_dir.path().join("source");
+	fs::create_dir_all(&source_dir).unwrap();
+	fs::write(source_dir.join("hello.txt"), "hello, tgz!\n").unwrap();
+	let packed_path = temp_dir.path().join("ware.tgz");
+	let hash = tgz_dir_to_file(&source_dir, &packed_path, &Filters::default()).unwrap();
+
+	// tgz outputs are identified by their filesystem, like uncompressed ones.
+	assert_eq!(hash, hash_dir(&source_dir, &Filters::default()).unwrap());
+
+	let ware_id = WareID {
+		packtype: Packtype("tar".into()),
+		hash,
+	};
+	let warehouse = WarehouseAddr(format!("file://{}", packed_path.display()));
+	let store = WareStore::new(temp_dir.path().join("store"));
+	let unpacked = store.obtain(&ware_id, &[&warehouse]).unwrap();
+	assert_eq!(
+		fs::read_to_string(unpacked.join("hello.txt")).unwrap(),
+		"hello, tgz!\n"
+	);
+}
+
+#[test]
+fn fetch_ware_packed_with_filters() {
+	let temp_dir = TempDir::new().unwrap();
+	let source_dir = temp_dir.path().join("source");
+	fs::create_dir_all(source_dir.join("bin")).unwrap();
+	fs::write(source_dir.join("bin").join("tool"), "#!/bin/sh\n").unwrap();
+	fs::set_permissions(source_dir.join("bin"), fs::Permissions::from_mode(0o1755)).unwrap();
+	fs::set_permissions(
+		source_dir.join("bin").join("tool"),
+		fs::Permissions::from_mode(0o4755),
+	)
+	.unwrap();
+
+	// Filters of the mode are verified on the unpacked files.  Owners and mtimes are
+	// hashed as normalized by default, so filters keeping them can't be verified.
+	let filters = Filters::new(Some(&FilterMap {
+		sticky: Some(StickyFilter::Strip),
+		setid: Some(SetidFilter::Keep),
+		..Default::default()
+	}));
+	let packed_path = temp_dir.path().join("ware.tgz");
+	let hash = tgz_dir_to_file(&source_dir, &packed_path, &filters).unwrap();
+	assert_ne!(
+		hash,
+		hash_dir(
+			&source_dir,
+			&Filters {
+				setid: SetidFilter::Keep,
+				..Default::default()
+			}
+		)
+		.unwrap()
+	);
+
+	let ware_id = WareID {
+		packtype: Packtype("tar".into()),
+		hash,
+	};
+	let warehouse = WarehouseAddr(format!("file://{}", packed_path.display()));
+	let store = WareStore::new(temp_dir.path().join("store"));
+	let unpacked = store.obtain(&ware_id, &[&warehouse]).unwrap();
+	let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+	assert_eq!(mode(&unpacked.join("bin")), 0o755);
+	assert_eq!(mode(&unpacked.join("bin").join("tool")), 0o4755);
+}
+
+#[test]
+fn reject_entries_missing_from_tarball() {
+	let temp_dir = TempDir::new().unwrap();
+	let source_dir = temp_dir.path().join("source");
+	fs::create_dir_all(source_dir.join("sub")).unwrap();
+	fs::write(source_dir.join("sub").join("file.txt"), "hidden\n").unwrap();
+	let hash = hash_dir(&source_dir, &Filters::default()).unwrap();
+
+	// Unpacking creates the parent directory, which is not part of the tarball.
+	let mut archive = tar::Builder::new(Vec::new());
+	let mut header = tar::Header::new_gnu();
+	header.set_mode(0o644);
+	header.set_size(7);
+	archive
+		.append_data(&mut header, "sub/file.txt", &b"hidden\n"[..])
+		.unwrap();
+	let packed_path = temp_dir.path().join("ware.tar");
+	fs::write(&packed_path, archive.into_inner().unwrap()).unwrap();
+
+	let ware_id = WareID {
+		packtype: Packtype("tar".into()),
+		hash,
+	};
+	let warehouse = WarehouseAddr(format!("file://{}", packed_path.display()));
+	let store = WareStore::new(temp_dir.path().join("store"));
+	assert!(store.obtain(&ware_id, &[&warehouse]).is_err());
+	assert!(!store.path(&ware_id).exists());
+}
+
+#[test]
+fn reject_hash_mismatch() {
+	let temp_dir = TempDir::new().unwrap();
+	let (ware_id, packed) = create_ware(&temp_dir);
+
+	let packed_path = temp_dir.path().join("ware.tgz");
+	fs::write(&packed_path, packed).unwrap();
+
+	let wrong_id = WareID {
+		packtype: ware_id.packtype.clone(),
+		hash: "0".repeat(ware_id.hash.len()),
+	};
+	let warehouse = WarehouseAddr(format!("file://{}", packed_path.display()));
+	let store = WareStore::new(temp_dir.path().join("store"));
+
+	assert!(store.obtain(&wrong_id, &[&warehouse]).is_err());
+	assert!(!store.path(&wrong_id).exists());
+}
+
+#[test]
+fn missing_warehouse() {
+	let temp_dir = TempDir::new().unwrap();
+	let (ware_id, _) = create_ware(&temp_dir);
+
+	let store = WareStore::new(temp_dir.path().join("store"));
+	assert!(store.obtain(&ware_id, &[]).is_err());
+}
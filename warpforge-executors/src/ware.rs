@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use flate2::read::GzDecoder;
+use warpforge_api::content::{Packtype, WareID};
+use warpforge_api::formula::{SetidFilter, WarehouseAddr};
+use warpforge_terminal::logln;
+
+use crate::fshash::{self, Filters};
+#[cfg(doc)]
+use crate::pack::hash_dir;
+use crate::{Error, Result};
+
+/// Packtypes we know how to unpack.
+///
+/// "tar" wares are tarballs, optionally gzip compressed (which is what `tgz` outputs are),
+/// identified by the hash of their unpacked filesystem (see [hash_dir]).
+const SUPPORTED_PACKTYPES: &[&str] = &["tar"];
+
+/// Local store of unpacked wares.
+///
+/// Wares are unpacked into `<root>/<packtype>/<hash>`.  Since the hash of the unpacked
+/// filesystem is verified before a ware lands in its final location, any directory
+/// found at that path can be used without fetching (or checking) it again.
+pub(crate) struct WareStore {
+	root: PathBuf,
+}
+
+impl WareStore {
+	pub(crate) fn new(root: impl AsRef<Path>) -> Self {
+		Self {
+			root: root.as_ref().to_owned(),
+		}
+	}
+
+	pub(crate) fn path(&self, ware_id: &WareID) -> PathBuf {
+		let WareID {
+			packtype: Packtype(packtype),
+			hash,
+		} = ware_id;
+		self.root.join(packtype).join(hash)
+	}
+
+	/// Returns the path of the unpacked ware, fetching it from the first of
+	/// the given warehouses which serves it, if it is not in the store yet.
+	pub(crate) fn obtain(&self, ware_id: &WareID, addrs: &[&WarehouseAddr]) -> Result<PathBuf> {
+		let target = self.path(ware_id);
+		if target.is_dir() {
+			return Ok(target);
+		}
+
+		let Packtype(packtype) = &ware_id.packtype;
+		if !SUPPORTED_PACKTYPES.contains(&packtype.as_str()) {
+			let msg = format!("ware '{ware_id}': unsupported packtype '{packtype}'");
+			return Err(Error::SystemSetupCauseless { msg });
+		}
+		if ware_id.hash.is_empty() || ware_id.hash.contains(['/', '.']) {
+			let msg = format!("ware '{ware_id}': invalid hash");
+			return Err(Error::SystemSetupCauseless { msg });
+		}
+
+		let mut last_err = None;
+		for addr in addrs {
+			match self.fetch(ware_id, addr, &target) {
+				Ok(path) => return Ok(path),
+				Err(err) => {
+					logln!("{err}");
+					last_err = Some(err);
+				}
+			}
+		}
+		match (last_err, addrs.len()) {
+			(None, _) => {
+				let msg = format!("ware '{ware_id}': no warehouse known to fetch it from");
+				Err(Error::SystemSetupCauseless { msg })
+			}
+			(Some(err), 1) => Err(err),
+			(Some(err), count) => Err(Error::SystemSetupError {
+				msg: format!("ware '{ware_id}': failed to fetch from all {count} warehouses"),
+				cause: Box::new(err),
+			}),
+		}
+	}
+
+	/// Fetches the ware from the warehouse, verifies it and moves it into the store.
+	fn fetch(&self, ware_id: &WareID, addr: &WarehouseAddr, target: &Path) -> Result<PathBuf> {
+		let packtype_dir = target.parent().expect("ware path has packtype dir");
+		fs::create_dir_all(packtype_dir).map_err(|err| Error::SystemSetupError {
+			msg: "failed to create ware store directory".into(),
+			cause: Box::new(err),
+		})?;
+
+		// Unpack next to the final location, so the move into place is a cheap rename.
+		// The staging directory is removed on drop, whatever happens.
+		let staging = tempfile::Builder::new()
+			.prefix(".fetch-")
+			.tempdir_in(packtype_dir)
+			.map_err(|err| Error::SystemSetupError {
+				msg: "failed to create staging directory for ware".into(),
+				cause: Box::new(err),
+			})?;
+		let unpacked = staging.path().join("ware");
+
+		let tar_paths = unpack_tar(open_ware(ware_id, addr)?, &unpacked).map_err(|err| {
+			Error::SystemRuntimeError {
+				msg: format!("ware '{ware_id}': failed to unpack from '{addr}'"),
+				cause: Box::new(err),
+			}
+		})?;
+
+		// Setuid and setgid bits of wares we didn't pack ourselves are hashed as they are.
+		let filters = Filters {
+			setid: SetidFilter::Keep,
+			..Default::default()
+		};
+		let Some(actual) = hash_unpacked(&unpacked, &tar_paths, &filters)? else {
+			let msg = format!(
+				"ware '{ware_id}': content fetched from '{addr}' does not match the entries of its tarball"
+			);
+			return Err(Error::SystemSetupCauseless { msg });
+		};
+		if actual != ware_id.hash {
+			let msg = format!(
+				"ware '{ware_id}': content fetched from '{addr}' does not match hash (got '{actual}')"
+			);
+			return Err(Error::SystemSetupCauseless { msg });
+		}
+
+		match fs::rename(&unpacked, target) {
+			Ok(()) => Ok(target.to_owned()),
+			// Someone else won the race to unpack the same ware: that's fine, it's verified too.
+			Err(_) if target.is_dir() => Ok(target.to_owned()),
+			Err(err) => Err(Error::SystemRuntimeError {
+				msg: format!("ware '{ware_id}': failed to move ware into store"),
+				cause: Box::new(err),
+			}),
+		}
+	}
+}
+
+/// Path of a ware within a content-addressed warehouse.
+///
+/// The layout shards by hash prefix: `<hash[0..3]>/<hash[3..6]>/<hash>`.
+pub(crate) fn warehouse_subpath(hash: &str) -> PathBuf {
+	let shard = |range: std::ops::Range<usize>| hash.get(range).unwrap_or_default();
+	PathBuf::from(shard(0..3)).join(shard(3..6)).join(hash)
+}
+
+/// Opens a reader for the packed ware at the given warehouse address.
+///
+/// Supported addresses are:
+///   - `file:///some/file` -- the packed ware itself.
+///   - `file:///some/dir` and `ca+file:///some/dir` -- a content-addressed warehouse.
+///   - `http(s)://host/path` -- the packed ware itself.
+///   - `ca+http(s)://host/path` -- a content-addressed warehouse.
+fn open_ware(ware_id: &WareID, addr: &WarehouseAddr) -> Result<Box<dyn Read>> {
+	let WarehouseAddr(addr) = addr;
+	let (content_addressed, url) = match addr.strip_prefix("ca+") {
+		Some(url) => (true, url),
+		None => (false, addr.as_str()),
+	};
+
+	if let Some(path) = url.strip_prefix("file://") {
+		let mut path = PathBuf::from(path);
+		if content_addressed || path.is_dir() {
+			path = path.join(warehouse_subpath(&ware_id.hash));
+		}
+		let file = File::open(&path).map_err(|err| Error::SystemSetupError {
+			msg: format!("ware '{ware_id}': failed to open '{}'", path.display()),
+			cause: Box::new(err),
+		})?;
+		return Ok(Box::new(file));
+	}
+
+	if url.starts_with("http://") || url.starts_with("https://") {
+		let url = if content_addressed {
+			let subpath = warehouse_subpath(&ware_id.hash);
+			format!("{}/{}", url.trim_end_matches('/'), subpath.display())
+		} else {
+			url.to_owned()
+		};
+		let response = reqwest::blocking::get(&url)
+			.and_then(|response| response.error_for_status())
+			.map_err(|err| Error::SystemRuntimeError {
+				msg: format!("ware '{ware_id}': failed to fetch '{url}'"),
+				cause: Box::new(err),
+			})?;
+		return Ok(Box::new(response));
+	}
+
+	let msg = format!("ware '{ware_id}': unsupported warehouse address '{addr}'");
+	Err(Error::SystemSetupCauseless { msg })
+}
+
+/// Paths of the entries of an unpacked tarball, relative to `.` (like the paths of [fshash::walk]).
+pub(crate) type TarPaths = HashSet<PathBuf>;
+
+/// Hash of an unpacked tarball, identifying it as `tar` ware.
+///
+/// Nothing but the paths is taken from the tarball: the unpacked filesystem is hashed
+/// like when packing, so its owners and mtimes are replaced as the filters say.
+/// Wares packed with filters which keep these can't be verified on another host.
+///
+/// Returns `None` if the filesystem doesn't have exactly the entries of the tarball,
+/// e.g. because some were skipped while unpacking, or parent directories were missing.
+pub(crate) fn hash_unpacked(
+	root: impl AsRef<Path>,
+	tar_paths: &TarPaths,
+	filters: &Filters,
+) -> Result<Option<String>> {
+	let entries = fshash::walk(&root, filters)?;
+	let all_in_tar = (entries.iter()).all(|entry| tar_paths.contains(&entry.path));
+	if !all_in_tar || entries.len() != tar_paths.len() {
+		return Ok(None);
+	}
+	fshash::hash_entries(root, &entries).map(Some)
+}
+
+/// Unpacks a tar stream, transparently decompressing it if it's gzipped.
+///
+/// Returns the paths of the entries of the tarball, to verify them with [hash_unpacked].
+pub(crate) fn unpack_tar(reader: impl Read, target: impl AsRef<Path>) -> std::io::Result<TarPaths> {
+	let mut reader = BufReader::new(reader);
+	let is_gzip = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+
+	fs::create_dir(&target)?;
+	if is_gzip {
+		unpack_tar_raw(GzDecoder::new(reader), target)
+	} else {
+		unpack_tar_raw(reader, target)
+	}
+}
+
+fn unpack_tar_raw(reader: impl Read, target: impl AsRef<Path>) -> std::io::Result<TarPaths> {
+	let mut archive = tar::Archive::new(reader);
+	archive.set_preserve_permissions(true);
+
+	// Unlike `Archive::unpack`, this applies the mode and mtime of the root entry to the target,
+	// which are part of the hash of the ware.
+	let mut root_header = None;
+	let mut directories = Vec::new();
+	let mut paths = TarPaths::new();
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		let relative: PathBuf = (entry.path()?.components())
+			.filter(|c| *c != Component::CurDir)
+			.collect();
+		if relative.as_os_str().is_empty() {
+			paths.insert(PathBuf::from("."));
+			root_header = Some(entry.header().clone());
+			continue;
+		}
+		paths.insert(Path::new(".").join(relative));
+		if entry.header().entry_type().is_dir() {
+			directories.push(entry);
+		} else {
+			entry.unpack_in(&target)?;
+		}
+	}
+	// Directories last, so their permissions don't prevent unpacking their content.
+	for mut directory in directories.into_iter().rev() {
+		directory.unpack_in(&target)?;
+	}
+	if let Some(header) = root_header {
+		let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(header.mtime()?);
+		File::open(&target)?.set_modified(mtime)?;
+		fs::set_permissions(&target, fs::Permissions::from_mode(header.mode()? & 0o7777))?;
+	}
+	Ok(paths)
+}
@@ -161,7 +161,23 @@ impl FormulaValidator {
 				}
 			}
 			"ware" => {
-				todo!();
+				let (Some(packtype), Some(hash)) = (value.next(), value.next()) else {
+					return ValidationErrorWithPath::build(
+						"input type 'ware' requires packtype and hash",
+					)
+					.with_label("invalid ware")
+					.with_note("example ware: \"/guest/path\": \"ware:tar:<HASH>\"")
+					.finish();
+				};
+
+				if packtype.is_empty() || hash.is_empty() {
+					return ValidationErrorWithPath::build(
+						"input type 'ware' requires non-empty packtype and hash",
+					)
+					.with_label("invalid ware")
+					.with_note("example ware: \"/guest/path\": \"ware:tar:<HASH>\"")
+					.finish();
+				}
 			}
 			_ => {}
 		}
@@ -239,7 +255,51 @@ impl FormulaValidator {
 				})
 			}));
 
+			errors.extend(optional_key(value, "filters", |value| {
+				self.check_filters(value)
+			}));
+
 			errors
 		})
 	}
+
+	fn check_filters(&mut self, value: &serde_json::Value) -> Vec<ValidationErrorWithPath> {
+		expect_object_iterate(value, |(key, value)| {
+			let allowed_values: &[&str] = match key.as_str() {
+				"uid" | "gid" => {
+					return expect_string(value, |value| {
+						if value != "keep" && value.parse::<u32>().is_err() {
+							return ValidationErrorWithPath::build(format!(
+								"invalid {key} filter (allowed values: 'keep' or a number)"
+							))
+							.with_note(format!("example filter: \"{key}\": \"0\""))
+							.finish();
+						}
+						Vec::with_capacity(0)
+					});
+				}
+				"mtime" => &["keep", "epoch"],
+				"sticky" => &["keep", "strip"],
+				"setid" => &["reject", "strip", "keep"],
+				_invalid_filter => {
+					return ValidationErrorWithPath::build(
+						"invalid filter (allowed filters: 'uid', 'gid', 'mtime', 'sticky', 'setid')",
+					)
+					.with_target(TargetHint::Key)
+					.finish();
+				}
+			};
+
+			expect_string(value, |value| {
+				if !allowed_values.contains(&value) {
+					let message = format!(
+						"invalid {key} filter (allowed values: '{}')",
+						allowed_values.join("', '")
+					);
+					return ValidationErrorWithPath::custom(message);
+				}
+				Vec::with_capacity(0)
+			})
+		})
+	}
 }
@@ -14,6 +14,9 @@ pub(crate) struct PlotValidator<'a> {
 	graph_builder: PlotGraphBuilder<'a>,
 	formula_validators: IndexMap<&'a str, FormulaValidator>,
 
+	/// Sub-plots may pipe the outputs of sibling steps into their inputs.
+	is_sub_plot: bool,
+
 	/// Order in which plot steps should be run, determined by topological sort.
 	step_order: Vec<&'a str>,
 }
@@ -46,6 +49,8 @@ struct PlotGraph<'a> {
 
 #[derive(Default)]
 struct PlotStep<'a> {
+	/// Key of the step definition: "protoformula" or "plot".
+	kind: &'a str,
 	input_pipes: Vec<InputPipe<'a>>,
 	outputs: Vec<&'a str>,
 }
@@ -64,28 +69,29 @@ impl<'a> PlotValidator<'a> {
 		Self {
 			graph_builder: PlotGraphBuilder::new(),
 			formula_validators: IndexMap::new(),
+			is_sub_plot: false,
 			step_order: Vec::new(),
 		}
 		.check(parsed)
 	}
 
-	fn check(mut self, value: &'a serde_json::Value) -> Vec<ValidationErrorWithPath> {
+	fn check(self, value: &'a serde_json::Value) -> Vec<ValidationErrorWithPath> {
+		expect_key(value, "plot.v1", |value| self.check_plot(value))
+	}
+
+	fn check_plot(mut self, value: &'a serde_json::Value) -> Vec<ValidationErrorWithPath> {
 		let mut inputs = None;
 		let mut outputs = None;
 
-		let mut errors = expect_key(value, "plot.v1", |value| {
-			let mut errors = expect_key(value, "inputs", |value| {
-				inputs = Some(value);
-				self.check_inputs(value)
-			});
-			errors.extend(expect_key(value, "steps", |value| self.check_steps(value)));
-			errors.extend(expect_key(value, "outputs", |value| {
-				outputs = Some(value);
-				Vec::with_capacity(0)
-			}));
-
-			errors
+		let mut errors = expect_key(value, "inputs", |value| {
+			inputs = Some(value);
+			self.check_inputs(value)
 		});
+		errors.extend(expect_key(value, "steps", |value| self.check_steps(value)));
+		errors.extend(expect_key(value, "outputs", |value| {
+			outputs = Some(value);
+			Vec::with_capacity(0)
+		}));
 
 		if let (Some(inputs), Some(outputs)) = (inputs, outputs) {
 			errors.extend(self.check_graph_and_outputs(inputs, outputs));
@@ -101,6 +107,8 @@ impl<'a> PlotValidator<'a> {
 				let discriminant = parts.next().expect("split emits at least one value");
 
 				match discriminant {
+					// Pipes into sub-plots are checked by the plot containing the sub-plot.
+					"pipe" if self.is_sub_plot => {}
 					"literal" => {
 						if parts.next().is_none() {
 							return ValidationErrorWithPath::build(
@@ -168,49 +176,14 @@ impl<'a> PlotValidator<'a> {
 
 	fn check_steps(&mut self, value: &'a serde_json::Value) -> Vec<ValidationErrorWithPath> {
 		expect_object_iterate(value, |(step_name, value)| {
-			let mut errors = expect_key(value, "protoformula", |value| {
-				let mut step: PlotStep<'a> = Default::default();
-
-				// Ignoring errors here, because we check this
-				// structure in the FormulaValidator already.
-				let _ = expect_key(value, "inputs", |value| {
-					expect_object_iterate(value, |(port, value)| {
-						expect_string(value, |value| {
-							if let Some(pipe) = value.strip_prefix("pipe:") {
-								let mut parts = pipe.split(':');
-								if let (Some(input_step), Some(output), None) =
-									(parts.next(), parts.next(), parts.next())
-								{
-									step.input_pipes.push(InputPipe {
-										port,
-										step: input_step,
-										name: output,
-									});
-								} else {
-									return ValidationErrorWithPath::build("expected step and output")
-										.with_label("invalid pipe")
-										.with_note("example pipe: \"name\": \"pipe:step_name:step_output_name\"")
-										.finish();
-								}
-							}
-							Vec::with_capacity(0)
-						})
-					})
-				});
-				let _ = expect_key(value, "outputs", |value| {
-					expect_object_iterate(value, |(output_name, _value)| {
-						step.outputs.push(output_name);
-						Vec::with_capacity(0)
-					})
-				});
-
-				self.graph_builder.add_step(step_name, step);
-
-				let mut validator = FormulaValidator::new(true);
-				let errors = validator.check(value);
-				self.formula_validators.insert(step_name, validator);
+			let sub_plot = value.as_object().and_then(|object| object.get("plot"));
+			let mut errors = if let Some(sub_plot) = sub_plot {
+				let mut errors = self.check_sub_plot(step_name, sub_plot);
+				errors.prepend_object_index("plot");
 				errors
-			});
+			} else {
+				self.check_protoformula(step_name, value)
+			};
 
 			if step_name.is_empty() {
 				let key_error = ValidationErrorWithPath::build("empy step name not allowed")
@@ -224,6 +197,105 @@ impl<'a> PlotValidator<'a> {
 		})
 	}
 
+	fn check_protoformula(
+		&mut self,
+		step_name: &'a str,
+		value: &'a serde_json::Value,
+	) -> Vec<ValidationErrorWithPath> {
+		expect_key(value, "protoformula", |value| {
+			let mut step = PlotStep {
+				kind: "protoformula",
+				..Default::default()
+			};
+
+			// Ignoring errors here, because we check this
+			// structure in the FormulaValidator already.
+			let _ = expect_key(value, "inputs", |value| {
+				expect_object_iterate(value, |(port, value)| {
+					expect_string(value, |value| {
+						if let Some(pipe) = value.strip_prefix("pipe:") {
+							let mut parts = pipe.split(':');
+							if let (Some(input_step), Some(output), None) =
+								(parts.next(), parts.next(), parts.next())
+							{
+								step.input_pipes.push(InputPipe {
+									port,
+									step: input_step,
+									name: output,
+								});
+							} else {
+								return ValidationErrorWithPath::build("expected step and output")
+										.with_label("invalid pipe")
+										.with_note("example pipe: \"name\": \"pipe:step_name:step_output_name\"")
+										.finish();
+							}
+						}
+						Vec::with_capacity(0)
+					})
+				})
+			});
+			let _ = expect_key(value, "outputs", |value| {
+				expect_object_iterate(value, |(output_name, _value)| {
+					step.outputs.push(output_name);
+					Vec::with_capacity(0)
+				})
+			});
+
+			self.graph_builder.add_step(step_name, step);
+
+			let mut validator = FormulaValidator::new(true);
+			let errors = validator.check(value);
+			self.formula_validators.insert(step_name, validator);
+			errors
+		})
+	}
+
+	/// Checks a sub-plot: the sub-plot itself is checked by its own validator,
+	/// the pipes into its inputs are checked as part of this plot's graph.
+	fn check_sub_plot(
+		&mut self,
+		step_name: &'a str,
+		value: &'a serde_json::Value,
+	) -> Vec<ValidationErrorWithPath> {
+		let mut step = PlotStep {
+			kind: "plot",
+			..Default::default()
+		};
+
+		// Ignoring errors here, because the sub-plot validator reports them.
+		let _ = expect_key(value, "inputs", |value| {
+			expect_object_iterate(value, |(label, value)| {
+				expect_string(value, |value| {
+					if let Some(pipe) = value.strip_prefix("pipe:") {
+						if let Some((input_step, output)) = pipe.split_once(':') {
+							step.input_pipes.push(InputPipe {
+								port: label,
+								step: input_step,
+								name: output,
+							});
+						}
+					}
+					Vec::with_capacity(0)
+				})
+			})
+		});
+		let _ = expect_key(value, "outputs", |value| {
+			expect_object_iterate(value, |(output_name, _value)| {
+				step.outputs.push(output_name);
+				Vec::with_capacity(0)
+			})
+		});
+		self.graph_builder.add_step(step_name, step);
+
+		Self {
+			graph_builder: PlotGraphBuilder::new(),
+			formula_validators: IndexMap::new(),
+			is_sub_plot: true,
+			step_order: Vec::new(),
+		}
+		.check_plot(value)
+	}
+
 	fn check_graph_and_outputs(
 		&mut self,
 		plot_inputs: &serde_json::Value,
@@ -238,7 +310,6 @@ impl<'a> PlotValidator<'a> {
 		outputs_errors.prepend_object_index("outputs");
 		errors.extend(outputs_errors);
 
-		errors.prepend_object_index("plot.v1");
 		errors
 	}
 
@@ -254,7 +325,7 @@ impl<'a> PlotValidator<'a> {
 
 			for pipe in &step.input_pipes {
 				if !pipe.step.is_empty() {
-					if pipe.port.starts_with('$') {
+					if step.kind == "protoformula" && pipe.port.starts_with('$') {
 						let mut error = ValidationErrorWithPath::build(
 							"env variable may only be piped from plot inputs",
 						)
@@ -307,6 +378,10 @@ impl<'a> PlotValidator<'a> {
 					let Some((input_type, _)) = plot_input.split_once(':') else {
 						continue;
 					};
+					// Pipes into sub-plots are checked by the plot containing the sub-plot.
+					if input_type == "pipe" {
+						continue;
+					}
 					let allowed_types = FormulaValidator::allowed_input_types(pipe.port, true);
 					if allowed_types.is_empty() {
 						continue;
@@ -327,7 +402,7 @@ impl<'a> PlotValidator<'a> {
 				}
 			}
 
-			input_errors.prepend_object_indices(&[target_step_name, "protoformula", "inputs"]);
+			input_errors.prepend_object_indices(&[target_step_name, step.kind, "inputs"]);
 			step_errors.extend(input_errors);
 		}
 
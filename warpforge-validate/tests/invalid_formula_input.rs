@@ -219,3 +219,29 @@ fn mount_invalid_type() {
 	"#;
 	check_formula(formula);
 }
+
+#[test]
+fn ware_invalid_format() {
+	let formula = r#"
+		{
+			"formula": {
+				"formula.v1": {
+					"inputs": <missing_root>{
+						"/path/1": <ware_invalid_format>"ware"</ware_invalid_format>,
+						"/path/2": <ware_invalid_format>"ware:tar"</ware_invalid_format>,
+						"/path/3": <ware_invalid_format>"ware::4z9DCTxoKkStqXQRwtf9nimpfQQ36dbndDsAPCQgECfbXt3edanUrsVKCjE9TkX2v9"</ware_invalid_format>,
+						"/path/4": "ware:tar:4z9DCTxoKkStqXQRwtf9nimpfQQ36dbndDsAPCQgECfbXt3edanUrsVKCjE9TkX2v9"
+					}</missing_root>,
+					"action": "echo",
+					"outputs": {}
+				}
+			},
+			"context": {
+				"context.v1": {
+					"warehouses": {}
+				}
+			}
+		}
+	"#;
+	check_formula(formula);
+}
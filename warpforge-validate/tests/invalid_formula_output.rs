@@ -85,3 +85,39 @@ fn invalid_packtype() {
 	"#;
 	check_formula(formula);
 }
+
+#[test]
+fn invalid_filters() {
+	let formula = r#"
+		{
+			"formula": {
+				"formula.v1": {
+					"inputs": <missing_root>{
+						"$MSG": "literal:hello",
+						"/path": "mount:ro:/host/path"
+					}</missing_root>,
+					"action": "echo",
+					"outputs": {
+						"output": {
+							"from": "/out",
+							"filters": {
+								"uid": "keep",
+								"gid": <invalid_gid>"wheel"</invalid_gid>,
+								"mtime": <invalid_mtime>"now"</invalid_mtime>,
+								"sticky": "strip",
+								"setid": <invalid_setid>true</invalid_setid>,
+								<invalid_filter>"dev"</invalid_filter>: "reject"
+							}
+						}
+					}
+				}
+			},
+			"context": {
+				"context.v1": {
+					"warehouses": {}
+				}
+			}
+		}
+	"#;
+	check_formula(formula);
+}
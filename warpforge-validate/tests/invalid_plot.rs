@@ -89,3 +89,83 @@ fn invalid_oci() {
 	"#;
 	check_plot(plot);
 }
+
+#[test]
+fn sub_plot() {
+	let plot = r#"
+		{
+			"plot.v1": {
+				"inputs": {
+					"msg": "literal:hello, sub-plot!"
+				},
+				"steps": {
+					"prepare": {
+						"protoformula": {
+							"inputs": {
+								"/": "oci:docker.io/busybox:latest"
+							},
+							"action": {
+								"exec": {
+									"command": ["/bin/touch", "/out/prepared"]
+								}
+							},
+							"outputs": {
+								"out": { "from": "/out" }
+							}
+						}
+					},
+					"bootstrap": {
+						"plot": {
+							"inputs": {
+								"msg": "pipe::msg",
+								"prepared": "pipe:prepare:out",
+								"missing": <invalid_pipe>"pipe:unknown:out"</invalid_pipe>
+							},
+							"steps": {
+								"build": {
+									"protoformula": {
+										"inputs": {
+											"/": "oci:docker.io/busybox:latest",
+											"/prepared": "pipe::prepared",
+											"$MSG": "pipe::msg",
+											"/other": "mount:ro:/host/path",
+											"/unknown": <invalid_pipe>"pipe:unknown:out"</invalid_pipe>
+										},
+										"action": {
+											"exec": {
+												"command": ["/bin/cp", "-R", "/prepared", "/out"]
+											}
+										},
+										"outputs": {
+											"out": { "from": "/out" }
+										}
+									}
+								}
+							},
+							"outputs": {
+								"built": "pipe:build:out"
+							}
+						}
+					},
+					"use": {
+						"protoformula": {
+							"inputs": {
+								"/": "oci:docker.io/busybox:latest",
+								"/built": "pipe:bootstrap:built",
+								"/missing": <invalid_pipe>"pipe:bootstrap:missing"</invalid_pipe>
+							},
+							"action": {
+								"exec": {
+									"command": ["/bin/ls", "/built"]
+								}
+							},
+							"outputs": {}
+						}
+					}
+				},
+				"outputs": {}
+			}
+		}
+	"#;
+	check_plot(plot);
+}